@@ -0,0 +1,19 @@
+-- 价格和数量保留16位小数，市场配置的price_decimals/volume_decimals不能超过16
+CREATE TABLE IF NOT EXISTS orders (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  client_order_id VARCHAR(64) NOT NULL,
+  market VARCHAR(16) NOT NULL,
+  price DECIMAL(32,16) NOT NULL,
+  volume DECIMAL(32,16) NOT NULL,
+  origin_volume DECIMAL(32,16) NOT NULL,
+  state SMALLINT UNSIGNED NOT NULL,
+  side TINYINT UNSIGNED NOT NULL,
+  trades_count SMALLINT UNSIGNED NOT NULL DEFAULT 0,
+  created_by VARCHAR(64),
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+  PRIMARY KEY (id),
+  UNIQUE KEY index_orders_on_created_by_and_client_order_id (created_by, client_order_id),
+  KEY index_orders_on_market_and_state (market, state, created_at, id),
+  KEY index_orders_on_state_and_side (state, side)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS trades (
+  id BIGINT UNSIGNED NOT NULL AUTO_INCREMENT,
+  price DECIMAL(32,16) NOT NULL,
+  volume DECIMAL(32,16) NOT NULL,
+  trend SMALLINT UNSIGNED,
+  ask_order_id BIGINT UNSIGNED NOT NULL,
+  bid_order_id BIGINT UNSIGNED NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+  PRIMARY KEY (id),
+  KEY index_trades_on_ask_order_id (ask_order_id),
+  KEY index_trades_on_bid_order_id (bid_order_id),
+  KEY index_trades_on_created_at (created_at)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,10 @@
+-- 每个用户每种资产一行，available可以用来下单，frozen被挂单占用
+CREATE TABLE IF NOT EXISTS accounts (
+  user_id VARCHAR(64) NOT NULL,
+  asset VARCHAR(16) NOT NULL,
+  available DECIMAL(32,16) NOT NULL DEFAULT 0,
+  frozen DECIMAL(32,16) NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, asset)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
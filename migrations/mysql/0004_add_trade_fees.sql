@@ -0,0 +1,3 @@
+-- 卖方的手续费按quote记，买方的按base记，负数是返佣
+ALTER TABLE trades ADD COLUMN ask_fee DECIMAL(32,16) NOT NULL DEFAULT 0 AFTER bid_order_id;
+ALTER TABLE trades ADD COLUMN bid_fee DECIMAL(32,16) NOT NULL DEFAULT 0 AFTER ask_fee;
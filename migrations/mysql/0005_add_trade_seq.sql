@@ -0,0 +1,7 @@
+-- 成交编号由撮合引擎按市场分配，(market, seq)唯一；trend记录taker的方向，0卖1买
+-- 已有的成交沿用自增id作为编号，找不到订单的成交也要有编号，否则唯一索引建不起来
+ALTER TABLE trades ADD COLUMN market VARCHAR(16) NOT NULL DEFAULT '' AFTER id;
+ALTER TABLE trades ADD COLUMN seq BIGINT UNSIGNED NOT NULL DEFAULT 0 AFTER market;
+UPDATE trades SET seq = id;
+UPDATE trades JOIN orders ON orders.id = trades.ask_order_id SET trades.market = orders.market;
+ALTER TABLE trades ADD UNIQUE KEY index_trades_on_market_and_seq (market, seq);
@@ -0,0 +1,15 @@
+-- K线，period是周期的秒数，time是周期起始的unix时间
+CREATE TABLE IF NOT EXISTS candles (
+  market VARCHAR(16) NOT NULL,
+  period INT UNSIGNED NOT NULL,
+  time BIGINT NOT NULL,
+  open DECIMAL(32,16) NOT NULL,
+  high DECIMAL(32,16) NOT NULL,
+  low DECIMAL(32,16) NOT NULL,
+  close DECIMAL(32,16) NOT NULL,
+  volume DECIMAL(32,16) NOT NULL,
+  funds DECIMAL(32,16) NOT NULL,
+  trades_count INT UNSIGNED NOT NULL,
+  updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+  PRIMARY KEY (market, period, time)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,2 @@
+-- 按用户查询订单（我的挂单、历史订单），结果按id倒序分页
+ALTER TABLE orders ADD KEY index_orders_on_created_by_and_state (created_by, state, id);
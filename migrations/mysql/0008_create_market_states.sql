@@ -0,0 +1,7 @@
+-- 市场的交易状态，没有记录的市场是continuous
+CREATE TABLE IF NOT EXISTS market_states (
+  market VARCHAR(16) NOT NULL,
+  state VARCHAR(16) NOT NULL,
+  updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+  PRIMARY KEY (market)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
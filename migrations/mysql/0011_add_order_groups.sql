@@ -0,0 +1,16 @@
+-- OCO的另一条腿，不是OCO时是NULL
+ALTER TABLE orders ADD COLUMN oco_id BIGINT UNSIGNED NULL AFTER peg_offset;
+-- 括号单还没挂出的出场单，入场单撤销或者出场单挂出之后删除
+CREATE TABLE IF NOT EXISTS bracket_exits (
+  entry_id BIGINT UNSIGNED NOT NULL,
+  market VARCHAR(16) NOT NULL,
+  first_price DECIMAL(32,16) NOT NULL,
+  first_volume DECIMAL(32,16) NOT NULL,
+  first_side TINYINT UNSIGNED NOT NULL,
+  second_price DECIMAL(32,16) NOT NULL,
+  second_volume DECIMAL(32,16) NOT NULL,
+  second_side TINYINT UNSIGNED NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (entry_id),
+  KEY index_bracket_exits_on_market (market)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
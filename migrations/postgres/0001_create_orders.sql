@@ -0,0 +1,18 @@
+-- 价格和数量保留16位小数，市场配置的price_decimals/volume_decimals不能超过16
+CREATE TABLE IF NOT EXISTS orders (
+  id BIGSERIAL PRIMARY KEY,
+  client_order_id VARCHAR(64) NOT NULL,
+  market VARCHAR(16) NOT NULL,
+  price NUMERIC(32,16) NOT NULL,
+  volume NUMERIC(32,16) NOT NULL,
+  origin_volume NUMERIC(32,16) NOT NULL,
+  state SMALLINT NOT NULL,
+  side SMALLINT NOT NULL,
+  trades_count INTEGER NOT NULL DEFAULT 0,
+  created_by VARCHAR(64),
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  updated_at TIMESTAMP NOT NULL DEFAULT now(),
+  CONSTRAINT index_orders_on_created_by_and_client_order_id UNIQUE (created_by, client_order_id)
+);
+CREATE INDEX IF NOT EXISTS index_orders_on_market_and_state ON orders (market, state, created_at, id);
+CREATE INDEX IF NOT EXISTS index_orders_on_state_and_side ON orders (state, side);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS trades (
+  id BIGSERIAL PRIMARY KEY,
+  price NUMERIC(32,16) NOT NULL,
+  volume NUMERIC(32,16) NOT NULL,
+  trend SMALLINT,
+  ask_order_id BIGINT NOT NULL,
+  bid_order_id BIGINT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS index_trades_on_ask_order_id ON trades (ask_order_id);
+CREATE INDEX IF NOT EXISTS index_trades_on_bid_order_id ON trades (bid_order_id);
+CREATE INDEX IF NOT EXISTS index_trades_on_created_at ON trades (created_at);
@@ -0,0 +1,10 @@
+-- 每个用户每种资产一行，available可以用来下单，frozen被挂单占用
+CREATE TABLE IF NOT EXISTS accounts (
+  user_id VARCHAR(64) NOT NULL,
+  asset VARCHAR(16) NOT NULL,
+  available NUMERIC(32,16) NOT NULL DEFAULT 0,
+  frozen NUMERIC(32,16) NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL DEFAULT now(),
+  updated_at TIMESTAMP NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, asset)
+);
@@ -0,0 +1,4 @@
+-- 卖方的手续费按quote记，买方的按base记，负数是返佣
+ALTER TABLE trades
+  ADD COLUMN ask_fee NUMERIC(32,16) NOT NULL DEFAULT 0,
+  ADD COLUMN bid_fee NUMERIC(32,16) NOT NULL DEFAULT 0;
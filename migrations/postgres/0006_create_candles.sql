@@ -0,0 +1,15 @@
+-- K线，period是周期的秒数，time是周期起始的unix时间
+CREATE TABLE IF NOT EXISTS candles (
+  market VARCHAR(16) NOT NULL,
+  period INTEGER NOT NULL,
+  time BIGINT NOT NULL,
+  open NUMERIC(32,16) NOT NULL,
+  high NUMERIC(32,16) NOT NULL,
+  low NUMERIC(32,16) NOT NULL,
+  close NUMERIC(32,16) NOT NULL,
+  volume NUMERIC(32,16) NOT NULL,
+  funds NUMERIC(32,16) NOT NULL,
+  trades_count INTEGER NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT now(),
+  PRIMARY KEY (market, period, time)
+);
@@ -0,0 +1,6 @@
+-- 市场的交易状态，没有记录的市场是continuous
+CREATE TABLE IF NOT EXISTS market_states (
+  market VARCHAR(16) NOT NULL PRIMARY KEY,
+  state VARCHAR(16) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+-- 成交条件，和日志里的写法一样（aon、min=<数量>、min_total=<数量>），普通限价单是NULL
+ALTER TABLE orders ADD COLUMN fill_condition VARCHAR(64);
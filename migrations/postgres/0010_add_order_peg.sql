@@ -0,0 +1,4 @@
+-- 挂钩订单的参考价（primary、market、midpoint）和偏移，限价就是price；普通订单是NULL
+ALTER TABLE orders
+  ADD COLUMN peg_reference VARCHAR(16),
+  ADD COLUMN peg_offset NUMERIC(32,16);
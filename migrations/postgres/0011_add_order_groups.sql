@@ -0,0 +1,15 @@
+-- OCO的另一条腿，不是OCO时是NULL
+ALTER TABLE orders ADD COLUMN oco_id BIGINT;
+-- 括号单还没挂出的出场单，入场单撤销或者出场单挂出之后删除
+CREATE TABLE IF NOT EXISTS bracket_exits (
+  entry_id BIGINT PRIMARY KEY,
+  market VARCHAR(16) NOT NULL,
+  first_price NUMERIC(32,16) NOT NULL,
+  first_volume NUMERIC(32,16) NOT NULL,
+  first_side SMALLINT NOT NULL,
+  second_price NUMERIC(32,16) NOT NULL,
+  second_volume NUMERIC(32,16) NOT NULL,
+  second_side SMALLINT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS index_bracket_exits_on_market ON bracket_exits (market);
@@ -0,0 +1,18 @@
+-- 数量和价格存成REAL，只适合测试和本地开发
+CREATE TABLE IF NOT EXISTS orders (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  client_order_id TEXT NOT NULL,
+  market TEXT NOT NULL,
+  price REAL NOT NULL,
+  volume REAL NOT NULL,
+  origin_volume REAL NOT NULL,
+  state INTEGER NOT NULL,
+  side INTEGER NOT NULL,
+  trades_count INTEGER NOT NULL DEFAULT 0,
+  created_by TEXT,
+  created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+  updated_at TEXT DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (created_by, client_order_id)
+);
+CREATE INDEX IF NOT EXISTS index_orders_on_market_and_state ON orders (market, state, created_at, id);
+CREATE INDEX IF NOT EXISTS index_orders_on_state_and_side ON orders (state, side);
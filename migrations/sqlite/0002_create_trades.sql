@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS trades (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  price REAL NOT NULL,
+  volume REAL NOT NULL,
+  trend INTEGER,
+  ask_order_id INTEGER NOT NULL,
+  bid_order_id INTEGER NOT NULL,
+  created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+  updated_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS index_trades_on_ask_order_id ON trades (ask_order_id);
+CREATE INDEX IF NOT EXISTS index_trades_on_bid_order_id ON trades (bid_order_id);
+CREATE INDEX IF NOT EXISTS index_trades_on_created_at ON trades (created_at);
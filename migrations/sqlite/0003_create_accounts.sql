@@ -0,0 +1,11 @@
+-- 每个用户每种资产一行，available可以用来下单，frozen被挂单占用
+-- 余额存成十进制文本，在程序里用BigDecimal精确计算
+CREATE TABLE IF NOT EXISTS accounts (
+  user_id TEXT NOT NULL,
+  asset TEXT NOT NULL,
+  available TEXT NOT NULL DEFAULT '0',
+  frozen TEXT NOT NULL DEFAULT '0',
+  created_at TEXT DEFAULT CURRENT_TIMESTAMP,
+  updated_at TEXT DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, asset)
+);
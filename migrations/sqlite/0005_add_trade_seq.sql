@@ -0,0 +1,6 @@
+-- 成交编号由撮合引擎按市场分配，(market, seq)唯一；trend记录taker的方向，0卖1买
+-- 已有的成交沿用自增id作为编号
+ALTER TABLE trades ADD COLUMN market TEXT NOT NULL DEFAULT '';
+ALTER TABLE trades ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+UPDATE trades SET market = COALESCE((SELECT market FROM orders WHERE orders.id = trades.ask_order_id), ''), seq = id;
+CREATE UNIQUE INDEX IF NOT EXISTS index_trades_on_market_and_seq ON trades (market, seq);
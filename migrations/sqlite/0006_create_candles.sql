@@ -0,0 +1,15 @@
+-- K线，period是周期的秒数，time是周期起始的unix时间
+CREATE TABLE IF NOT EXISTS candles (
+  market TEXT NOT NULL,
+  period INTEGER NOT NULL,
+  time INTEGER NOT NULL,
+  open REAL NOT NULL,
+  high REAL NOT NULL,
+  low REAL NOT NULL,
+  close REAL NOT NULL,
+  volume REAL NOT NULL,
+  funds REAL NOT NULL,
+  trades_count INTEGER NOT NULL,
+  updated_at TEXT DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (market, period, time)
+);
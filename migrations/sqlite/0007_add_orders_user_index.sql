@@ -0,0 +1,2 @@
+-- 按用户查询订单（我的挂单、历史订单），结果按id倒序分页
+CREATE INDEX IF NOT EXISTS index_orders_on_created_by_and_state ON orders (created_by, state, id);
@@ -0,0 +1,6 @@
+-- 市场的交易状态，没有记录的市场是continuous
+CREATE TABLE IF NOT EXISTS market_states (
+  market TEXT NOT NULL PRIMARY KEY,
+  state TEXT NOT NULL,
+  updated_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,3 @@
+-- 挂钩订单的参考价（primary、market、midpoint）和偏移，限价就是price；普通订单是NULL
+ALTER TABLE orders ADD COLUMN peg_reference TEXT;
+ALTER TABLE orders ADD COLUMN peg_offset REAL;
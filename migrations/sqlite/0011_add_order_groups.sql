@@ -0,0 +1,15 @@
+-- OCO的另一条腿，不是OCO时是NULL
+ALTER TABLE orders ADD COLUMN oco_id INTEGER;
+-- 括号单还没挂出的出场单，入场单撤销或者出场单挂出之后删除
+CREATE TABLE IF NOT EXISTS bracket_exits (
+  entry_id INTEGER PRIMARY KEY,
+  market TEXT NOT NULL,
+  first_price REAL NOT NULL,
+  first_volume REAL NOT NULL,
+  first_side INTEGER NOT NULL,
+  second_price REAL NOT NULL,
+  second_volume REAL NOT NULL,
+  second_side INTEGER NOT NULL,
+  created_at TEXT DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS index_bracket_exits_on_market ON bracket_exits (market);
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::error::Error;
+use bigdecimal::BigDecimal;
+use bigdecimal::Zero;
+
+use crate::engine::TradeEvent;
+use crate::errors::TinyError;
+use crate::fees::Fees;
+use crate::market::Market;
+use crate::models::Order;
+use crate::persister::Batch;
+
+// 一次余额变动: user_id在asset上可用余额和冻结余额的增量
+// 所有金额都用BigDecimal精确计算，price_decimals + volume_decimals不超过数据库的精度，成交额不会被截断
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub user_id: String,
+    pub asset: String,
+    pub available: BigDecimal,
+    pub frozen: BigDecimal,
+    // 允许余额变成负数，只用于手续费账户: 返佣和收到的手续费可能不是同一种资产
+    pub overdraft: bool,
+}
+
+impl Change {
+    pub fn new(user_id: &str, asset: &str, available: BigDecimal, frozen: BigDecimal) -> Change {
+        Change {
+            user_id: user_id.to_string(),
+            asset: asset.to_string(),
+            available: available,
+            frozen: frozen,
+            overdraft: false,
+        }
+    }
+
+    fn overdraft(user_id: &str, asset: &str, available: BigDecimal) -> Change {
+        Change {
+            overdraft: true,
+            ..Change::new(user_id, asset, available, BigDecimal::zero())
+        }
+    }
+
+    // 充值和提现只改变可用余额
+    pub fn deposit(user_id: &str, asset: &str, amount: f64) -> Change {
+        Change::new(user_id, asset, decimal(amount), BigDecimal::zero())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.available.is_zero() && self.frozen.is_zero()
+    }
+}
+
+// 经过字符串转换，避免引入二进制浮点的尾差
+pub fn decimal(value: f64) -> BigDecimal {
+    value.to_string().parse::<BigDecimal>().unwrap()
+}
+
+// 挂单需要冻结的资产和数量: 买单冻结price * volume的quote，卖单冻结volume的base
+fn frozen_amount(market: &Market, side: u8, price: f64, volume: f64) -> (&str, BigDecimal) {
+    if side == 0 {
+        (&market.base, decimal(volume))
+    } else {
+        (&market.quote, decimal(price) * decimal(volume))
+    }
+}
+
+// 下单时从可用余额冻结
+pub fn freeze(market: &Market, user_id: &str, side: u8, price: f64, volume: f64) -> Change {
+    let (asset, amount) = frozen_amount(market, side, price, volume);
+    Change::new(user_id, asset, -amount.clone(), amount)
+}
+
+// 撤单时把剩余数量对应的冻结退回可用余额
+pub fn unfreeze(market: &Market, user_id: &str, side: u8, price: f64, volume: f64) -> Change {
+    let (asset, amount) = frozen_amount(market, side, price, volume);
+    Change::new(user_id, asset, amount.clone(), -amount)
+}
+
+// 一笔成交的结算: 卖方的冻结base换成可用quote，买方的冻结quote换成可用base，各自扣掉手续费记到手续费账户
+// 买单按自己的限价冻结，成交价更低时差价退回买方的可用余额
+pub fn settle(market: &Market, trade: &TradeEvent, ask: &Order, bid: &Order) -> (Vec<Change>, Fees) {
+    let volume = decimal(trade.volume);
+    let funds = decimal(trade.price) * volume.clone();
+    let bid_frozen = decimal(bid.price) * volume.clone();
+    let ask_user_id = ask.created_by.clone().unwrap_or_default();
+    let bid_user_id = bid.created_by.clone().unwrap_or_default();
+    let fees = market.fees.fees(trade, &ask_user_id, &bid_user_id);
+    let changes = vec![
+        Change::new(&ask_user_id, &market.base, BigDecimal::zero(), -volume.clone()),
+        Change::new(&ask_user_id, &market.quote, funds.clone() - fees.ask.clone(), BigDecimal::zero()),
+        Change::new(&bid_user_id, &market.quote, bid_frozen.clone() - funds, -bid_frozen),
+        Change::new(&bid_user_id, &market.base, volume - fees.bid.clone(), BigDecimal::zero()),
+        Change::overdraft(&market.fees.account, &market.quote, fees.ask.clone()),
+        Change::overdraft(&market.fees.account, &market.base, fees.bid.clone()),
+    ];
+    (changes, fees)
+}
+
+// 一批成交、撤单和改单减量引起的余额变动，按(user_id, asset)合并，去掉为零的变动；以及每笔成交的手续费
+// orders是批次涉及的订单，撤单的订单要是扣减完这一批成交和减量之后的剩余数量
+pub fn batch_changes(market: &Market, batch: &Batch, orders: &HashMap<u64, Order>) -> Result<(Vec<Change>, Vec<Fees>), Box<dyn Error>> {
+    let find = |id: &u64| orders.get(id).ok_or_else(|| TinyError::new(&format!("order {} not found", id)));
+
+    let mut changes = Vec::new();
+    let mut fees = Vec::new();
+    for trade in &batch.trades {
+        let (trade_changes, trade_fees) = settle(market, trade, find(&trade.ask_order_id)?, find(&trade.bid_order_id)?);
+        changes.extend(trade_changes);
+        fees.push(trade_fees);
+    }
+    for (order_id, volume) in &batch.reductions {
+        let order = find(order_id)?;
+        changes.push(unfreeze(market, &order.created_by.clone().unwrap_or_default(), order.side, order.price, *volume));
+    }
+    for order_id in &batch.cancels {
+        let order = find(order_id)?;
+        changes.push(unfreeze(market, &order.created_by.clone().unwrap_or_default(), order.side, order.price, order.volume));
+    }
+
+    let mut merged: Vec<Change> = Vec::new();
+    for change in changes {
+        match merged.iter_mut().find(|merged| merged.user_id == change.user_id && merged.asset == change.asset) {
+            Some(merged) => {
+                merged.available = merged.available.clone() + change.available;
+                merged.frozen = merged.frozen.clone() + change.frozen;
+            },
+            None => merged.push(change)
+        }
+    }
+    Ok((merged.into_iter().filter(|change| !change.is_zero()).collect(), fees))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use bigdecimal::BigDecimal;
+    use bigdecimal::Zero;
+    use super::{batch_changes, decimal, freeze, settle, Change};
+    use crate::engine::Engine;
+    use crate::engine::LimitOrder;
+    use crate::engine::Side;
+    use crate::engine::TradeEvent;
+    use crate::fees::FeeRate;
+    use crate::fees::FeeSchedule;
+    use crate::fees::Fees;
+    use crate::market::Market;
+    use crate::models::Order;
+    use crate::persister::Batch;
+
+    fn order(id: u64, side: u8, price: f64, volume: f64, user_id: &str) -> Order {
+        Order {
+            id: id,
+            client_order_id: id.to_string(),
+            market: "ethbtc".to_string(),
+            price: price,
+            volume: volume,
+            origin_volume: volume,
+            state: 100,
+            side: side,
+            trades_count: 0,
+            created_by: Some(user_id.to_string()),
+            created_at: None,
+            updated_at: None,
+            fill_condition: None,
+            peg_reference: None,
+            peg_offset: None,
+            oco_id: None,
+        }
+    }
+
+    // 内存里的账本，余额变成负数时报错
+    struct Ledger {
+        balances: HashMap<(String, String), (BigDecimal, BigDecimal)>,
+    }
+
+    impl Ledger {
+        fn apply(&mut self, change: &Change) -> Result<(), String> {
+            let balance = self.balances.entry((change.user_id.clone(), change.asset.clone())).or_insert((BigDecimal::zero(), BigDecimal::zero()));
+            let available = balance.0.clone() + change.available.clone();
+            let frozen = balance.1.clone() + change.frozen.clone();
+            if (available < BigDecimal::zero() && !change.overdraft) || frozen < BigDecimal::zero() {
+                return Err(format!("negative balance: {:?}", change));
+            }
+            *balance = (available, frozen);
+            Ok(())
+        }
+
+        fn total(&self, asset: &str) -> BigDecimal {
+            self.balances.iter()
+                .filter(|((_, balance_asset), _)| balance_asset == asset)
+                .fold(BigDecimal::zero(), |sum, (_, (available, frozen))| sum + available.clone() + frozen.clone())
+        }
+
+        fn frozen(&self, user_id: &str, asset: &str) -> BigDecimal {
+            self.balances.get(&(user_id.to_string(), asset.to_string())).map_or(BigDecimal::zero(), |balance| balance.1.clone())
+        }
+    }
+
+    #[test]
+    fn bid_gets_price_improvement_back() {
+        let mut market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        market.fees = FeeSchedule::new("fees", FeeRate::new(-0.001, 0.002));
+        assert_eq!(Change::new("u1", "btc", decimal(-3.0), decimal(3.0)), freeze(&market, "u1", 1, 1.5, 2.0));
+
+        let trade = TradeEvent {
+            id: 1,
+            price: 1.2,
+            volume: 0.5,
+            funds: 0.6,
+            ask_order_id: 2,
+            ask_order_filled: true,
+            bid_order_id: 1,
+            bid_order_filled: false,
+            taker_side: Side::Buy,
+            maker_order_id: 2,
+            taker_order_id: 1,
+            ask_owner: "u2".to_string(),
+            bid_owner: "u1".to_string(),
+        };
+        let (changes, fees) = settle(&market, &trade, &order(2, 0, 1.2, 0.5, "u2"), &order(1, 1, 1.5, 2.0, "u1"));
+        // 卖方是maker，拿到0.6 * 0.001的返佣；买方是taker，从收到的eth里扣0.5 * 0.002
+        assert_eq!(Fees { ask: decimal(-0.0006), bid: decimal(0.001) }, fees);
+        assert_eq!(vec![
+            Change::new("u2", "eth", decimal(0.0), decimal(-0.5)),
+            Change::new("u2", "btc", decimal(0.6006), decimal(0.0)),
+            Change::new("u1", "btc", decimal(0.15), decimal(-0.75)),
+            Change::new("u1", "eth", decimal(0.499), decimal(0.0)),
+            Change::overdraft("fees", "btc", decimal(-0.0006)),
+            Change::overdraft("fees", "eth", decimal(0.001)),
+        ], changes);
+    }
+
+    // 随机下单、撤单，每一步之后每种资产的总量（包括手续费账户）不变，每个用户的冻结等于他的挂单需要冻结的量
+    #[test]
+    fn total_assets_are_conserved() {
+        let mut market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        market.fees = FeeSchedule::new("fees", FeeRate::new(0.001, 0.002));
+        market.fees.add_tier("maker", FeeRate::new(-0.0003, 0.0015));
+        market.fees.set_user_tier("u3", "maker");
+        let users = ["u1", "u2", "u3"];
+        let mut ledger = Ledger { balances: HashMap::new() };
+        for user_id in &users {
+            ledger.apply(&Change::deposit(user_id, "eth", 100000.0)).unwrap();
+            ledger.apply(&Change::deposit(user_id, "btc", 100000.0)).unwrap();
+        }
+        let (total_eth, total_btc) = (ledger.total("eth"), ledger.total("btc"));
+
+        let batch = RefCell::new(Batch::default());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().trades.push(event);
+            Ok(())
+        };
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().cancels.push(order_id);
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        let mut orders: HashMap<u64, Order> = HashMap::new();
+        let (mut trades, mut cancels) = (0, 0);
+
+        let mut seed: u64 = 42;
+        let mut random = |n: u64| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) % n
+        };
+        for id in 1..=300 {
+            if id % 7 == 0 && !orders.is_empty() {
+                // 撤掉一个还在挂单的订单
+                let mut ids = orders.keys().cloned().collect::<Vec<u64>>();
+                ids.sort();
+                let order = &orders[&ids[random(ids.len() as u64) as usize]];
+                let side = if order.side == 0 { Side::Sell } else { Side::Buy };
+                engine.cancel(LimitOrder::new(order.id, side, order.volume, order.price)).unwrap();
+            } else {
+                let side = random(2) as u8;
+                let price = (90 + random(20)) as f64 / 8.0;
+                let volume = (1 + random(16)) as f64 / 4.0;
+                let user_id = users[random(3) as usize];
+                ledger.apply(&freeze(&market, user_id, side, price, volume)).unwrap();
+                orders.insert(id, order(id, side, price, volume, user_id));
+                engine.submit(LimitOrder::new(id, if side == 0 { Side::Sell } else { Side::Buy }, volume, price)).unwrap();
+            }
+
+            // 和Storage::write_batch一样: 先扣减成交的数量，再按扣减后的订单结算
+            let batch = batch.replace(Batch::default());
+            trades += batch.trades.len();
+            cancels += batch.cancels.len();
+            for (order_id, volume, _trades_count, _filled) in batch.order_fills() {
+                let order = orders.get_mut(&order_id).unwrap();
+                order.volume = (decimal(order.volume) - decimal(volume)).to_string().parse::<f64>().unwrap();
+            }
+            let (changes, _fees) = batch_changes(&market, &batch, &orders).unwrap();
+            for change in changes {
+                ledger.apply(&change).unwrap();
+            }
+            orders.retain(|id, order| order.volume > 0.0 && !batch.cancels.contains(id));
+
+            assert_eq!(total_eth, ledger.total("eth"));
+            assert_eq!(total_btc, ledger.total("btc"));
+            for user_id in &users {
+                let (mut eth, mut btc) = (BigDecimal::zero(), BigDecimal::zero());
+                for order in orders.values().filter(|order| order.created_by.as_ref().unwrap() == user_id) {
+                    if order.side == 0 {
+                        eth = eth + decimal(order.volume);
+                    } else {
+                        btc = btc + decimal(order.price) * decimal(order.volume);
+                    }
+                }
+                assert_eq!(eth, ledger.frozen(user_id, "eth"));
+                assert_eq!(btc, ledger.frozen(user_id, "btc"));
+            }
+        }
+        assert!(trades > 100 && cancels > 10);
+    }
+}
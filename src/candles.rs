@@ -0,0 +1,205 @@
+use std::fmt;
+
+use crate::accounts::decimal;
+use crate::engine::TradeEvent;
+
+// K线的周期，单位秒
+pub const PERIODS: &[u32] = &[60, 300, 900, 3600, 14400, 86400];
+
+// 回补的起点要对齐到最长的周期，否则第一根日K线只包含一部分成交
+pub fn align(timestamp: i64) -> i64 {
+    let period = *PERIODS.last().unwrap() as i64;
+    timestamp - timestamp.rem_euclid(period)
+}
+
+pub fn period_name(period: u32) -> String {
+    match period {
+        p if p % 86400 == 0 => format!("{}d", p / 86400),
+        p if p % 3600 == 0 => format!("{}h", p / 3600),
+        p => format!("{}m", p / 60),
+    }
+}
+
+// 一根K线，time是这个周期的起始时间（unix秒）
+// volume是成交的base数量之和，funds是成交的quote金额之和
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+    pub market: String,
+    pub period: u32,
+    pub time: i64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub funds: f64,
+    pub trades_count: u32,
+}
+
+impl Candle {
+    fn new(market: &str, period: u32, time: i64, price: f64) -> Candle {
+        Candle {
+            market: market.to_string(),
+            period: period,
+            time: time,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 0.0,
+            funds: 0.0,
+            trades_count: 0,
+        }
+    }
+
+    fn add(&mut self, price: f64, volume: f64, funds: f64) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume = (decimal(self.volume) + decimal(volume)).to_string().parse::<f64>().unwrap();
+        self.funds = (decimal(self.funds) + decimal(funds)).to_string().parse::<f64>().unwrap();
+        self.trades_count += 1;
+    }
+
+    // 发布时用的routing key，比如candle.ethbtc.1m
+    pub fn routing_key(&self) -> String {
+        format!("candle.{}.{}", self.market, period_name(self.period))
+    }
+}
+
+impl fmt::Display for Candle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "candle market={} period={} time={} open={} high={} low={} close={} volume={} funds={} count={}",
+            self.market, period_name(self.period), self.time,
+            self.open, self.high, self.low, self.close,
+            self.volume, self.funds, self.trades_count)
+    }
+}
+
+// 一个市场所有周期的K线聚合，每个周期只保留还没结束的那一根
+// 成交的时间由调用者给出，要求基本递增；比当前K线还早的成交算进当前K线
+pub struct CandleAggregator {
+    market: String,
+    current: Vec<Option<Candle>>,
+}
+
+impl CandleAggregator {
+    pub fn new(market: &str) -> CandleAggregator {
+        CandleAggregator {
+            market: market.to_string(),
+            current: vec![None; PERIODS.len()],
+        }
+    }
+
+    // 用历史成交(price, volume, 时间)重建K线，返回全部已经结束的K线
+    // 返回的聚合器里保留最后还没结束的K线，可以接着处理新的成交
+    pub fn backfill(market: &str, trades: &[(f64, f64, i64)]) -> (CandleAggregator, Vec<Candle>) {
+        let mut aggregator = CandleAggregator::new(market);
+        let mut closed = Vec::new();
+        for (price, volume, timestamp) in trades {
+            let funds = (decimal(*price) * decimal(*volume)).to_string().parse::<f64>().unwrap();
+            closed.extend(aggregator.add(*price, *volume, funds, *timestamp));
+        }
+        (aggregator, closed)
+    }
+
+    pub fn add_trade(&mut self, trade: &TradeEvent, timestamp: i64) -> Vec<Candle> {
+        self.add(trade.price, trade.volume, trade.funds, timestamp)
+    }
+
+    // 返回因为这笔成交而结束的K线
+    pub fn add(&mut self, price: f64, volume: f64, funds: f64, timestamp: i64) -> Vec<Candle> {
+        let closed = self.close_expired(timestamp);
+        let market = &self.market;
+        for (i, period) in PERIODS.iter().enumerate() {
+            let time = timestamp - timestamp.rem_euclid(*period as i64);
+            let candle = self.current[i].get_or_insert_with(|| Candle::new(market, *period, time, price));
+            candle.add(price, volume, funds);
+        }
+        closed
+    }
+
+    // 结束在timestamp之前已经到期的K线，没有新成交时定时调用，保证K线及时落库
+    pub fn close_expired(&mut self, timestamp: i64) -> Vec<Candle> {
+        let mut closed = Vec::new();
+        for (i, period) in PERIODS.iter().enumerate() {
+            let expired = match &self.current[i] {
+                Some(candle) => timestamp >= candle.time + *period as i64,
+                None => false
+            };
+            if expired {
+                closed.push(self.current[i].take().unwrap());
+            }
+        }
+        closed
+    }
+
+    // 还没结束的K线，按周期从短到长
+    pub fn current(&self) -> Vec<&Candle> {
+        self.current.iter().filter_map(|candle| candle.as_ref()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{period_name, Candle, CandleAggregator, PERIODS};
+    use crate::accounts::decimal;
+
+    #[test]
+    fn can_name_periods() {
+        assert_eq!(vec!["1m", "5m", "15m", "1h", "4h", "1d"], PERIODS.iter().map(|period| period_name(*period)).collect::<Vec<String>>());
+    }
+
+    #[test]
+    fn can_aggregate_trades() {
+        let mut aggregator = CandleAggregator::new("ethbtc");
+        // 2020-09-13 12:26:40 UTC
+        let start = 1_600_000_000;
+        assert!(aggregator.add(0.03, 1.0, 0.03, start).is_empty());
+        assert!(aggregator.add(0.05, 0.1, 0.005, start + 10).is_empty());
+        assert!(aggregator.add(0.02, 0.2, 0.004, start + 19).is_empty());
+
+        let minute = aggregator.current()[0].clone();
+        assert_eq!(Candle {
+            market: "ethbtc".to_string(),
+            period: 60,
+            time: 1_599_999_960,
+            open: 0.03,
+            high: 0.05,
+            low: 0.02,
+            close: 0.02,
+            volume: 1.3,
+            funds: 0.039,
+            trades_count: 3,
+        }, minute);
+        assert_eq!("candle.ethbtc.1m", minute.routing_key());
+
+        // 下一分钟的成交结束上一根1分钟K线，更长周期的K线继续累加
+        let closed = aggregator.add(0.04, 0.5, 0.02, start + 20);
+        assert_eq!(vec![minute], closed);
+        assert_eq!((1_600_000_020, 0.04, 1), (aggregator.current()[0].time, aggregator.current()[0].open, aggregator.current()[0].trades_count));
+        assert_eq!((1.8, 4), (aggregator.current()[5].volume, aggregator.current()[5].trades_count));
+
+        // 到期的K线可以在没有成交时结束
+        let closed = aggregator.close_expired(start + 3600);
+        assert_eq!(vec![60, 300, 900, 3600], closed.iter().map(|candle| candle.period).collect::<Vec<u32>>());
+        assert_eq!(2, aggregator.current().len());
+    }
+
+    #[test]
+    fn backfill_matches_live_aggregation() {
+        let trades = vec![(0.03, 1.0, 100), (0.031, 0.3, 130), (0.029, 0.7, 250), (0.03, 0.1, 400)];
+        let (aggregator, closed) = CandleAggregator::backfill("ethbtc", &trades);
+
+        let mut live = CandleAggregator::new("ethbtc");
+        let mut live_closed = Vec::new();
+        for (price, volume, timestamp) in &trades {
+            let funds = (decimal(*price) * decimal(*volume)).to_string().parse::<f64>().unwrap();
+            live_closed.extend(live.add(*price, *volume, funds, *timestamp));
+        }
+        assert_eq!(vec![(60, 60), (60, 120), (60, 240), (300, 0)], closed.iter().map(|candle| (candle.period, candle.time)).collect::<Vec<(u32, i64)>>());
+        assert_eq!(live.current(), aggregator.current());
+        assert_eq!(live_closed.len(), closed.len());
+        assert_eq!(0.0093, closed[1].funds);
+    }
+}
@@ -0,0 +1,362 @@
+use std::error::Error;
+
+use crate::engine::Side;
+use crate::engine::MarketState;
+use crate::engine::Condition;
+use crate::engine::CancelFilter;
+use crate::errors::SubmitError;
+
+// 消息的最终处理方式
+// 只有订单和它产生的所有成交都持久化之后才ack
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Settlement {
+    Ack,
+    // 没有任何副作用，交给broker重新投递
+    Requeue,
+    // 消息本身有问题或者撮合已经发生，重新投递会造成重复撮合，放进死信队列人工处理
+    DeadLetter,
+}
+
+impl Settlement {
+    pub fn of(result: &Result<u64, SubmitError>) -> Settlement {
+        match result {
+            Ok(_) => Settlement::Ack,
+            Err(SubmitError::NotPersisted(_)) => Settlement::Requeue,
+            Err(SubmitError::Rejected(_)) => Settlement::DeadLetter,
+            Err(SubmitError::PartiallyPersisted(_, _)) => Settlement::DeadLetter,
+        }
+    }
+}
+
+// 消息确认的抽象，方便在测试里用假的broker代替RabbitMQ
+pub trait Broker {
+    type Delivery;
+
+    fn ack(&self, delivery: Self::Delivery) -> Result<(), Box<dyn Error>>;
+    fn nack(&self, delivery: Self::Delivery, requeue: bool) -> Result<(), Box<dyn Error>>;
+}
+
+impl<'a> Broker for amiquip::Consumer<'a> {
+    type Delivery = amiquip::Delivery;
+
+    fn ack(&self, delivery: amiquip::Delivery) -> Result<(), Box<dyn Error>> {
+        Ok(amiquip::Consumer::ack(self, delivery)?)
+    }
+
+    fn nack(&self, delivery: amiquip::Delivery, requeue: bool) -> Result<(), Box<dyn Error>> {
+        Ok(amiquip::Consumer::nack(self, delivery, requeue)?)
+    }
+}
+
+// 消息格式: price,volume,side,user_id,client_order_id[,condition]
+// client_order_id由客户端生成，同一个用户内唯一，用来保证重复投递的消息不会重复下单
+// user_id不能是空的或者"-"，也不能有空白字符
+// condition是成交条件: aon（全部成交）、min=<数量>（每次至少成交）、min_total=<数量>（一共至少成交）
+#[derive(Debug, PartialEq)]
+pub struct OrderMessage {
+    pub price: f64,
+    pub volume: f64,
+    pub side: u8,
+    pub user_id: String,
+    pub client_order_id: String,
+    pub condition: Condition,
+}
+
+impl OrderMessage {
+    pub fn parse(body: &[u8]) -> Option<OrderMessage> {
+        let body = String::from_utf8_lossy(body);
+        let split = body.split(",").collect::<Vec<&str>>();
+        if (split.len() != 5 && split.len() != 6) || split[4].is_empty() {
+            return None;
+        }
+        if split[3].is_empty() || split[3] == "-" || split[3].chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return None;
+        }
+        let condition = match split.get(5) {
+            None => Condition::None,
+            Some(condition) => Condition::parse(condition)?
+        };
+
+        Some(OrderMessage {
+            price: split[0].parse::<f64>().ok()?,
+            volume: split[1].parse::<f64>().ok()?,
+            side: split[2].parse::<u8>().ok()?,
+            user_id: split[3].to_string(),
+            client_order_id: split[4].to_string(),
+            condition: condition,
+        })
+    }
+}
+
+// 管理消息，routing key是admin.<market>
+// 格式: state <continuous|halted|cancel_only|post_only|auction>
+//       reference_price <price>（价格带的指数价格）
+//       uncross（结束集合竞价）
+//       mass_cancel [owner=<user_id>] [side=<buy|sell>] [min=<price>] [max=<price>]（批量撤单，不写的条件不限）
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    SetState(MarketState),
+    SetReferencePrice(f64),
+    Uncross,
+    MassCancel(CancelFilter),
+}
+
+impl AdminCommand {
+    pub fn parse(body: &[u8]) -> Option<AdminCommand> {
+        let body = String::from_utf8_lossy(body);
+        let split = body.trim().split(' ').collect::<Vec<&str>>();
+        match split.as_slice() {
+            ["state", state] => Some(AdminCommand::SetState(MarketState::parse(state)?)),
+            ["uncross"] => Some(AdminCommand::Uncross),
+            ["reference_price", price] => price.parse::<f64>().ok().filter(|price| *price > 0.0).map(AdminCommand::SetReferencePrice),
+            ["mass_cancel", conditions @ ..] => AdminCommand::parse_filter(conditions).map(AdminCommand::MassCancel),
+            _ => None
+        }
+    }
+
+    // 每个条件最多出现一次
+    fn parse_filter(conditions: &[&str]) -> Option<CancelFilter> {
+        let mut filter = CancelFilter::all();
+        let price = |price: &str| price.parse::<f64>().ok().filter(|price| *price > 0.0);
+        for condition in conditions {
+            match condition.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+                ["owner", owner] if filter.owner.is_none() && !owner.is_empty() => filter.owner = Some(owner.to_string()),
+                ["side", "buy"] if filter.side.is_none() => filter.side = Some(Side::Buy),
+                ["side", "sell"] if filter.side.is_none() => filter.side = Some(Side::Sell),
+                ["min", min_price] if filter.min_price.is_none() => filter.min_price = Some(price(min_price)?),
+                ["max", max_price] if filter.max_price.is_none() => filter.max_price = Some(price(max_price)?),
+                _ => return None
+            }
+        }
+        Some(filter)
+    }
+}
+
+// 已经撮合完、成交还在异步写入的消息，等持久化屏障返回后再统一ack
+pub struct PendingAcks<D> {
+    deliveries: Vec<D>,
+}
+
+impl<D> PendingAcks<D> {
+    pub fn new() -> PendingAcks<D> {
+        PendingAcks { deliveries: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deliveries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deliveries.is_empty()
+    }
+
+    // flushed是持久化屏障的结果: 成功时全部ack
+    // 失败时这些消息的成交可能没有写入，内存中的订单簿已经和数据库不一致，不能继续撮合:
+    // 全部重新投递并返回错误，调用者应该停止消费并退出，重启后从日志恢复（重新投递的消息按client_order_id去重）
+    pub fn settle<B>(&mut self, broker: &B, flushed: Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>>
+    where B: Broker<Delivery = D>
+    {
+        match flushed {
+            Ok(()) => {
+                for delivery in self.deliveries.drain(..) {
+                    broker.ack(delivery)?;
+                }
+                Ok(())
+            },
+            Err(err) => {
+                for delivery in self.deliveries.drain(..) {
+                    broker.nack(delivery, true)?;
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+// 处理一条下单消息，submit返回后根据结果nack，或者放进pending等成交写入后再ack
+// 下单失败时连同处理方式一起返回submit的错误，由调用者记录
+pub fn handle<B, F>(broker: &B, pending: &mut PendingAcks<B::Delivery>, delivery: B::Delivery, body: &[u8], mut submit: F) -> Result<(Settlement, Option<SubmitError>), Box<dyn Error>>
+where B: Broker, F: FnMut(&OrderMessage) -> Result<u64, SubmitError>
+{
+    let (settlement, error) = match OrderMessage::parse(body) {
+        Some(message) => {
+            let result = submit(&message);
+            (Settlement::of(&result), result.err())
+        },
+        None => (Settlement::DeadLetter, None)
+    };
+
+    match settlement {
+        Settlement::Ack => pending.deliveries.push(delivery),
+        Settlement::Requeue => broker.nack(delivery, true)?,
+        Settlement::DeadLetter => broker.nack(delivery, false)?,
+    };
+    Ok((settlement, error))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use super::{handle, AdminCommand, Broker, OrderMessage, PendingAcks, Settlement};
+    use crate::engine::Engine;
+    use crate::engine::CancelFilter;
+    use crate::engine::LimitOrder;
+    use crate::engine::Condition;
+    use crate::engine::MarketState;
+    use crate::engine::Side;
+    use crate::engine::TradeEvent;
+    use crate::errors::SubmitError;
+    use crate::errors::TinyError;
+    use crate::persister::Batch;
+    use crate::persister::Persister;
+
+    // 本地的broker替身，记录每个delivery tag最后的处理方式
+    struct FakeBroker {
+        settled: RefCell<Vec<(u64, Settlement)>>,
+    }
+
+    impl FakeBroker {
+        fn new() -> FakeBroker {
+            FakeBroker { settled: RefCell::new(Vec::new()) }
+        }
+    }
+
+    impl Broker for FakeBroker {
+        type Delivery = u64;
+
+        fn ack(&self, delivery_tag: u64) -> Result<(), Box<dyn Error>> {
+            self.settled.borrow_mut().push((delivery_tag, Settlement::Ack));
+            Ok(())
+        }
+
+        fn nack(&self, delivery_tag: u64, requeue: bool) -> Result<(), Box<dyn Error>> {
+            let settlement = if requeue { Settlement::Requeue } else { Settlement::DeadLetter };
+            self.settled.borrow_mut().push((delivery_tag, settlement));
+            Ok(())
+        }
+    }
+
+    fn on_cancel(_order_id: u64) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    #[test]
+    fn acks_only_after_trades_are_persisted() {
+        let broker = FakeBroker::new();
+        let mut pending = PendingAcks::new();
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let store_fails = Arc::new(AtomicBool::new(false));
+        let (writer_stored, writer_store_fails) = (stored.clone(), store_fails.clone());
+        let persister = Persister::start(16, 16, move |batch: &Batch| -> Result<(), Box<dyn Error>> {
+            if writer_store_fails.load(Ordering::SeqCst) {
+                return Err(Box::new(TinyError::new("mysql is gone")));
+            }
+            for trade in &batch.trades {
+                writer_stored.lock().unwrap().push((trade.ask_order_id, trade.bid_order_id));
+            }
+            Ok(())
+        });
+        let on_trade = |event: TradeEvent| persister.trade(event);
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        let mut next_id = 0;
+        let mut submit = |message: &OrderMessage| -> Result<u64, SubmitError> {
+            next_id += 1;
+            let side = if message.side == 0 { Side::Sell } else { Side::Buy };
+            engine.submit(LimitOrder::new(next_id, side, message.volume, message.price))
+                .map_err(|err| SubmitError::PartiallyPersisted(vec![next_id], err))?;
+            Ok(next_id)
+        };
+
+        handle(&broker, &mut pending, 1, b"1.5,2,1,u1,c1", &mut submit).unwrap();
+        handle(&broker, &mut pending, 2, b"1.5,1,0,u2,c1", &mut submit).unwrap();
+        // 成交还没确认写入，不能ack
+        assert_eq!(2, pending.len());
+        assert!(broker.settled.borrow().is_empty());
+
+        pending.settle(&broker, persister.flush()).unwrap();
+        assert_eq!(vec![(2, 1)], *stored.lock().unwrap());
+
+        store_fails.store(true, Ordering::SeqCst);
+        handle(&broker, &mut pending, 3, b"1.5,1,0,u3,c1", &mut submit).unwrap();
+        // 写入失败时重新投递，由调用者退出
+        assert_eq!("persist batch failed after 5 attempts: mysql is gone", pending.settle(&broker, persister.flush()).unwrap_err().to_string());
+
+        assert!(pending.is_empty());
+        assert_eq!(vec![
+            (1, Settlement::Ack),
+            (2, Settlement::Ack),
+            (3, Settlement::Requeue),
+        ], *broker.settled.borrow());
+    }
+
+    #[test]
+    fn requeues_when_order_was_not_created() {
+        let broker = FakeBroker::new();
+        let (settlement, error) = handle(&broker, &mut PendingAcks::new(), 7, b"1.5,2,1,u1,c1", |_message| {
+            Err(SubmitError::NotPersisted(Box::new(TinyError::new("connection refused"))))
+        }).unwrap();
+
+        assert_eq!(Settlement::Requeue, settlement);
+        assert_eq!("order not persisted: connection refused", error.unwrap().to_string());
+        assert_eq!(vec![(7, Settlement::Requeue)], *broker.settled.borrow());
+    }
+
+    #[test]
+    fn dead_letters_malformed_messages() {
+        let broker = FakeBroker::new();
+        let mut pending = PendingAcks::new();
+        let (settlement, error) = handle(&broker, &mut pending, 9, b"1.5,abc,1", |_message| Ok(1)).unwrap();
+        assert_eq!(Settlement::DeadLetter, settlement);
+        assert!(error.is_none());
+
+        let (settlement, _) = handle(&broker, &mut pending, 10, b"1.5,2,1,u1,", |_message| Ok(1)).unwrap();
+        assert_eq!(Settlement::DeadLetter, settlement);
+
+        assert_eq!(vec![(9, Settlement::DeadLetter), (10, Settlement::DeadLetter)], *broker.settled.borrow());
+    }
+
+    #[test]
+    fn parses_client_order_id() {
+        let message = OrderMessage::parse(b"0.000003456,11.000000035,0,u123456,web-42").unwrap();
+        assert_eq!(OrderMessage {
+            price: 0.000003456,
+            volume: 11.000000035,
+            side: 0,
+            user_id: "u123456".to_string(),
+            client_order_id: "web-42".to_string(),
+            condition: Condition::None,
+        }, message);
+
+        assert_eq!(Condition::AllOrNone, OrderMessage::parse(b"1.5,2,1,u1,web-43,aon").unwrap().condition);
+        assert_eq!(Condition::MinFill(0.5), OrderMessage::parse(b"1.5,2,1,u1,web-44,min=0.5").unwrap().condition);
+        assert_eq!(Condition::MinTotal(1.0), OrderMessage::parse(b"1.5,2,1,u1,web-45,min_total=1").unwrap().condition);
+        assert_eq!(None, OrderMessage::parse(b"1.5,2,1,u1,web-46,min=0"));
+        assert_eq!(None, OrderMessage::parse(b"1.5,2,1,u1,web-47,fok"));
+        assert_eq!(None, OrderMessage::parse(b"1.5,2,1,-,web-48"));
+        assert_eq!(None, OrderMessage::parse(b"1.5,2,1,u 1,web-49"));
+        assert_eq!(None, OrderMessage::parse(b"1.5,2,1,,web-50"));
+    }
+
+    #[test]
+    fn parses_admin_commands() {
+        assert_eq!(Some(AdminCommand::SetState(MarketState::CancelOnly)), AdminCommand::parse(b"state cancel_only\n"));
+        assert_eq!(None, AdminCommand::parse(b"state closed"));
+        assert_eq!(None, AdminCommand::parse(b"halt"));
+        assert_eq!(Some(AdminCommand::SetReferencePrice(0.035)), AdminCommand::parse(b"reference_price 0.035"));
+        assert_eq!(None, AdminCommand::parse(b"reference_price -1"));
+        assert_eq!(Some(AdminCommand::Uncross), AdminCommand::parse(b"uncross\n"));
+
+        assert_eq!(Some(AdminCommand::MassCancel(CancelFilter::all())), AdminCommand::parse(b"mass_cancel"));
+        let filter = CancelFilter::all().with_owner("u1").with_side(Side::Sell).with_price_range(Some(1.5), Some(2.0));
+        assert_eq!(Some(AdminCommand::MassCancel(filter)), AdminCommand::parse(b"mass_cancel owner=u1 side=sell min=1.5 max=2\n"));
+        assert_eq!(Some(AdminCommand::MassCancel(CancelFilter::all().with_price_range(None, Some(2.0)))), AdminCommand::parse(b"mass_cancel max=2"));
+        assert_eq!(None, AdminCommand::parse(b"mass_cancel side=both"));
+        assert_eq!(None, AdminCommand::parse(b"mass_cancel min=-1"));
+        assert_eq!(None, AdminCommand::parse(b"mass_cancel owner=u1 owner=u2"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::fmt;
+use bigdecimal::BigDecimal;
+
+use crate::engine::OrderBookPair;
+use crate::accounts::decimal;
+
+// 集合竞价的撮合结果: 统一的成交价格、成交量，以及剩余的不平衡量（买量减卖量，负数表示卖方剩余）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uncross {
+    pub price: f64,
+    pub volume: f64,
+    pub imbalance: f64,
+}
+
+impl fmt::Display for Uncross {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "auction price={} volume={} imbalance={}", self.price, self.volume, self.imbalance)
+    }
+}
+
+// 在订单簿的所有挂单价格里找成交量最大的价格，相同时依次比较:
+//   1. 不平衡量的绝对值最小
+//   2. 市场压力: 都是买方剩余时取最高价，都是卖方剩余时取最低价
+//   3. 离参考价最近，一样近时取低价；没有参考价时取最低价
+// 没有可以成交的数量时返回None
+pub fn equilibrium(order_book_pair: &OrderBookPair, reference: Option<f64>) -> Option<Uncross> {
+    let buy_book = &order_book_pair.buy_order_book;
+    let sell_book = &order_book_pair.sell_order_book;
+    let mut prices = buy_book.iter().chain(sell_book.iter()).map(|order| order.price).collect::<Vec<f64>>();
+    prices.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    prices.dedup();
+
+    let zero = BigDecimal::from(0);
+    let mut candidates = prices.into_iter()
+        .map(|price| {
+            let demand = buy_book.iter().filter(|order| order.price >= price).fold(zero.clone(), |sum, order| sum + decimal(order.volume));
+            let supply = sell_book.iter().filter(|order| order.price <= price).fold(zero.clone(), |sum, order| sum + decimal(order.volume));
+            let volume = if demand < supply { demand.clone() } else { supply.clone() };
+            (price, volume, demand - supply)
+        })
+        .filter(|(_price, volume, _imbalance)| *volume > zero)
+        .collect::<Vec<(f64, BigDecimal, BigDecimal)>>();
+
+    let max_volume = candidates.iter().map(|(_price, volume, _imbalance)| volume.clone()).max()?;
+    candidates.retain(|(_price, volume, _imbalance)| *volume == max_volume);
+    let min_imbalance = candidates.iter().map(|(_price, _volume, imbalance)| imbalance.abs()).min().unwrap();
+    candidates.retain(|(_price, _volume, imbalance)| imbalance.abs() == min_imbalance);
+
+    // 候选价格按从低到高排列
+    let index = if candidates.iter().all(|(_price, _volume, imbalance)| *imbalance > zero) {
+        candidates.len() - 1
+    } else if candidates.iter().all(|(_price, _volume, imbalance)| *imbalance < zero) {
+        0
+    } else {
+        match reference {
+            Some(reference) => {
+                let reference = decimal(reference);
+                let distances = candidates.iter().map(|(price, _volume, _imbalance)| (decimal(*price) - reference.clone()).abs()).collect::<Vec<BigDecimal>>();
+                let min_distance = distances.iter().min().unwrap();
+                distances.iter().position(|distance| distance == min_distance).unwrap()
+            },
+            None => 0
+        }
+    };
+
+    let (price, volume, imbalance) = candidates.swap_remove(index);
+    Some(Uncross {
+        price: price,
+        volume: volume.to_string().parse::<f64>().unwrap(),
+        imbalance: imbalance.to_string().parse::<f64>().unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::equilibrium;
+    use crate::engine::LimitOrder;
+    use crate::engine::OrderBookPair;
+    use crate::engine::Side;
+
+    fn create_book_pair(orders: &[(u64, Side, f64, f64)]) -> OrderBookPair {
+        let mut order_book_pair = OrderBookPair::new();
+        for (id, side, volume, price) in orders {
+            let (book, _counter_book) = order_book_pair.get_books_mut(*side);
+            book.add(LimitOrder::new(*id, *side, *volume, *price));
+        }
+        order_book_pair
+    }
+
+    #[test]
+    fn maximizes_matched_volume() {
+        let order_book_pair = create_book_pair(&[
+            (1, Side::Buy, 3.0, 10.2),
+            (2, Side::Buy, 2.0, 10.1),
+            (3, Side::Buy, 1.0, 9.9),
+            (4, Side::Sell, 1.0, 9.8),
+            (5, Side::Sell, 2.0, 10.0),
+            (6, Side::Sell, 4.0, 10.3),
+        ]);
+        // 10.0、10.1和10.2都能成交3，只有10.2时买卖刚好相等
+        let uncross = equilibrium(&order_book_pair, None).unwrap();
+        assert_eq!((10.2, 3.0, 0.0), (uncross.price, uncross.volume, uncross.imbalance));
+
+        assert_eq!(None, equilibrium(&create_book_pair(&[(1, Side::Buy, 1.0, 9.0), (2, Side::Sell, 1.0, 9.5)]), None));
+    }
+
+    #[test]
+    fn breaks_ties_by_pressure_and_reference() {
+        // 9.9到10.1都成交2、不平衡量为0，按参考价取
+        let order_book_pair = create_book_pair(&[
+            (1, Side::Buy, 2.0, 10.1),
+            (2, Side::Sell, 2.0, 9.9),
+        ]);
+        assert_eq!(9.9, equilibrium(&order_book_pair, None).unwrap().price);
+        assert_eq!(10.1, equilibrium(&order_book_pair, Some(10.08)).unwrap().price);
+        assert_eq!(9.9, equilibrium(&order_book_pair, Some(10.0)).unwrap().price);
+
+        // 都是买方剩余时取最高价
+        let order_book_pair = create_book_pair(&[
+            (1, Side::Buy, 3.0, 10.1),
+            (2, Side::Sell, 2.0, 9.9),
+        ]);
+        assert_eq!(10.1, equilibrium(&order_book_pair, Some(9.0)).unwrap().price);
+    }
+}
@@ -1,16 +1,68 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use bigdecimal::BigDecimal;
+
 use crate::engine::Side;
 use crate::engine::OrderBook;
 use crate::engine::OrderBookPair;
 use crate::engine::LimitOrder;
+use crate::engine::Condition;
+use crate::engine::Journal;
+use crate::engine::Command;
+use crate::engine::Snapshots;
+use crate::engine::snapshot::Snapshot;
+use crate::accounts::decimal;
+use crate::engine::MarketState;
+use crate::engine::MarketStateEvent;
+use crate::engine::PriceBand;
+use crate::engine::BandAction;
+use crate::engine::BandReference;
+use crate::engine::Uncross;
+use crate::engine::equilibrium;
+use crate::engine::MatchingPolicy;
+use crate::engine::PriceTime;
+use crate::engine::OrderGroups;
+use crate::engine::Peg;
+use crate::engine::PeggedOrders;
+use crate::errors::TinyError;
 
 pub struct Engine<'a>
 {
     pub order_book_pair: OrderBookPair,
-    on_trade: &'a dyn Fn(TradeEvent),
-    on_cancel: &'a dyn Fn(u64),
+    on_trade: &'a dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>,
+    on_cancel: &'a dyn Fn(u64) -> Result<(), Box<dyn Error>>,
+    journal: Option<Journal>,
+    snapshots: Option<Snapshots>,
+    on_mass_cancel: Option<&'a dyn Fn(MassCancelEvent) -> Result<(), Box<dyn Error>>>,
+    on_reduce: Option<&'a dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>>,
+    on_state_change: Option<&'a dyn Fn(MarketStateEvent) -> Result<(), Box<dyn Error>>>,
+    on_indicative: Option<&'a dyn Fn(Option<Uncross>) -> Result<(), Box<dyn Error>>>,
+    // 快照失败不影响命令的结果，交给调用者记录
+    on_snapshot_error: Option<&'a dyn Fn(Box<dyn Error>)>,
+    state: MarketState,
+    // 批量撮合: 订单只挂单，定期按统一价格清算一次
+    batch: bool,
+    // 同一价位上的分配规则，默认价格优先、时间优先
+    policy: Arc<dyn MatchingPolicy>,
+    price_band: Option<PriceBand>,
+    // 价格带的参考价，没有时不限制成交价格
+    reference_price: Option<f64>,
+    groups: OrderGroups,
+    // 入场单已经全部成交、等待挂出的括号单出场单: (入场单id, 出场单, 出场单)
+    activated: Vec<(u64, LimitOrder, LimitOrder)>,
+    // 最近一次批量撤单的汇总，由mass_cancel返回给调用者
+    mass_cancelled: Option<MassCancelEvent>,
+    pegs: PeggedOrders,
+    // 最后一笔成交的编号，成交编号由引擎按顺序分配，重放时得到相同的编号
+    last_trade_id: u64,
+    // 进入过引擎的最大订单id，重启时比它大的挂单是写了数据库、还没写日志的订单
+    last_order_id: u64,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct TradeEvent {
+    pub id: u64,
     pub price: f64,
     pub volume: f64,
     pub funds: f64,
@@ -18,133 +70,1053 @@ pub struct TradeEvent {
     pub ask_order_filled: bool,
     pub bid_order_id: u64,
     pub bid_order_filled: bool,
+    // 主动成交的一方（taker）的方向，另一方是挂单的maker
+    pub taker_side: Side,
+    pub maker_order_id: u64,
+    pub taker_order_id: u64,
+    pub ask_owner: String,
+    pub bid_owner: String,
+}
+
+// 成交的规范文本表示，重放日志时用来逐字节比对
+impl fmt::Display for TradeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "trade id={} price={} volume={} funds={} ask={}:{}:{} bid={}:{}:{} taker={}",
+            self.id, self.price, self.volume, self.funds,
+            self.ask_order_id, self.ask_order_filled, self.ask_owner,
+            self.bid_order_id, self.bid_order_filled, self.bid_owner,
+            self.taker_side)
+    }
+}
+
+// 批量撤单的条件，None表示不限；价格范围包含两端
+#[derive(Debug, Clone, PartialEq)]
+pub struct CancelFilter {
+    pub owner: Option<String>,
+    pub side: Option<Side>,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    // 只撤这些订单，比如一个会话下的订单
+    pub order_ids: Option<Vec<u64>>,
+}
+
+impl CancelFilter {
+    // 这个市场的所有订单
+    pub fn all() -> CancelFilter {
+        CancelFilter {
+            owner: None,
+            side: None,
+            min_price: None,
+            max_price: None,
+            order_ids: None,
+        }
+    }
+
+    pub fn with_owner(mut self, owner: &str) -> CancelFilter {
+        self.owner = Some(owner.to_string());
+        self
+    }
+
+    pub fn with_side(mut self, side: Side) -> CancelFilter {
+        self.side = Some(side);
+        self
+    }
+
+    pub fn with_price_range(mut self, min_price: Option<f64>, max_price: Option<f64>) -> CancelFilter {
+        self.min_price = min_price;
+        self.max_price = max_price;
+        self
+    }
+
+    pub fn with_order_ids(mut self, order_ids: Vec<u64>) -> CancelFilter {
+        self.order_ids = Some(order_ids);
+        self
+    }
+
+    pub fn matches(&self, order: &LimitOrder) -> bool {
+        self.owner.as_ref().map_or(true, |owner| *owner == order.owner)
+            && self.side.map_or(true, |side| side == order.side)
+            && self.min_price.map_or(true, |min_price| order.price >= min_price)
+            && self.max_price.map_or(true, |max_price| order.price <= max_price)
+            && self.order_ids.as_ref().map_or(true, |order_ids| order_ids.contains(&order.id))
+    }
+}
+
+// 一次批量撤单的汇总，在每个订单的撤单事件之后发出
+// order_ids包括跟着撤掉的OCO的另一条腿，volume是撤掉的挂单剩余数量之和
+#[derive(Debug, Clone, PartialEq)]
+pub struct MassCancelEvent {
+    pub filter: CancelFilter,
+    pub order_ids: Vec<u64>,
+    pub volume: f64,
+}
+
+impl fmt::Display for MassCancelEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mass_cancel count={} volume={} orders={:?}", self.order_ids.len(), self.volume, self.order_ids)
+    }
+}
+
+// 改单原地减少了挂单的数量，数据库里的订单要扣减同样的数量并退回对应的冻结
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReduceEvent {
+    pub order_id: u64,
+    // 减少的数量
+    pub reduced: f64,
+    // 减少之后的剩余数量
+    pub volume: f64,
+}
+
+// 一次撮合的结果: 被价格带挡住的对手价，最后一笔成交价，
+// 因为OCO的另一条腿有成交要撤销的订单，和全部成交的订单
+#[derive(Default)]
+struct Matching {
+    breach: Option<f64>,
+    last_price: Option<f64>,
+    cancelled: Vec<u64>,
+    filled: Vec<u64>,
+}
+
+fn ignore_trade(_event: TradeEvent) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+fn ignore_cancel(_order_id: u64) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+fn ignore_reduce(_event: ReduceEvent) -> Result<(), Box<dyn Error>> {
+    Ok(())
 }
 
 impl<'a> Engine<'a> 
 {
-    pub fn new<'b>(on_trade: &'b dyn Fn(TradeEvent), on_cancel: &'b dyn Fn(u64)) -> Engine<'b> {
+    // 回调返回错误时撮合照常进行（内存中的订单簿已经改变），但submit/cancel会把第一个错误返回给调用者，
+    // 调用者据此决定是否确认消息
+    pub fn new<'b>(on_trade: &'b dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &'b dyn Fn(u64) -> Result<(), Box<dyn Error>>) -> Engine<'b> {
         Engine {
             order_book_pair: OrderBookPair::new(),
             on_trade: on_trade,
             on_cancel: on_cancel,
+            journal: None,
+            snapshots: None,
+            on_mass_cancel: None,
+            on_reduce: None,
+            on_state_change: None,
+            on_indicative: None,
+            on_snapshot_error: None,
+            state: MarketState::Continuous,
+            batch: false,
+            policy: Arc::new(PriceTime),
+            price_band: None,
+            reference_price: None,
+            groups: OrderGroups::new(),
+            activated: Vec::new(),
+            mass_cancelled: None,
+            pegs: PeggedOrders::new(),
+            last_trade_id: 0,
+            last_order_id: 0,
         }
     }
 
-    pub fn cancel(&mut self, order: LimitOrder) {
-        let (book, counter_book) = self.order_book_pair.get_books_mut(order.side);
-        match book.remove(&order) {
-            Some(removed_order) => (self.on_cancel)(removed_order.id),
-            None => ()
+    // 设置之后，每个命令在撮合之前先写入日志
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    pub fn cancel(&mut self, order: LimitOrder) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::Cancel(order))
+    }
+
+    // 在一条命令里撤掉所有符合条件的挂单，每个订单触发一次on_cancel，最后发出汇总
+    // 返回这次批量撤单的汇总
+    pub fn mass_cancel(&mut self, filter: CancelFilter) -> Result<MassCancelEvent, Box<dyn Error>> {
+        self.execute(Command::MassCancel(filter))?;
+        Ok(self.mass_cancelled.take().unwrap())
+    }
+
+    pub fn set_on_mass_cancel(&mut self, on_mass_cancel: &'a dyn Fn(MassCancelEvent) -> Result<(), Box<dyn Error>>) {
+        self.on_mass_cancel = Some(on_mass_cancel);
+    }
+
+    // 把已经在数据库中挂单的订单直接放回订单簿，不撮合也不产生成交，用于重启后恢复
+    pub fn restore(&mut self, order: LimitOrder) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::Restore(order))
+    }
+
+    // 把数据库中的挂钩订单放回订单簿，订单的价格是限价，按当前不含挂钩订单的最优价定价，不撮合
+    // 要在普通挂单都放回之后调用，否则参考价还不完整
+    pub fn restore_pegged(&mut self, order: LimitOrder, peg: Peg) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::RestorePeg(order, peg))
+    }
+
+    // 恢复两条已经放回订单簿的腿之间的OCO关系
+    pub fn restore_oco(&mut self, first_id: u64, second_id: u64) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::RestoreOco(first_id, second_id))
+    }
+
+    // 恢复已经放回订单簿的入场单还没挂出的出场单
+    pub fn restore_bracket(&mut self, entry_id: u64, exits: (LimitOrder, LimitOrder)) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::RestoreBracket(entry_id, exits.0, exits.1))
+    }
+
+    pub fn submit(&mut self, order: LimitOrder) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::Submit(order))
+    }
+
+    // 提交一对OCO订单: 先撮合第一条腿，有成交时第二条腿直接撤销，否则再撮合第二条腿
+    // 之后任何一条腿有成交或者被撤销，另一条腿自动撤销
+    pub fn submit_oco(&mut self, first: LimitOrder, second: LimitOrder) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::SubmitOco(first, second))
+    }
+
+    // 提交括号单: 入场单全部成交之后，两条出场单作为OCO等待调用者分配id后挂出（见take_activated）
+    // 入场单在全部成交之前被撤销时出场单作废
+    pub fn submit_bracket(&mut self, entry: LimitOrder, exits: (LimitOrder, LimitOrder)) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::SubmitBracket(entry, exits.0, exits.1))
+    }
+
+    // 取走已经可以挂出的括号单出场单
+    pub fn take_activated(&mut self) -> Vec<(u64, LimitOrder, LimitOrder)> {
+        self.activated.drain(..).collect()
+    }
+
+    pub fn groups(&self) -> &OrderGroups {
+        &self.groups
+    }
+
+    // 提交挂钩订单，价格跟着不含挂钩订单的最优价变化，还没有参考价时用订单本身的价格
+    pub fn submit_pegged(&mut self, order: LimitOrder, peg: Peg) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::SubmitPeg(order, peg))
+    }
+
+    pub fn pegs(&self) -> &PeggedOrders {
+        &self.pegs
+    }
+
+    // 修改挂单: 价格不变且数量减少时原地修改，保留时间优先，减少的数量交给on_reduce；
+    // 否则撤掉后按新价格和数量重新提交。新数量为0等同于撤单
+    pub fn amend(&mut self, order: LimitOrder, price: f64, volume: f64) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::Amend(order, price, volume))
+    }
+
+    pub fn set_on_reduce(&mut self, on_reduce: &'a dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>) {
+        self.on_reduce = Some(on_reduce);
+    }
+
+    // 切换市场状态，之后的命令按新状态检查
+    pub fn set_state(&mut self, state: MarketState) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::SetState(state))
+    }
+
+    pub fn state(&self) -> MarketState {
+        self.state
+    }
+
+    pub fn set_on_state_change(&mut self, on_state_change: &'a dyn Fn(MarketStateEvent) -> Result<(), Box<dyn Error>>) {
+        self.on_state_change = Some(on_state_change);
+    }
+
+    // 结束集合竞价: 按成交量最大的价格统一成交，然后回到连续撮合
+    pub fn uncross(&mut self) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::Uncross)
+    }
+
+    // 集合竞价期间当前订单簿的参考成交价和成交量，没有可以成交的订单时为None
+    pub fn indicative(&self) -> Option<Uncross> {
+        equilibrium(&self.order_book_pair, self.reference_price)
+    }
+
+    // 集合竞价期间每条命令执行之后发出最新的参考成交价
+    pub fn set_on_indicative(&mut self, on_indicative: &'a dyn Fn(Option<Uncross>) -> Result<(), Box<dyn Error>>) {
+        self.on_indicative = Some(on_indicative);
+    }
+
+    // 定期快照写入失败时调用，不设置时忽略，下一次到期时再做
+    pub fn set_on_snapshot_error(&mut self, on_snapshot_error: &'a dyn Fn(Box<dyn Error>)) {
+        self.on_snapshot_error = Some(on_snapshot_error);
+    }
+
+    // 撮合规则是配置，不写日志，重放日志之前要设置成同样的规则
+    pub fn set_matching_policy(&mut self, policy: Arc<dyn MatchingPolicy>) {
+        self.policy = policy;
+    }
+
+    // 批量撮合模式是配置，不写日志，重放日志之前要设置成同样的模式
+    pub fn set_batch_mode(&mut self, batch: bool) {
+        self.batch = batch;
+    }
+
+    pub fn batch_mode(&self) -> bool {
+        self.batch
+    }
+
+    // 批量撮合模式下清算这一批: 和集合竞价一样按成交量最大的价格统一成交，成交后仍然是批量撮合
+    pub fn clear_batch(&mut self) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::ClearBatch)
+    }
+
+    // 价格带是配置，不写日志，重放日志之前要设置成同样的价格带
+    pub fn set_price_band(&mut self, price_band: PriceBand) {
+        self.price_band = Some(price_band);
+    }
+
+    // 指数价格，或者重启后用最后一笔成交价初始化参考价
+    pub fn set_reference_price(&mut self, price: f64) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::SetReferencePrice(price))
+    }
+
+    pub fn reference_price(&self) -> Option<f64> {
+        self.reference_price
+    }
+
+    // 当前状态是否接受这条命令，不接受的命令不写日志也不执行
+    // 只挂单状态下会立即成交的下单和改单也不接受
+    pub fn check(&self, command: &Command) -> Result<(), Box<dyn Error>> {
+        let action = match command {
+            Command::Submit(_) | Command::SubmitOco(_, _) | Command::SubmitBracket(_, _, _) | Command::SubmitPeg(_, _) => "submit",
+            Command::Amend(_, _, _) => "amend",
+            Command::Cancel(_) => "cancel",
+            Command::MassCancel(_) => "mass cancel",
+            Command::Uncross => "uncross",
+            Command::ClearBatch if !self.batch => return Err(Box::new(TinyError::new("batch mode is off, clear batch rejected"))),
+            Command::ClearBatch => "clear batch",
+            _ => return Ok(())
         };
+        let accepted = match (self.state, command) {
+            (MarketState::Auction, _) => true,
+            (_, Command::Uncross) => false,
+            (MarketState::Continuous, Command::ClearBatch) | (MarketState::PostOnly, Command::ClearBatch) => true,
+            (_, Command::ClearBatch) => false,
+            (MarketState::Continuous, _) => true,
+            (MarketState::Halted, _) => false,
+            (MarketState::CancelOnly, Command::Cancel(_)) | (MarketState::CancelOnly, Command::MassCancel(_)) => true,
+            (MarketState::CancelOnly, _) => false,
+            (MarketState::PostOnly, Command::Submit(order)) => !self.crosses(order),
+            (MarketState::PostOnly, Command::SubmitOco(first, second)) => !self.crosses(first) && !self.crosses(second),
+            (MarketState::PostOnly, Command::SubmitBracket(entry, _, _)) => !self.crosses(entry),
+            (MarketState::PostOnly, Command::SubmitPeg(order, peg)) => !self.crosses(&self.pegged(order.clone(), peg)),
+            (MarketState::PostOnly, Command::Amend(order, price, volume)) => !self.crosses(&LimitOrder::new(order.id, order.side, *volume, *price)),
+            (MarketState::PostOnly, _) => true,
+        };
+        if accepted {
+            Ok(())
+        } else {
+            Err(Box::new(TinyError::new(&format!("market is {}, {} rejected", self.state, action))))
+        }
     }
 
-    pub fn submit(&mut self, mut order: LimitOrder) {
-        let (book, counter_book) = self.order_book_pair.get_books_mut(order.side);
-        let on_trade = &(self.on_trade);
-        Engine::do_matching(on_trade, &mut order, counter_book);
-        if !order.filled() {
-            book.add(order);
+    fn crosses(&self, order: &LimitOrder) -> bool {
+        let (_book, counter_book) = self.order_book_pair.get_books(order.side);
+        match counter_book.top() {
+            Some(counter_order) => order.trade_with(counter_order).is_some(),
+            None => false
         }
     }
 
-    fn do_matching(on_trade: &dyn Fn(TradeEvent), order: &mut LimitOrder, counter_book: &mut OrderBook) {
-        match counter_book.top_mut() {
-            Some(counter_order) => {
-                match order.trade_with(counter_order) {
-                    Some((trade_price, trade_volume, trade_funds)) => {
+    // 从数据库恢复时，接着数据库里最后一笔成交的编号继续分配
+    pub fn set_last_trade_id(&mut self, last_trade_id: u64) -> Result<(), Box<dyn Error>> {
+        self.execute(Command::SetLastTradeId(last_trade_id))
+    }
 
-                        let order_id = order.id;
-                        let counter_order_id = counter_order.id;
+    pub fn last_trade_id(&self) -> u64 {
+        self.last_trade_id
+    }
 
-                        // fill orders
-                        order.fill(trade_volume);
-                        counter_order.fill(trade_volume);
+    // 设置之后，每隔一定数量的日志记录做一次订单簿快照，只在设置了日志时生效
+    pub fn set_snapshots(&mut self, snapshots: Snapshots) {
+        self.snapshots = Some(snapshots);
+    }
 
-                        // filled?
-                        let order_filled = order.filled();
-                        let cloned_counter_order = counter_order.clone();
-                        let counter_order_filled = cloned_counter_order.filled();
+    pub fn last_order_id(&self) -> u64 {
+        self.last_order_id
+    }
 
-                        // if counter_order has filled, remove it from counter_book
-                        if counter_order_filled {
-                            counter_book.remove(&cloned_counter_order);
-                        }
+    // 用快照替换当前的订单簿和成交编号，之后应该重放快照之后的日志
+    pub fn load(&mut self, snapshot: Snapshot) {
+        self.order_book_pair = snapshot.order_book_pair;
+        self.last_trade_id = snapshot.last_trade_id;
+        self.last_order_id = snapshot.last_order_id;
+        self.reference_price = snapshot.reference_price;
+        self.groups = snapshot.groups;
+        self.pegs = snapshot.pegs;
+    }
+
+    // 按顺序重放日志里的命令，重放的命令不会再写入日志
+    // emit为false时不触发回调，只重建订单簿
+    pub fn replay(&mut self, records: Vec<(u64, Command)>, emit: bool) -> Result<(), Box<dyn Error>> {
+        for (_seq, command) in records {
+            self.apply(command, emit)?;
+        }
+        Ok(())
+    }
+
+    // 崩溃恢复: 重放日志，只把崩溃前还没有写入数据库的成交、撤单和减量交给回调
+    // 编号大于persisted_trade_id的成交没有写入；撤单和减量分别由unpersisted_cancel和unpersisted_reduce按数据库里的订单判断
+    // 市场状态、批量撤单汇总这些通知不再发出
+    pub fn recover(&mut self, records: Vec<(u64, Command)>, persisted_trade_id: u64, unpersisted_cancel: &dyn Fn(u64) -> Result<bool, Box<dyn Error>>, unpersisted_reduce: &dyn Fn(&ReduceEvent) -> Result<bool, Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+        let (on_trade, on_cancel, on_reduce) = (self.on_trade, self.on_cancel, self.on_reduce.unwrap_or(&ignore_reduce));
+        let on_trade = |event: TradeEvent| if event.id > persisted_trade_id { on_trade(event) } else { Ok(()) };
+        let on_cancel = |order_id: u64| if unpersisted_cancel(order_id)? { on_cancel(order_id) } else { Ok(()) };
+        let on_reduce = |event: ReduceEvent| if unpersisted_reduce(&event)? { on_reduce(event) } else { Ok(()) };
+        for (_seq, command) in records {
+            self.apply_with(&on_trade, &on_cancel, &on_reduce, command, false)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, command: Command) -> Result<(), Box<dyn Error>> {
+        self.check(&command)?;
+        let seq = match self.journal.as_mut() {
+            Some(journal) => Some(journal.append(&command)?),
+            None => None
+        };
+        let mut result = self.apply(command, true);
+        if let (MarketState::Auction, Some(on_indicative)) = (self.state, self.on_indicative) {
+            result = result.and(on_indicative(self.indicative()));
+        }
+
+        if let (Some(seq), Some(snapshots), Some(journal)) = (seq, self.snapshots.as_ref(), self.journal.as_mut()) {
+            if snapshots.is_due(seq) {
+                // 快照失败不影响这条命令，下一次再做
+                if let Err(err) = snapshots.take(seq, &self.order_book_pair, self.last_trade_id, self.last_order_id, self.reference_price, &self.groups, &self.pegs, journal) {
+                    if let Some(on_snapshot_error) = self.on_snapshot_error {
+                        on_snapshot_error(Box::new(TinyError::new(&format!("snapshot at {} failed: {}", seq, err))));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    fn apply(&mut self, command: Command, emit: bool) -> Result<(), Box<dyn Error>> {
+        let (on_trade, on_cancel, on_reduce) = if emit {
+            (self.on_trade, self.on_cancel, self.on_reduce.unwrap_or(&ignore_reduce))
+        } else {
+            (&ignore_trade as &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, &ignore_cancel as &dyn Fn(u64) -> Result<(), Box<dyn Error>>, &ignore_reduce as &dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>)
+        };
+        self.apply_with(on_trade, on_cancel, on_reduce, command, emit)
+    }
+
+    fn apply_with(&mut self, on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, on_reduce: &dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>, command: Command, emit: bool) -> Result<(), Box<dyn Error>> {
+        let result = self.apply_command(on_trade, on_cancel, on_reduce, command, emit);
+        // 每条命令都可能改变最优价，挂钩订单跟着重新定价
+        result.and(self.reprice_pegs(on_trade, on_cancel, emit))
+    }
+
+    fn apply_command(&mut self, on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, on_reduce: &dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>, command: Command, emit: bool) -> Result<(), Box<dyn Error>> {
+        if let Some(order_id) = command.max_order_id() {
+            self.last_order_id = self.last_order_id.max(order_id);
+        }
+        let book_pair = &mut self.order_book_pair;
+        let trade_id = &mut self.last_trade_id;
 
-                        match order.side {
-                            Side::Sell => {
-                                let trade_event = TradeEvent {
-                                    price: trade_price,
-                                    volume: trade_volume,
-                                    funds: trade_funds,
-                                    ask_order_id: order_id,
-                                    ask_order_filled: order_filled,
-                                    bid_order_id: counter_order_id,
-                                    bid_order_filled: counter_order_filled,
-                                };
-                                on_trade(trade_event)
-                            },
-                            Side::Buy => {
-                                let trade_event = TradeEvent {
-                                    price: trade_price,
-                                    volume: trade_volume,
-                                    funds: trade_funds,
-                                    ask_order_id: counter_order_id,
-                                    ask_order_filled: counter_order_filled,
-                                    bid_order_id: order_id,
-                                    bid_order_filled: order_filled,
-                                };
-                                on_trade(trade_event)
-                            } 
+        match command {
+            Command::Restore(order) => {
+                let (book, _counter_book) = book_pair.get_books_mut(order.side);
+                book.add(order);
+                Ok(())
+            },
+            Command::RestorePeg(order, peg) => {
+                let order = self.pegged(order, &peg);
+                self.pegs.add(order.id, order.side, peg);
+                let (book, _counter_book) = self.order_book_pair.get_books_mut(order.side);
+                book.add(order);
+                Ok(())
+            },
+            Command::RestoreOco(first_id, second_id) => {
+                self.groups.link(first_id, second_id);
+                Ok(())
+            },
+            Command::RestoreBracket(entry_id, first, second) => {
+                self.groups.add_bracket(entry_id, (first, second));
+                Ok(())
+            },
+            Command::Submit(order) => self.submit_order(on_trade, on_cancel, order, emit),
+            Command::SubmitOco(first, second) => {
+                self.groups.link(first.id, second.id);
+                let result = self.submit_order(on_trade, on_cancel, first, emit);
+                // 第一条腿有成交或者被撤销时，第二条腿已经撤销
+                if self.groups.partner(second.id).is_some() {
+                    result.and(self.submit_order(on_trade, on_cancel, second, emit))
+                } else {
+                    result
+                }
+            },
+            Command::SubmitBracket(entry, first, second) => {
+                self.groups.add_bracket(entry.id, (first, second));
+                self.submit_order(on_trade, on_cancel, entry, emit)
+            },
+            Command::SubmitPeg(order, peg) => {
+                let order = self.pegged(order, &peg);
+                self.pegs.add(order.id, order.side, peg);
+                self.submit_order(on_trade, on_cancel, order, emit)
+            },
+            Command::Cancel(order) => {
+                let (book, _counter_book) = book_pair.get_books_mut(order.side);
+                match book.remove(&order) {
+                    Some(removed_order) => on_cancel(removed_order.id).and(self.cancel_group(on_cancel, removed_order.id)),
+                    None => Ok(())
+                }
+            },
+            Command::Amend(order, price, volume) => {
+                let (book, _counter_book) = book_pair.get_books_mut(order.side);
+                if price == order.price && volume > 0.0 {
+                    if let Some(resting_order) = book.get_mut(&order) {
+                        if volume <= resting_order.volume {
+                            let reduced = decimal(resting_order.volume) - decimal(volume);
+                            resting_order.volume = volume;
+                            if reduced == decimal(0.0) {
+                                return Ok(());
+                            }
+                            return on_reduce(ReduceEvent {
+                                order_id: resting_order.id,
+                                reduced: reduced.to_string().parse::<f64>().unwrap(),
+                                volume: volume,
+                            });
                         }
+                    }
+                }
+                match book.remove(&order) {
+                    Some(removed_order) if volume > 0.0 => {
+                        let amended_order = LimitOrder::new(removed_order.id, removed_order.side, volume, price).with_owner(&removed_order.owner).with_condition(removed_order.condition);
+                        self.submit_order(on_trade, on_cancel, amended_order, emit)
+                    },
+                    Some(removed_order) => on_cancel(removed_order.id).and(self.cancel_group(on_cancel, removed_order.id)),
+                    None => Ok(())
+                }
+            },
+            Command::MassCancel(filter) => {
+                let orders = book_pair.buy_order_book.iter()
+                    .chain(book_pair.sell_order_book.iter())
+                    .filter(|order| filter.matches(order))
+                    .cloned()
+                    .collect::<Vec<LimitOrder>>();
+                let mut result = Ok(());
+                let mut order_ids = Vec::new();
+                let mut volume = decimal(0.0);
+                for order in &orders {
+                    let (book, _counter_book) = self.order_book_pair.get_books_mut(order.side);
+                    // OCO的另一条腿可能已经跟着前面的订单撤掉了
+                    if book.remove(order).is_none() {
+                        continue;
+                    }
+                    order_ids.push(order.id);
+                    volume += decimal(order.volume);
+                    if let Some(partner) = self.groups.partner(order.id) {
+                        order_ids.push(partner);
+                        volume += self.order_book_pair.find(partner).map_or(decimal(0.0), |partner_order| decimal(partner_order.volume));
+                    }
+                    // 和撮合一样，回调出错时继续撤单，返回第一个错误
+                    result = result.and(on_cancel(order.id)).and(self.cancel_group(on_cancel, order.id));
+                }
+                let event = MassCancelEvent {
+                    filter: filter,
+                    order_ids: order_ids,
+                    volume: volume.to_string().parse::<f64>().unwrap(),
+                };
+                self.mass_cancelled = Some(event.clone());
+                match self.on_mass_cancel {
+                    Some(on_mass_cancel) if emit => result.and(on_mass_cancel(event)),
+                    _ => result
+                }
+            },
+            Command::SetState(state) => self.change_state(state, emit),
+            Command::Uncross => {
+                let result = match self.indicative() {
+                    Some(uncross) => self.uncross_at(on_trade, on_cancel, uncross.price),
+                    None => Ok(())
+                };
+                result.and(self.change_state(MarketState::Continuous, emit))
+            },
+            Command::ClearBatch => match self.indicative() {
+                Some(uncross) => self.uncross_at(on_trade, on_cancel, uncross.price),
+                None => Ok(())
+            },
+            Command::SetReferencePrice(price) => {
+                self.reference_price = Some(price);
+                Ok(())
+            },
+            Command::SetLastTradeId(last_trade_id) => {
+                *trade_id = last_trade_id;
+                Ok(())
+            }
+        }
+    }
+
+    fn change_state(&mut self, state: MarketState, emit: bool) -> Result<(), Box<dyn Error>> {
+        let event = MarketStateEvent { from: self.state, to: state };
+        self.state = state;
+        match self.on_state_change {
+            Some(on_state_change) if emit && event.from != event.to => on_state_change(event),
+            _ => Ok(())
+        }
+    }
+
+    // 订单被撤销之后撤掉OCO的另一条腿，作废括号单还没挂出的出场单
+    fn cancel_group(&mut self, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, id: u64) -> Result<(), Box<dyn Error>> {
+        self.groups.take_bracket(id);
+        match self.groups.unlink(id) {
+            Some(partner) => self.settle_groups(on_cancel, vec![partner], Vec::new()),
+            None => Ok(())
+        }
+    }
+
+    // 撮合之后撤掉OCO的另一条腿（可能还没有进入订单簿），挂出全部成交的入场单的出场单
+    fn settle_groups(&mut self, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, cancelled: Vec<u64>, filled: Vec<u64>) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        for id in cancelled {
+            if let Some(order) = self.order_book_pair.find(id).cloned() {
+                let (book, _counter_book) = self.order_book_pair.get_books_mut(order.side);
+                book.remove(&order);
+            }
+            self.groups.take_bracket(id);
+            result = result.and(on_cancel(id));
+        }
+        for id in filled {
+            if let Some((first, second)) = self.groups.take_bracket(id) {
+                self.activated.push((id, first, second));
+            }
+        }
+        result
+    }
+
+    // 不含挂钩订单的最优买价和卖价，挂钩订单不会跟着自己或者别的挂钩订单移动
+    fn unpegged_best(&self) -> (Option<f64>, Option<f64>) {
+        let best = |book: &OrderBook| book.iter().find(|order| !self.pegs.contains(order.id)).map(|order| order.price);
+        (best(&self.order_book_pair.buy_order_book), best(&self.order_book_pair.sell_order_book))
+    }
+
+    fn pegged(&self, order: LimitOrder, peg: &Peg) -> LimitOrder {
+        let (best_bid, best_ask) = self.unpegged_best();
+        let price = peg.price(order.side, best_bid, best_ask).unwrap_or(order.price);
+        LimitOrder { price: price, ..order }
+    }
+
+    // 按id的顺序给挂钩订单重新定价，价格变了的订单排到新价位的最后，已经离开订单簿的删除
+    // 连续撮合时和对手盘交叉的挂钩订单重新撮合；成交会改变最优价，所以有成交时再来一轮
+    // 没有成交时重新定价的结果不变，循环一定结束
+    fn reprice_pegs(&mut self, on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, emit: bool) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        while !self.pegs.is_empty() {
+            let (best_bid, best_ask) = self.unpegged_best();
+            let pegs = self.pegs.iter().map(|(id, side, peg)| (id, side, peg.clone())).collect::<Vec<(u64, Side, Peg)>>();
+            for (id, side, peg) in &pegs {
+                let (book, _counter_book) = self.order_book_pair.get_books_mut(*side);
+                let order = match book.find(*id) {
+                    Some(order) => order.clone(),
+                    None => {
+                        self.pegs.remove(*id);
+                        continue;
+                    }
+                };
+                let price = peg.price(*side, best_bid, best_ask).unwrap_or(order.price);
+                if price != order.price {
+                    book.remove(&order);
+                    book.add(LimitOrder { price: price, ..order });
+                }
+            }
+
+            if self.state != MarketState::Continuous || self.batch {
+                break;
+            }
+            let last_trade_id = self.last_trade_id;
+            for (id, side, _peg) in pegs {
+                let (book, counter_book) = self.order_book_pair.get_books_mut(side);
+                let order = match book.find(id) {
+                    Some(order) if counter_book.top().map_or(false, |counter_order| order.trade_with(counter_order).is_some()) => order.clone(),
+                    _ => continue
+                };
+                book.remove(&order);
+                result = result.and(self.submit_order(on_trade, on_cancel, order, emit));
+            }
+            if self.last_trade_id == last_trade_id {
+                break;
+            }
+        }
+        result
+    }
+
+    // 和submit_order一样，参考价是指数价格时只由外部设置
+    fn update_reference(&mut self, price: f64) {
+        if self.price_band.as_ref().map_or(true, |band| band.reference == BandReference::LastTrade) {
+            self.reference_price = Some(price);
+        }
+    }
+
+    // 按price撮合两边所有能成交的订单，成交价格都是price，先到的订单是maker
+    fn uncross_at(&mut self, on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, price: f64) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        loop {
+            let book_pair = &mut self.order_book_pair;
+            let (bid, ask) = match (book_pair.buy_order_book.top(), book_pair.sell_order_book.top()) {
+                (Some(bid), Some(ask)) if bid.price >= price && ask.price <= price => (bid.clone(), ask.clone()),
+                _ => break
+            };
+            let volume = bid.volume.min(ask.volume);
+            let mut filled = Vec::new();
+            for (book, order) in vec![(&mut book_pair.buy_order_book, &bid), (&mut book_pair.sell_order_book, &ask)] {
+                let resting_order = book.get_mut(order).unwrap();
+                resting_order.fill(volume);
+                filled.push(resting_order.filled());
+                if resting_order.filled() {
+                    book.remove(order);
+                }
+            }
+
+            self.last_trade_id += 1;
+            let (taker_side, maker_order_id, taker_order_id) = if bid.id > ask.id {
+                (Side::Buy, ask.id, bid.id)
+            } else {
+                (Side::Sell, bid.id, ask.id)
+            };
+            let trade_event = TradeEvent {
+                id: self.last_trade_id,
+                price: price,
+                volume: volume,
+                funds: (decimal(volume) * decimal(price)).to_string().parse::<f64>().unwrap(),
+                ask_order_id: ask.id,
+                ask_order_filled: filled[1],
+                bid_order_id: bid.id,
+                bid_order_filled: filled[0],
+                taker_side: taker_side,
+                maker_order_id: maker_order_id,
+                taker_order_id: taker_order_id,
+                ask_owner: ask.owner.clone(),
+                bid_owner: bid.owner.clone(),
+            };
+            // 和撮合一样，回调出错时继续成交，返回第一个错误
+            result = result.and(on_trade(trade_event));
+            self.update_reference(price);
+
+            let cancelled = [bid.id, ask.id].iter().filter_map(|id| self.groups.unlink(*id)).collect();
+            let filled = vec![(bid.id, filled[0]), (ask.id, filled[1])].into_iter().filter(|(_id, filled)| *filled).map(|(id, _filled)| id).collect();
+            result = result.and(self.settle_groups(on_cancel, cancelled, filled));
+        }
+        result
+    }
+
+    // 撮合一个新订单，剩余部分挂单；集合竞价期间和批量撮合模式下只挂单
+    // 有价格带时只和带内的对手单成交，碰到价格带时剩余部分按配置撤销或者以边界价格挂单，偏离太大时暂停交易
+    fn submit_order(&mut self, on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, order: LimitOrder, emit: bool) -> Result<(), Box<dyn Error>> {
+        let mut matching = Matching::default();
+        let result = self.match_order(on_trade, on_cancel, order, emit, &mut matching);
+        result.and(self.settle_groups(on_cancel, matching.cancelled, matching.filled))
+    }
+
+    fn match_order(&mut self, on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &dyn Fn(u64) -> Result<(), Box<dyn Error>>, mut order: LimitOrder, emit: bool, matching: &mut Matching) -> Result<(), Box<dyn Error>> {
+        if self.state == MarketState::Auction || self.batch {
+            let (book, _counter_book) = self.order_book_pair.get_books_mut(order.side);
+            book.add(order);
+            return Ok(());
+        }
+        let reference = self.reference_price;
+        let band = self.price_band.clone();
+        let bounds = match (&band, reference) {
+            (Some(band), Some(reference)) => Some(band.bounds(reference)),
+            _ => None
+        };
+        let policy = self.policy.clone();
+        let (book, counter_book) = self.order_book_pair.get_books_mut(order.side);
+        let result = Engine::do_matching(on_trade, &mut self.last_trade_id, &mut order, counter_book, bounds, &*policy, &mut self.groups, matching);
 
-                        if !order_filled {
-                            Engine::do_matching(on_trade, order, counter_book);
-                        } 
+        // 参考价是指数价格时只由外部设置
+        if let Some(price) = matching.last_price {
+            if band.as_ref().map_or(true, |band| band.reference == BandReference::LastTrade) {
+                self.reference_price = Some(price);
+            }
+        }
+        if order.filled() {
+            return result;
+        }
 
+        match (band, reference, bounds, matching.breach) {
+            (Some(band), Some(reference), Some((low, high)), Some(breach)) => {
+                let result = match band.action {
+                    BandAction::Cancel => {
+                        if let Some(partner) = self.groups.unlink(order.id) {
+                            matching.cancelled.push(partner);
+                        }
+                        self.groups.take_bracket(order.id);
+                        result.and(on_cancel(order.id))
                     },
-                    None => ()
+                    BandAction::Rest => {
+                        order.price = match order.side {
+                            Side::Buy => high,
+                            Side::Sell => low,
+                        };
+                        book.add(order);
+                        result
+                    }
+                };
+                if band.should_halt(reference, breach) {
+                    result.and(self.change_state(MarketState::Halted, emit))
+                } else {
+                    result
                 }
             },
-            None => ()
+            _ => {
+                book.add(order);
+                result
+            }
         }
+    }
 
+    // 和对手方从最优价位开始逐个价位成交，同一价位上按撮合规则分配数量
+    // 满足不了成交条件的挂单被跳过，仍然留在原来的位置上
+    fn do_matching(on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, trade_id: &mut u64, order: &mut LimitOrder, counter_book: &mut OrderBook, bounds: Option<(f64, f64)>, policy: &dyn MatchingPolicy, groups: &mut OrderGroups, matching: &mut Matching) -> Result<(), Box<dyn Error>> {
+        // 要求一共至少成交多少的订单先算出能成交的数量，不够时不成交
+        let required = match order.condition {
+            Condition::MinTotal(volume) => volume.min(order.volume),
+            Condition::AllOrNone => order.volume,
+            _ => 0.0
+        };
+        if required > 0.0 && Engine::fillable(order, counter_book, bounds, policy, groups) < decimal(required) {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        let mut price = counter_book.next_price(None);
+        while let Some(trade_price) = price {
+            let level = counter_book.orders_at(trade_price);
+            // counter order是老订单，所以价格以他的为准
+            if order.filled() || order.trade_with(&level[0]).is_none() {
+                break;
+            }
+            // 价格带以外的对手单不成交
+            if bounds.map_or(false, |(low, high)| trade_price < low || trade_price > high) {
+                matching.breach = Some(trade_price);
+                break;
+            }
+            result = result.and(Engine::match_level(on_trade, trade_id, order, counter_book, trade_price, policy, groups, matching));
+            price = counter_book.next_price(Some(trade_price));
+        }
+        result
+    }
+
+    // 按do_matching和match_level同样的规则只读地走一遍对手盘，返回主动单能成交的数量，不修改订单簿和OCO
+    // 每个价位上的挂单复制一份，记下试成交后的剩余数量；OCO的一条腿有成交时另一条腿不再参与
+    fn fillable(order: &LimitOrder, counter_book: &OrderBook, bounds: Option<(f64, f64)>, policy: &dyn MatchingPolicy, groups: &OrderGroups) -> BigDecimal {
+        let mut taker = order.clone().with_condition(Condition::None);
+        let mut removed = Vec::new();
+        let mut price = counter_book.next_price(None);
+        while let Some(trade_price) = price {
+            let mut level = counter_book.orders_at(trade_price);
+            if taker.filled() || taker.trade_with(&level[0]).is_none() {
+                break;
+            }
+            if bounds.map_or(false, |(low, high)| trade_price < low || trade_price > high) {
+                break;
+            }
+            let mut skipped = Vec::new();
+            while !taker.filled() {
+                let eligible = level.iter().enumerate()
+                    .filter(|(_, counter_order)| !counter_order.filled() && !skipped.contains(&counter_order.id) && !removed.contains(&counter_order.id))
+                    .filter(|(_, counter_order)| taker.volume.min(counter_order.volume) >= counter_order.min_fill())
+                    .map(|(i, _)| i)
+                    .collect::<Vec<usize>>();
+                if eligible.is_empty() {
+                    break;
+                }
+                let allocations = policy.allocate(taker.volume, &eligible.iter().map(|i| level[*i].volume).collect::<Vec<f64>>());
+                for (i, trade_volume) in eligible.into_iter().zip(allocations) {
+                    if trade_volume <= 0.0 {
+                        continue;
+                    }
+                    if trade_volume < level[i].min_fill() {
+                        skipped.push(level[i].id);
+                        continue;
+                    }
+                    if removed.contains(&level[i].id) {
+                        break;
+                    }
+                    taker.fill(trade_volume);
+                    level[i].fill(trade_volume);
+                    for id in &[taker.id, level[i].id] {
+                        if let Some(partner) = groups.partner(*id) {
+                            removed.push(partner);
+                        }
+                    }
+                }
+            }
+            price = counter_book.next_price(Some(trade_price));
+        }
+        decimal(order.volume) - decimal(taker.volume)
+    }
+
+    // 在一个价位上成交，直到主动单全部成交或者这个价位上没有能成交的挂单
+    fn match_level(on_trade: &dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, trade_id: &mut u64, order: &mut LimitOrder, counter_book: &mut OrderBook, trade_price: f64, policy: &dyn MatchingPolicy, groups: &mut OrderGroups, matching: &mut Matching) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        // 分到的数量不满足成交条件的挂单，这个主动单不再和它成交
+        let mut skipped = Vec::new();
+        while !order.filled() {
+            let eligible = counter_book.orders_at(trade_price).into_iter()
+                .filter(|counter_order| !skipped.contains(&counter_order.id))
+                .filter(|counter_order| {
+                    let volume = order.volume.min(counter_order.volume);
+                    volume >= counter_order.min_fill() && volume >= Engine::taker_min_fill(order)
+                })
+                .collect::<Vec<LimitOrder>>();
+            if eligible.is_empty() {
+                break;
+            }
+
+            let allocations = policy.allocate(order.volume, &eligible.iter().map(|counter_order| counter_order.volume).collect::<Vec<f64>>());
+            for (counter_order, trade_volume) in eligible.iter().zip(allocations) {
+                if trade_volume <= 0.0 {
+                    continue;
+                }
+                if trade_volume < counter_order.min_fill() || trade_volume < Engine::taker_min_fill(order) {
+                    skipped.push(counter_order.id);
+                    continue;
+                }
+                // 同一价位上OCO的另一条腿有成交，这个挂单已经撤掉了，剩下的数量重新分配
+                let resting_order = match counter_book.get_mut(counter_order) {
+                    Some(resting_order) => resting_order,
+                    None => break
+                };
+                let trade_funds = (decimal(trade_volume) * decimal(trade_price)).to_string().parse::<f64>().unwrap();
+
+                // fill orders
+                order.fill(trade_volume);
+                resting_order.fill(trade_volume);
+
+                // filled?
+                let order_filled = order.filled();
+                let counter_order_filled = resting_order.filled();
+
+                // if counter_order has filled, remove it from counter_book
+                if counter_order_filled {
+                    counter_book.remove(counter_order);
+                    matching.filled.push(counter_order.id);
+                }
+                if order_filled {
+                    matching.filled.push(order.id);
+                }
+                // 有成交的OCO订单的另一条腿马上从对手方撤掉，不能再成交；在本方的等撮合结束后再撤
+                for id in &[order.id, counter_order.id] {
+                    if let Some(partner) = groups.unlink(*id) {
+                        if let Some(partner_order) = counter_book.find(partner).cloned() {
+                            counter_book.remove(&partner_order);
+                        }
+                        matching.cancelled.push(partner);
+                    }
+                }
+
+                *trade_id += 1;
+                matching.last_price = Some(trade_price);
+                let trade_event = match order.side {
+                    Side::Sell => TradeEvent {
+                        id: *trade_id,
+                        price: trade_price,
+                        volume: trade_volume,
+                        funds: trade_funds,
+                        ask_order_id: order.id,
+                        ask_order_filled: order_filled,
+                        bid_order_id: counter_order.id,
+                        bid_order_filled: counter_order_filled,
+                        taker_side: Side::Sell,
+                        maker_order_id: counter_order.id,
+                        taker_order_id: order.id,
+                        ask_owner: order.owner.clone(),
+                        bid_owner: counter_order.owner.clone(),
+                    },
+                    Side::Buy => TradeEvent {
+                        id: *trade_id,
+                        price: trade_price,
+                        volume: trade_volume,
+                        funds: trade_funds,
+                        ask_order_id: counter_order.id,
+                        ask_order_filled: counter_order_filled,
+                        bid_order_id: order.id,
+                        bid_order_filled: order_filled,
+                        taker_side: Side::Buy,
+                        maker_order_id: counter_order.id,
+                        taker_order_id: order.id,
+                        ask_owner: counter_order.owner.clone(),
+                        bid_owner: order.owner.clone(),
+                    }
+                };
+                // 后续成交的错误不能覆盖前面的错误
+                result = result.and(on_trade(trade_event));
+            }
+        }
+        result
+    }
+
+    // 主动单每次成交至少要成交的数量
+    fn taker_min_fill(order: &LimitOrder) -> f64 {
+        match order.condition {
+            Condition::MinFill(volume) => volume.min(order.volume),
+            _ => 0.0
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::sync::Arc;
     use super::Engine;
     use crate::engine::Side;
     use crate::engine::LimitOrder;
+    use crate::engine::Condition;
     use super::TradeEvent;
+    use super::CancelFilter;
+    use super::MassCancelEvent;
+    use super::ReduceEvent;
+    use crate::engine::MarketState;
+    use crate::engine::MarketStateEvent;
+    use crate::engine::PriceBand;
+    use crate::engine::BandAction;
+    use crate::engine::BandReference;
+    use crate::engine::Uncross;
+    use crate::engine::ProRata;
+    use crate::engine::Peg;
+    use crate::engine::PegReference;
+    use crate::errors::TinyError;
+    use crate::engine::journal;
+    use crate::engine::Journal;
+    use crate::engine::Snapshots;
+    use crate::engine::snapshot;
+
+    fn on_cancel(_order_id: u64) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
 
-    fn create_engine<'a>(on_trade: &'a dyn Fn(TradeEvent)) -> Engine<'a> {
-        let mut engine = Engine::new(on_trade);
+    fn create_engine<'a>(on_trade: &'a dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>) -> Engine<'a> {
+        let mut engine = Engine::new(on_trade, &on_cancel);
 
-        let mut order1 = LimitOrder {
+        let order1 = LimitOrder {
             id: 1,
             price: 1.34,
             volume: 1.2,
             side: Side::Buy,
+            owner: "u1".to_string(),
+            condition: Condition::None,
             
         };
-        engine.submit(order1);
+        engine.submit(order1).unwrap();
         
-        let mut order2 = LimitOrder {
+        let order2 = LimitOrder {
             id: 2,
             price: 1.35,
             volume: 0.9,
             side: Side::Buy,
+            owner: "u1".to_string(),
+            condition: Condition::None,
             
         };
-        engine.submit(order2);
+        engine.submit(order2).unwrap();
         return engine;
     }
 
     #[test]
     fn can_do_matching1() {
-        let on_trade = |event: TradeEvent| {
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
              println!("price: {}, volume: {}", event.price, event.volume);
+             Ok(())
         };
         let mut engine = create_engine(&on_trade);
 
@@ -152,14 +1124,16 @@ mod tests {
         assert_eq!(2, buy_book.len());
         assert_eq!(2, buy_book.top().unwrap().id);
 
-        let mut order3 = LimitOrder {
+        let order3 = LimitOrder {
             id: 3,
             price: 1.345,
             volume: 1.2,
             side: Side::Sell,
+            owner: "u1".to_string(),
+            condition: Condition::None,
             
         };
-        engine.submit(order3);
+        engine.submit(order3).unwrap();
 
         let (buy_book, sell_book) = engine.order_book_pair.get_books(Side::Buy);
         assert_eq!(1, buy_book.len());
@@ -170,19 +1144,22 @@ mod tests {
 
     #[test]
     fn can_do_matching2() {
-        let on_trade = |event: TradeEvent| {
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
              println!("price: {}, volume: {}", event.price, event.volume);
+             Ok(())
         };
         let mut engine = create_engine(&on_trade);
 
-        let mut order3 = LimitOrder {
+        let order3 = LimitOrder {
             id: 3,
             price: 1.345,
             volume: 0.8,
             side: Side::Sell,
+            owner: "u1".to_string(),
+            condition: Condition::None,
             
         };
-        engine.submit(order3);
+        engine.submit(order3).unwrap();
 
         let (buy_book, sell_book) = engine.order_book_pair.get_books(Side::Buy);
         assert_eq!(2, buy_book.len());
@@ -190,4 +1167,663 @@ mod tests {
         assert_eq!(2, buy_book.top().unwrap().id);
         assert_eq!(None, sell_book.top());
     }
+
+    #[test]
+    fn keeps_matching_and_reports_first_persistence_error() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push(event.bid_order_id);
+            if event.bid_order_id == 2 {
+                Err(Box::new(TinyError::new("trade 2 not stored")))
+            } else {
+                Ok(())
+            }
+        };
+        let mut engine = create_engine(&on_trade);
+
+        let order3 = LimitOrder {
+            id: 3,
+            price: 1.34,
+            volume: 2.1,
+            side: Side::Sell,
+            owner: "u1".to_string(),
+            condition: Condition::None,
+        };
+        let result = engine.submit(order3);
+
+        assert_eq!(vec![2, 1], *trades.borrow());
+        assert_eq!("trade 2 not stored", result.unwrap_err().to_string());
+        let (buy_book, sell_book) = engine.order_book_pair.get_books(Side::Buy);
+        assert!(buy_book.is_empty());
+        assert!(sell_book.is_empty());
+    }
+
+    #[test]
+    fn reports_maker_and_taker() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push(event);
+            Ok(())
+        };
+        let mut engine = create_engine(&on_trade);
+        engine.set_last_trade_id(10).unwrap();
+
+        engine.submit(LimitOrder::new(3, Side::Sell, 2.5, 1.34).with_owner("u2")).unwrap();
+        engine.submit(LimitOrder::new(4, Side::Buy, 0.5, 1.4).with_owner("u3")).unwrap();
+
+        let trades = trades.borrow();
+        assert_eq!(vec![11, 12, 13], trades.iter().map(|trade| trade.id).collect::<Vec<u64>>());
+        // 卖单3主动成交，买单2和1是maker
+        assert_eq!((Side::Sell, 2, 3), (trades[0].taker_side, trades[0].maker_order_id, trades[0].taker_order_id));
+        assert_eq!(("u2", "u1"), (trades[0].ask_owner.as_str(), trades[0].bid_owner.as_str()));
+        assert_eq!((1, 3), (trades[1].maker_order_id, trades[1].taker_order_id));
+        // 买单4吃掉卖单3的剩余部分
+        assert_eq!((Side::Buy, 3, 4), (trades[2].taker_side, trades[2].maker_order_id, trades[2].taker_order_id));
+        assert_eq!((3, 4), (trades[2].ask_order_id, trades[2].bid_order_id));
+        assert_eq!(("u2", "u3"), (trades[2].ask_owner.as_str(), trades[2].bid_owner.as_str()));
+    }
+
+    #[test]
+    fn can_restore_without_matching() {
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            panic!("restore must not trade");
+        };
+        let mut engine = create_engine(&on_trade);
+
+        engine.restore(LimitOrder::new(3, Side::Sell, 0.5, 1.30)).unwrap();
+
+        let (buy_book, sell_book) = engine.order_book_pair.get_books(Side::Buy);
+        assert_eq!(2, buy_book.orders_count());
+        assert_eq!(3, sell_book.top().unwrap().id);
+    }
+
+    #[test]
+    fn can_amend() {
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let reduced = RefCell::new(Vec::new());
+        let on_reduce = |event: ReduceEvent| -> Result<(), Box<dyn Error>> {
+            reduced.borrow_mut().push(event);
+            Ok(())
+        };
+        let mut engine = create_engine(&on_trade);
+        engine.set_on_reduce(&on_reduce);
+        engine.submit(LimitOrder::new(3, Side::Buy, 0.5, 1.35)).unwrap();
+
+        // 减量保留时间优先，减少的数量交给on_reduce
+        engine.amend(LimitOrder::new(2, Side::Buy, 0.9, 1.35), 1.35, 0.4).unwrap();
+        engine.amend(LimitOrder::new(2, Side::Buy, 0.4, 1.35), 1.35, 0.4).unwrap();
+        let (buy_book, _sell_book) = engine.order_book_pair.get_books(Side::Buy);
+        assert_eq!(LimitOrder::new(2, Side::Buy, 0.4, 1.35).with_owner("u1"), *buy_book.top().unwrap());
+        assert_eq!(vec![ReduceEvent { order_id: 2, reduced: 0.5, volume: 0.4 }], *reduced.borrow());
+
+        // 改价排到队尾
+        engine.amend(LimitOrder::new(2, Side::Buy, 0.4, 1.35), 1.34, 0.4).unwrap();
+        engine.amend(LimitOrder::new(3, Side::Buy, 0.5, 1.35), 1.35, 0.0).unwrap();
+        let (buy_book, _sell_book) = engine.order_book_pair.get_books(Side::Buy);
+        assert_eq!(2, buy_book.orders_count());
+        assert_eq!(1, buy_book.top().unwrap().id);
+    }
+
+    #[test]
+    fn can_mass_cancel() {
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let canceled = RefCell::new(Vec::new());
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            canceled.borrow_mut().push(order_id);
+            Ok(())
+        };
+        let summaries = RefCell::new(Vec::new());
+        let on_mass_cancel = |event: MassCancelEvent| -> Result<(), Box<dyn Error>> {
+            summaries.borrow_mut().push(event);
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_on_mass_cancel(&on_mass_cancel);
+        engine.submit(LimitOrder::new(1, Side::Buy, 1.0, 1.3).with_owner("u1")).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 0.5, 1.32).with_owner("u2")).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Sell, 0.2, 1.4).with_owner("u1")).unwrap();
+        engine.submit(LimitOrder::new(4, Side::Sell, 0.3, 1.5).with_owner("u1")).unwrap();
+        engine.submit(LimitOrder::new(5, Side::Buy, 0.7, 1.31).with_owner("u1")).unwrap();
+
+        engine.mass_cancel(CancelFilter::all().with_owner("u1").with_price_range(Some(1.31), Some(1.4))).unwrap();
+        assert_eq!(vec![5, 3], *canceled.borrow());
+        assert_eq!(vec![5, 3], summaries.borrow()[0].order_ids);
+        assert_eq!(0.9, summaries.borrow()[0].volume);
+
+        engine.mass_cancel(CancelFilter::all().with_side(Side::Buy)).unwrap();
+        assert_eq!(vec![5, 3, 2, 1], *canceled.borrow());
+        assert_eq!(vec![4], engine.order_book_pair.sell_order_book.iter().map(|order| order.id).collect::<Vec<u64>>());
+        assert!(engine.order_book_pair.buy_order_book.is_empty());
+
+        // 没有符合条件的订单时也发出汇总
+        assert!(engine.mass_cancel(CancelFilter::all().with_owner("u3")).unwrap().order_ids.is_empty());
+        assert_eq!((3, 0), (summaries.borrow().len(), summaries.borrow()[2].order_ids.len()));
+
+        // OCO的另一条腿跟着撤掉，也算在汇总里
+        engine.submit_oco(LimitOrder::new(6, Side::Sell, 0.1, 1.6).with_owner("u2"), LimitOrder::new(7, Side::Buy, 0.2, 1.2).with_owner("u2")).unwrap();
+        let event = engine.mass_cancel(CancelFilter::all().with_side(Side::Sell).with_price_range(Some(1.6), None)).unwrap();
+        assert_eq!((vec![6, 7], 0.3), (event.order_ids, event.volume));
+        assert!(engine.order_book_pair.buy_order_book.is_empty());
+    }
+
+    #[test]
+    fn enforces_market_state() {
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let events = RefCell::new(Vec::new());
+        let on_state_change = |event: MarketStateEvent| -> Result<(), Box<dyn Error>> {
+            events.borrow_mut().push(event.to_string());
+            Ok(())
+        };
+        let mut engine = create_engine(&on_trade);
+        engine.set_on_state_change(&on_state_change);
+
+        engine.set_state(MarketState::Halted).unwrap();
+        assert_eq!("market is halted, submit rejected", engine.submit(LimitOrder::new(3, Side::Sell, 0.1, 1.0)).unwrap_err().to_string());
+        assert!(engine.cancel(LimitOrder::new(1, Side::Buy, 1.2, 1.34)).is_err());
+
+        engine.set_state(MarketState::CancelOnly).unwrap();
+        assert!(engine.submit(LimitOrder::new(3, Side::Sell, 0.1, 1.0)).is_err());
+        assert!(engine.amend(LimitOrder::new(1, Side::Buy, 1.2, 1.34), 1.34, 1.0).is_err());
+        engine.cancel(LimitOrder::new(1, Side::Buy, 1.2, 1.34)).unwrap();
+
+        // 只挂单: 会立即成交的订单被拒绝，不会成交的照常挂单
+        engine.set_state(MarketState::PostOnly).unwrap();
+        assert!(engine.submit(LimitOrder::new(3, Side::Sell, 0.1, 1.35)).is_err());
+        engine.submit(LimitOrder::new(4, Side::Sell, 0.1, 1.36)).unwrap();
+        assert!(engine.amend(LimitOrder::new(4, Side::Sell, 0.1, 1.36), 1.35, 0.1).is_err());
+
+        engine.set_state(MarketState::PostOnly).unwrap();
+        engine.set_state(MarketState::Continuous).unwrap();
+        engine.submit(LimitOrder::new(5, Side::Buy, 0.1, 1.36)).unwrap();
+        assert_eq!(vec![
+            "market_state from=continuous to=halted",
+            "market_state from=halted to=cancel_only",
+            "market_state from=cancel_only to=post_only",
+            "market_state from=post_only to=continuous",
+        ], *events.borrow());
+        assert_eq!(1, engine.order_book_pair.buy_order_book.orders_count());
+        assert!(engine.order_book_pair.sell_order_book.is_empty());
+    }
+
+    #[test]
+    fn cancels_outside_price_band_and_halts() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push(event.price);
+            Ok(())
+        };
+        let cancels = RefCell::new(Vec::new());
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            cancels.borrow_mut().push(order_id);
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_price_band(PriceBand::new(BandReference::LastTrade, 0.05, BandAction::Cancel).with_halt(0.08, 300));
+        engine.submit(LimitOrder::new(1, Side::Sell, 1.0, 100.0)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Sell, 1.0, 104.0)).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Sell, 1.0, 110.0)).unwrap();
+        engine.set_reference_price(100.0).unwrap();
+
+        // 110超出了100的±5%，剩余部分撤销，而且偏离超过8%，暂停交易
+        engine.submit(LimitOrder::new(4, Side::Buy, 3.0, 120.0)).unwrap();
+        assert_eq!(vec![100.0, 104.0], *trades.borrow());
+        assert_eq!(vec![4], *cancels.borrow());
+        assert_eq!(Some(104.0), engine.reference_price());
+        assert_eq!(MarketState::Halted, engine.state());
+        assert!(engine.order_book_pair.buy_order_book.is_empty());
+        assert_eq!(3, engine.order_book_pair.sell_order_book.top().unwrap().id);
+    }
+
+    #[test]
+    fn rests_at_price_band_edge() {
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_price_band(PriceBand::new(BandReference::Index, 0.05, BandAction::Rest));
+        engine.submit(LimitOrder::new(1, Side::Sell, 1.0, 110.0)).unwrap();
+
+        // 没有参考价时不限制
+        engine.submit(LimitOrder::new(2, Side::Buy, 0.5, 110.0)).unwrap();
+        assert_eq!(None, engine.reference_price());
+
+        engine.set_reference_price(100.0).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Buy, 1.0, 120.0)).unwrap();
+        assert_eq!(MarketState::Continuous, engine.state());
+        assert_eq!(105.0, engine.order_book_pair.buy_order_book.top().unwrap().price);
+
+        // 按原来的价格也能撤掉改过价格的挂单
+        engine.cancel(LimitOrder::new(3, Side::Buy, 1.0, 120.0)).unwrap();
+        assert!(engine.order_book_pair.buy_order_book.is_empty());
+    }
+
+    #[test]
+    fn uncrosses_call_auction_at_one_price() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push(event);
+            Ok(())
+        };
+        let indicatives = RefCell::new(Vec::new());
+        let on_indicative = |uncross: Option<Uncross>| -> Result<(), Box<dyn Error>> {
+            indicatives.borrow_mut().push(uncross.map(|uncross| (uncross.price, uncross.volume)));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_on_indicative(&on_indicative);
+        assert_eq!("market is continuous, uncross rejected", engine.uncross().unwrap_err().to_string());
+
+        engine.set_state(MarketState::Auction).unwrap();
+        engine.submit(LimitOrder::new(1, Side::Sell, 2.0, 10.0).with_owner("u1")).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 1.0, 10.2).with_owner("u2")).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Buy, 2.0, 10.1).with_owner("u3")).unwrap();
+        engine.amend(LimitOrder::new(1, Side::Sell, 2.0, 10.0), 10.1, 2.5).unwrap();
+        assert!(trades.borrow().is_empty());
+        assert_eq!(vec![None, None, Some((10.0, 1.0)), Some((10.1, 2.0)), Some((10.1, 2.5))], *indicatives.borrow());
+
+        engine.uncross().unwrap();
+        assert_eq!(MarketState::Continuous, engine.state());
+        assert_eq!(
+            vec![(2, 1, 10.1, 1.0, Side::Buy), (3, 1, 10.1, 1.5, Side::Buy)],
+            trades.borrow().iter().map(|trade| (trade.bid_order_id, trade.ask_order_id, trade.price, trade.volume, trade.taker_side)).collect::<Vec<(u64, u64, f64, f64, Side)>>()
+        );
+        assert_eq!(0.5, engine.order_book_pair.buy_order_book.top().unwrap().volume);
+        assert!(engine.order_book_pair.sell_order_book.is_empty());
+        assert_eq!(Some(10.1), engine.reference_price());
+        assert_eq!(5, indicatives.borrow().len());
+    }
+
+    #[test]
+    fn clears_batches_at_uniform_price() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.price, event.volume));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        assert_eq!("batch mode is off, clear batch rejected", engine.clear_batch().unwrap_err().to_string());
+
+        engine.set_batch_mode(true);
+        engine.submit(LimitOrder::new(1, Side::Sell, 1.0, 10.0)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 2.0, 10.5)).unwrap();
+        assert!(trades.borrow().is_empty());
+        engine.clear_batch().unwrap();
+        assert_eq!(vec![(10.5, 1.0)], *trades.borrow());
+
+        // 下一批和上一批剩下的挂单一起清算
+        engine.submit(LimitOrder::new(3, Side::Sell, 2.0, 10.2)).unwrap();
+        engine.submit(LimitOrder::new(4, Side::Buy, 0.5, 10.3)).unwrap();
+        engine.clear_batch().unwrap();
+        assert_eq!(vec![(10.5, 1.0), (10.2, 1.0), (10.2, 0.5)], *trades.borrow());
+        assert_eq!(MarketState::Continuous, engine.state());
+        assert!(engine.order_book_pair.buy_order_book.is_empty());
+        assert_eq!(0.5, engine.order_book_pair.sell_order_book.top().unwrap().volume);
+
+        engine.set_state(MarketState::Halted).unwrap();
+        assert!(engine.clear_batch().is_err());
+    }
+
+    #[test]
+    fn allocates_best_level_pro_rata() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.maker_order_id, event.price, event.volume, event.bid_order_filled));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_matching_policy(Arc::new(ProRata::new(1)));
+        engine.submit(LimitOrder::new(1, Side::Buy, 1.0, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 3.0, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Buy, 1.0, 0.9)).unwrap();
+
+        // 1.0价位上按1:3分配2，剩下的1.5和下一个价位成交
+        engine.submit(LimitOrder::new(4, Side::Sell, 2.0, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(5, Side::Sell, 3.5, 0.9)).unwrap();
+        assert_eq!(vec![
+            (1, 1.0, 0.5, false),
+            (2, 1.0, 1.5, false),
+            (1, 1.0, 0.5, true),
+            (2, 1.0, 1.5, true),
+            (3, 0.9, 1.0, true),
+        ], *trades.borrow());
+        assert_eq!(0.5, engine.order_book_pair.sell_order_book.top().unwrap().volume);
+    }
+
+    #[test]
+    fn skips_all_or_none_orders_until_fillable() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.bid_order_id, event.ask_order_id, event.volume));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit(LimitOrder::new(1, Side::Sell, 2.0, 1.0).with_condition(Condition::AllOrNone)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Sell, 1.0, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Sell, 1.0, 1.1)).unwrap();
+
+        // 不能全部成交的挂单被跳过，后面的挂单和下一个价位照常成交
+        engine.submit(LimitOrder::new(4, Side::Buy, 1.0, 1.1)).unwrap();
+        engine.submit(LimitOrder::new(5, Side::Buy, 1.5, 1.1)).unwrap();
+        assert_eq!(vec![(4, 2, 1.0), (5, 3, 1.0)], *trades.borrow());
+        assert_eq!(1, engine.order_book_pair.sell_order_book.top().unwrap().id);
+
+        // 被跳过的挂单保留原来的位置，能全部成交时成交
+        engine.submit(LimitOrder::new(6, Side::Buy, 2.0, 1.0)).unwrap();
+        assert_eq!((6, 1, 2.0), trades.borrow()[2]);
+        assert!(engine.order_book_pair.sell_order_book.is_empty());
+
+        // 主动的全部成交单不能全部成交时不成交，直接挂单
+        engine.submit(LimitOrder::new(7, Side::Buy, 1.0, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(8, Side::Sell, 2.0, 1.0).with_condition(Condition::AllOrNone)).unwrap();
+        assert_eq!(3, trades.borrow().len());
+        assert_eq!(2, engine.order_book_pair.buy_order_book.orders_count());
+        assert_eq!(8, engine.order_book_pair.sell_order_book.top().unwrap().id);
+    }
+
+    #[test]
+    fn all_or_none_counts_only_one_leg_of_oco() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.bid_order_id, event.ask_order_id, event.volume));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit_oco(LimitOrder::new(1, Side::Sell, 1.0, 1.3), LimitOrder::new(2, Side::Sell, 1.0, 1.4)).unwrap();
+
+        // 两条腿加起来够，但一条腿成交之后另一条腿就撤了，不能全部成交
+        engine.submit(LimitOrder::new(3, Side::Buy, 2.0, 1.4).with_condition(Condition::AllOrNone)).unwrap();
+        assert!(trades.borrow().is_empty());
+        assert_eq!(2, engine.order_book_pair.sell_order_book.orders_count());
+
+        engine.submit(LimitOrder::new(4, Side::Buy, 1.0, 1.4).with_condition(Condition::AllOrNone)).unwrap();
+        assert_eq!(vec![(4, 1, 1.0)], *trades.borrow());
+        assert!(engine.order_book_pair.sell_order_book.is_empty());
+    }
+
+    #[test]
+    fn enforces_minimum_quantities() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.bid_order_id, event.ask_order_id, event.volume));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit(LimitOrder::new(1, Side::Sell, 0.5, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Sell, 3.0, 1.1)).unwrap();
+
+        // 每次至少成交1，数量不够的挂单跳过
+        engine.submit(LimitOrder::new(3, Side::Buy, 2.0, 1.1).with_condition(Condition::MinFill(1.0))).unwrap();
+        assert_eq!(vec![(3, 2, 2.0)], *trades.borrow());
+
+        // 一共只能成交1.5，不够2时不成交
+        engine.submit(LimitOrder::new(4, Side::Buy, 5.0, 1.1).with_condition(Condition::MinTotal(2.0))).unwrap();
+        assert_eq!(1, trades.borrow().len());
+        assert_eq!(4, engine.order_book_pair.buy_order_book.top().unwrap().id);
+
+        // 挂单之后每次也至少成交2
+        engine.submit(LimitOrder::new(5, Side::Sell, 1.0, 1.1)).unwrap();
+        assert_eq!(1, trades.borrow().len());
+        engine.submit(LimitOrder::new(6, Side::Sell, 3.0, 1.1)).unwrap();
+        assert_eq!(vec![(3, 2, 2.0), (4, 6, 3.0)], *trades.borrow());
+        assert_eq!(2.0, engine.order_book_pair.buy_order_book.top().unwrap().volume);
+    }
+
+    #[test]
+    fn reprices_pegged_orders_with_the_book() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.bid_order_id, event.ask_order_id, event.volume));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit(LimitOrder::new(1, Side::Sell, 1.0, 1.2)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 1.0, 1.0)).unwrap();
+
+        let mut primary = Peg::new(PegReference::Primary).with_offset(0.01);
+        primary.price_decimals = 2;
+        engine.submit_pegged(LimitOrder::new(3, Side::Buy, 1.0, 0.5), primary.clone()).unwrap();
+        // 挂钩订单不跟随别的挂钩订单，两个订单都在1.01
+        engine.submit_pegged(LimitOrder::new(4, Side::Buy, 1.0, 0.5), primary).unwrap();
+        assert_eq!(vec![1.01, 1.01], engine.order_book_pair.buy_order_book.iter().take(2).map(|order| order.price).collect::<Vec<f64>>());
+
+        engine.submit(LimitOrder::new(5, Side::Buy, 1.0, 1.05)).unwrap();
+        assert_eq!((3, 1.06), engine.order_book_pair.buy_order_book.top().map(|order| (order.id, order.price)).unwrap());
+        engine.cancel(LimitOrder::new(5, Side::Buy, 1.0, 1.05)).unwrap();
+        assert_eq!(1.01, engine.order_book_pair.buy_order_book.top().unwrap().price);
+
+        // 中间价挂钩的买单和卖单在中间价成交
+        let mut midpoint = Peg::new(PegReference::Midpoint);
+        midpoint.price_decimals = 2;
+        engine.submit_pegged(LimitOrder::new(6, Side::Sell, 1.0, 2.0), midpoint.clone()).unwrap();
+        assert_eq!((6, 1.1), engine.order_book_pair.sell_order_book.top().map(|order| (order.id, order.price)).unwrap());
+        engine.submit_pegged(LimitOrder::new(7, Side::Buy, 0.4, 0.1), midpoint).unwrap();
+        assert_eq!(vec![(7, 6, 0.4)], *trades.borrow());
+    }
+
+    #[test]
+    fn rematches_pegged_orders_crossing_after_repricing() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.bid_order_id, event.ask_order_id, event.volume));
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit(LimitOrder::new(1, Side::Buy, 1.0, 1.0)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 1.0, 0.9)).unwrap();
+
+        // 卖单跟买一减0.05: 先和1.0成交，买一变成0.9之后重新定价到0.85，又和0.9成交
+        let mut market = Peg::new(PegReference::Market).with_offset(-0.05).with_limit(0.8);
+        market.price_decimals = 2;
+        engine.submit_pegged(LimitOrder::new(3, Side::Sell, 3.0, 2.0), market).unwrap();
+        assert_eq!(vec![(1, 3, 1.0), (2, 3, 1.0)], *trades.borrow());
+        assert_eq!((0.85, 1.0), engine.order_book_pair.sell_order_book.top().map(|order| (order.price, order.volume)).unwrap());
+
+        // 不低于限价
+        engine.submit(LimitOrder::new(4, Side::Buy, 1.0, 0.7)).unwrap();
+        assert_eq!(0.8, engine.order_book_pair.sell_order_book.top().unwrap().price);
+        assert_eq!(2, trades.borrow().len());
+
+        engine.cancel(LimitOrder::new(3, Side::Sell, 1.0, 2.0)).unwrap();
+        assert!(engine.pegs().is_empty());
+    }
+
+    #[test]
+    fn cancels_other_leg_of_oco() {
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push((event.bid_order_id, event.ask_order_id, event.volume));
+            Ok(())
+        };
+        let cancels = RefCell::new(Vec::new());
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            cancels.borrow_mut().push(order_id);
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit(LimitOrder::new(1, Side::Buy, 1.0, 1.0)).unwrap();
+
+        // 第二条腿立即成交，已经挂单的第一条腿撤销
+        engine.submit_oco(LimitOrder::new(2, Side::Sell, 1.0, 1.2), LimitOrder::new(3, Side::Sell, 1.0, 0.9)).unwrap();
+        assert_eq!(vec![(1, 3, 1.0)], *trades.borrow());
+        assert_eq!(vec![2], *cancels.borrow());
+        assert!(engine.order_book_pair.sell_order_book.is_empty());
+
+        // 一个订单扫过两条腿时，第一条腿成交之后另一条腿不再成交
+        engine.submit_oco(LimitOrder::new(4, Side::Sell, 1.0, 1.3), LimitOrder::new(5, Side::Sell, 1.0, 1.4)).unwrap();
+        engine.submit(LimitOrder::new(6, Side::Buy, 2.0, 1.5)).unwrap();
+        assert_eq!(vec![(1, 3, 1.0), (6, 4, 1.0)], *trades.borrow());
+        assert_eq!(vec![2, 5], *cancels.borrow());
+        assert_eq!(1.0, engine.order_book_pair.buy_order_book.top().unwrap().volume);
+
+        // 撤掉一条腿时另一条腿也撤掉
+        engine.submit_oco(LimitOrder::new(7, Side::Sell, 1.0, 1.6), LimitOrder::new(8, Side::Sell, 1.0, 1.7)).unwrap();
+        engine.cancel(LimitOrder::new(7, Side::Sell, 1.0, 1.6)).unwrap();
+        assert_eq!(vec![2, 5, 7, 8], *cancels.borrow());
+        assert!(engine.order_book_pair.sell_order_book.is_empty());
+        assert!(engine.groups().is_empty());
+    }
+
+    #[test]
+    fn activates_bracket_exits_after_entry_fills() {
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.submit(LimitOrder::new(1, Side::Sell, 0.4, 1.1)).unwrap();
+
+        let exits = (LimitOrder::new(0, Side::Sell, 1.0, 1.3).with_owner("u1"), LimitOrder::new(0, Side::Sell, 1.0, 1.0).with_owner("u1"));
+        engine.submit_bracket(LimitOrder::new(2, Side::Buy, 1.0, 1.1).with_owner("u1"), exits.clone()).unwrap();
+        assert!(engine.take_activated().is_empty());
+
+        engine.submit(LimitOrder::new(3, Side::Sell, 0.6, 1.1)).unwrap();
+        assert_eq!(vec![(2, exits.0.clone(), exits.1.clone())], engine.take_activated());
+        assert!(engine.take_activated().is_empty());
+
+        // 入场单全部成交之前撤销时出场单作废
+        engine.submit_bracket(LimitOrder::new(4, Side::Buy, 1.0, 0.5), exits).unwrap();
+        engine.cancel(LimitOrder::new(4, Side::Buy, 1.0, 0.5)).unwrap();
+        assert!(engine.take_activated().is_empty());
+        assert!(engine.groups().is_empty());
+    }
+
+    #[test]
+    fn replay_reproduces_books_and_trades() {
+        let dir = std::env::temp_dir().join(format!("matching-rs-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let trades = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push(event.to_string());
+            Ok(())
+        };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_journal(Journal::open(&dir).unwrap());
+        engine.restore(LimitOrder::new(1, Side::Buy, 1.2, 1.34)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 0.9, 1.35)).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Sell, 0.000003456, 1.345)).unwrap();
+        engine.amend(LimitOrder::new(1, Side::Buy, 1.2, 1.34), 1.34, 1.0).unwrap();
+        engine.submit(LimitOrder::new(4, Side::Sell, 1.5, 1.3)).unwrap();
+        engine.cancel(LimitOrder::new(4, Side::Sell, 0.400003456, 1.3)).unwrap();
+        engine.submit(LimitOrder::new(5, Side::Sell, 0.1, 1.5).with_owner("u2")).unwrap();
+        engine.mass_cancel(CancelFilter::all().with_owner("u2")).unwrap();
+        engine.set_state(MarketState::Auction).unwrap();
+        engine.set_reference_price(1.33).unwrap();
+        engine.submit(LimitOrder::new(6, Side::Sell, 0.5, 1.32)).unwrap();
+        engine.uncross().unwrap();
+        engine.set_state(MarketState::PostOnly).unwrap();
+        assert_eq!(4, trades.borrow().len());
+
+        let replayed_trades = RefCell::new(Vec::new());
+        let on_replayed_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            replayed_trades.borrow_mut().push(event.to_string());
+            Ok(())
+        };
+        let mut replayed = Engine::new(&on_replayed_trade, &on_cancel);
+        replayed.replay(journal::read(&dir).unwrap(), true).unwrap();
+
+        assert_eq!(*trades.borrow(), *replayed_trades.borrow());
+        assert_eq!(format!("{:?}", engine.order_book_pair), format!("{:?}", replayed.order_book_pair));
+        assert_eq!(MarketState::PostOnly, replayed.state());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn can_recover_from_snapshot_and_journal_tail() {
+        let journal_dir = std::env::temp_dir().join(format!("matching-rs-engine-journal-{}", std::process::id()));
+        let snapshot_dir = std::env::temp_dir().join(format!("matching-rs-engine-snapshot-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&journal_dir);
+        let _ = std::fs::remove_dir_all(&snapshot_dir);
+
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_journal(Journal::open(&journal_dir).unwrap());
+        engine.set_snapshots(Snapshots::new(&snapshot_dir, 3));
+        for id in 1..=8 {
+            let side = if id % 2 == 0 { Side::Buy } else { Side::Sell };
+            engine.submit(LimitOrder::new(id, side, 0.1 * id as f64, 1.3 + 0.01 * (id % 3) as f64)).unwrap();
+        }
+
+        let snapshot = snapshot::load_latest(&snapshot_dir).unwrap().0.unwrap();
+        assert_eq!(6, snapshot.seq);
+        let mut recovered = Engine::new(&on_trade, &on_cancel);
+        recovered.load(snapshot);
+        recovered.replay(journal::read_from(&journal_dir, 6).unwrap(), false).unwrap();
+
+        assert_eq!(format!("{:?}", engine.order_book_pair), format!("{:?}", recovered.order_book_pair));
+        assert!(engine.last_trade_id() > 0);
+        assert_eq!(engine.last_trade_id(), recovered.last_trade_id());
+        assert_eq!((8, 8), (engine.last_order_id(), recovered.last_order_id()));
+        assert_eq!(engine.reference_price(), recovered.reference_price());
+        std::fs::remove_dir_all(&journal_dir).unwrap();
+        std::fs::remove_dir_all(&snapshot_dir).unwrap();
+    }
+
+    #[test]
+    fn reports_failed_snapshots() {
+        let journal_dir = std::env::temp_dir().join(format!("matching-rs-snapshot-error-journal-{}", std::process::id()));
+        // 快照目录是一个文件，写快照总是失败
+        let snapshot_dir = std::env::temp_dir().join(format!("matching-rs-snapshot-error-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&journal_dir);
+        std::fs::write(&snapshot_dir, "").unwrap();
+
+        let errors = RefCell::new(Vec::new());
+        let on_snapshot_error = |err: Box<dyn Error>| errors.borrow_mut().push(err.to_string());
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_journal(Journal::open(&journal_dir).unwrap());
+        engine.set_snapshots(Snapshots::new(&snapshot_dir, 2));
+        engine.set_on_snapshot_error(&on_snapshot_error);
+        for id in 1..=4 {
+            engine.submit(LimitOrder::new(id, Side::Sell, 1.0, 1.3)).unwrap();
+        }
+
+        assert_eq!(2, errors.borrow().len());
+        assert!(errors.borrow()[0].starts_with("snapshot at 2 failed: "));
+        assert_eq!(4, engine.order_book_pair.sell_order_book.orders_count());
+        std::fs::remove_dir_all(&journal_dir).unwrap();
+        std::fs::remove_file(&snapshot_dir).unwrap();
+    }
+
+    #[test]
+    fn recover_emits_only_unpersisted_events() {
+        let dir = std::env::temp_dir().join(format!("matching-rs-recover-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut engine = Engine::new(&on_trade, &on_cancel);
+        engine.set_journal(Journal::open(&dir).unwrap());
+        engine.submit(LimitOrder::new(1, Side::Sell, 1.0, 1.3)).unwrap();
+        engine.submit(LimitOrder::new(2, Side::Buy, 0.4, 1.3)).unwrap();
+        engine.submit(LimitOrder::new(3, Side::Buy, 0.4, 1.3)).unwrap();
+        engine.submit(LimitOrder::new(4, Side::Buy, 0.5, 1.2)).unwrap();
+        engine.cancel(LimitOrder::new(4, Side::Buy, 0.5, 1.2)).unwrap();
+        engine.cancel(LimitOrder::new(1, Side::Sell, 0.2, 1.3)).unwrap();
+        engine.submit(LimitOrder::new(5, Side::Buy, 0.5, 1.1)).unwrap();
+        engine.amend(LimitOrder::new(5, Side::Buy, 0.5, 1.1), 1.1, 0.3).unwrap();
+        engine.amend(LimitOrder::new(5, Side::Buy, 0.3, 1.1), 1.1, 0.2).unwrap();
+
+        // 数据库里只有第一笔成交、订单4的撤单和订单5的第一次减量
+        let trades = RefCell::new(Vec::new());
+        let cancels = RefCell::new(Vec::new());
+        let reduced = RefCell::new(Vec::new());
+        let on_recovered_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            trades.borrow_mut().push(event.id);
+            Ok(())
+        };
+        let on_recovered_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            cancels.borrow_mut().push(order_id);
+            Ok(())
+        };
+        let on_recovered_reduce = |event: ReduceEvent| -> Result<(), Box<dyn Error>> {
+            reduced.borrow_mut().push(event.volume);
+            Ok(())
+        };
+        let unpersisted_cancel = |order_id: u64| -> Result<bool, Box<dyn Error>> { Ok(order_id != 4) };
+        let unpersisted_reduce = |event: &ReduceEvent| -> Result<bool, Box<dyn Error>> { Ok(event.volume < 0.3) };
+        let mut recovered = Engine::new(&on_recovered_trade, &on_recovered_cancel);
+        recovered.set_on_reduce(&on_recovered_reduce);
+        recovered.recover(journal::read(&dir).unwrap(), 1, &unpersisted_cancel, &unpersisted_reduce).unwrap();
+
+        assert_eq!(vec![2], *trades.borrow());
+        assert_eq!(vec![1], *cancels.borrow());
+        assert_eq!(vec![0.2], *reduced.borrow());
+        assert_eq!(format!("{:?}", engine.order_book_pair), format!("{:?}", recovered.order_book_pair));
+        assert_eq!(5, recovered.last_order_id());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,608 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::engine::Side;
+use crate::engine::LimitOrder;
+use crate::engine::Condition;
+use crate::engine::Peg;
+use crate::engine::PegReference;
+use crate::engine::CancelFilter;
+use crate::engine::MarketState;
+
+// 引擎接受的输入命令，撮合之前先写入日志
+// 引擎是确定性的，按顺序重放这些命令就能得到完全相同的订单簿和成交
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    // 从数据库恢复的挂单，不撮合
+    Restore(LimitOrder),
+    // 从数据库恢复的挂钩订单，订单的价格是限价
+    RestorePeg(LimitOrder, Peg),
+    // 从数据库恢复的OCO关系，两条腿已经恢复
+    RestoreOco(u64, u64),
+    // 从数据库恢复的括号单还没挂出的出场单，入场单已经恢复
+    RestoreBracket(u64, LimitOrder, LimitOrder),
+    Submit(LimitOrder),
+    // OCO的两条腿
+    SubmitOco(LimitOrder, LimitOrder),
+    // 括号单的入场单和两条出场单，出场单的id在挂出时才分配
+    SubmitBracket(LimitOrder, LimitOrder, LimitOrder),
+    // 挂钩订单，订单的价格是还没有参考价时使用的价格
+    SubmitPeg(LimitOrder, Peg),
+    Cancel(LimitOrder),
+    // 修改挂单的价格和剩余数量
+    Amend(LimitOrder, f64, f64),
+    // 从数据库恢复时设置最后一笔成交的编号
+    SetLastTradeId(u64),
+    // 批量撤单
+    MassCancel(CancelFilter),
+    SetState(MarketState),
+    // 价格带的参考价
+    SetReferencePrice(f64),
+    // 结束集合竞价，按统一价格成交后回到连续撮合
+    Uncross,
+    // 批量撮合模式下清算一批
+    ClearBatch,
+}
+
+impl Command {
+    fn encode(&self) -> String {
+        match self {
+            Command::Restore(order) => format!("restore {}", encode_order(order)),
+            Command::RestorePeg(order, peg) => format!("restore_peg {} {}", encode_order(order), encode_peg(peg)),
+            Command::RestoreOco(first_id, second_id) => format!("restore_oco {} {}", first_id, second_id),
+            Command::RestoreBracket(entry_id, first, second) => format!("restore_bracket {} {} {}", entry_id, encode_order(first), encode_order(second)),
+            Command::Submit(order) => format!("submit {}", encode_order(order)),
+            Command::SubmitOco(first, second) => format!("oco {} {}", encode_order(first), encode_order(second)),
+            Command::SubmitBracket(entry, first, second) => format!("bracket {} {} {}", encode_order(entry), encode_order(first), encode_order(second)),
+            Command::SubmitPeg(order, peg) => format!("peg {} {}", encode_order(order), encode_peg(peg)),
+            Command::Cancel(order) => format!("cancel {}", encode_order(order)),
+            Command::Amend(order, price, volume) => format!("amend {} {} {}", encode_order(order), price, volume),
+            Command::SetLastTradeId(trade_id) => format!("last_trade_id {}", trade_id),
+            Command::MassCancel(filter) => format!("mass_cancel {}", encode_filter(filter)),
+            Command::SetState(state) => format!("state {}", state),
+            Command::SetReferencePrice(price) => format!("reference_price {}", price),
+            Command::Uncross => "uncross".to_string(),
+            Command::ClearBatch => "clear_batch".to_string(),
+        }
+    }
+
+    // 这条命令带进引擎的新订单里最大的id，括号单的出场单在挂出时才有id
+    pub fn max_order_id(&self) -> Option<u64> {
+        match self {
+            Command::Restore(order) | Command::RestorePeg(order, _) | Command::Submit(order) | Command::SubmitBracket(order, _, _) | Command::SubmitPeg(order, _) => Some(order.id),
+            Command::SubmitOco(first, second) => Some(first.id.max(second.id)),
+            _ => None
+        }
+    }
+
+    // 早期的日志里订单没有owner字段，少一个字段
+    fn decode(payload: &str) -> Option<Command> {
+        let fields = payload.split(' ').collect::<Vec<&str>>();
+        match (fields[0], fields.len()) {
+            ("restore", 5) | ("restore", 6) => Some(Command::Restore(decode_order(&fields[1..])?)),
+            ("submit", 5) | ("submit", 6) => Some(Command::Submit(decode_order(&fields[1..])?)),
+            ("oco", 11) => Some(Command::SubmitOco(decode_order(&fields[1..6])?, decode_order(&fields[6..11])?)),
+            ("bracket", 16) => Some(Command::SubmitBracket(decode_order(&fields[1..6])?, decode_order(&fields[6..11])?, decode_order(&fields[11..16])?)),
+            ("peg", 10) => Some(Command::SubmitPeg(decode_order(&fields[1..6])?, decode_peg(&fields[6..10])?)),
+            ("restore_peg", 10) => Some(Command::RestorePeg(decode_order(&fields[1..6])?, decode_peg(&fields[6..10])?)),
+            ("restore_oco", 3) => Some(Command::RestoreOco(fields[1].parse::<u64>().ok()?, fields[2].parse::<u64>().ok()?)),
+            ("restore_bracket", 12) => Some(Command::RestoreBracket(fields[1].parse::<u64>().ok()?, decode_order(&fields[2..7])?, decode_order(&fields[7..12])?)),
+            ("cancel", 5) | ("cancel", 6) => Some(Command::Cancel(decode_order(&fields[1..])?)),
+            ("amend", 7) | ("amend", 8) => Some(Command::Amend(
+                decode_order(&fields[1..fields.len() - 2])?,
+                fields[fields.len() - 2].parse::<f64>().ok()?,
+                fields[fields.len() - 1].parse::<f64>().ok()?,
+            )),
+            ("last_trade_id", 2) => Some(Command::SetLastTradeId(fields[1].parse::<u64>().ok()?)),
+            ("mass_cancel", 5) | ("mass_cancel", 6) => Some(Command::MassCancel(decode_filter(&fields[1..])?)),
+            ("state", 2) => Some(Command::SetState(MarketState::parse(fields[1])?)),
+            ("uncross", 1) => Some(Command::Uncross),
+            ("clear_batch", 1) => Some(Command::ClearBatch),
+            ("reference_price", 2) => Some(Command::SetReferencePrice(fields[1].parse::<f64>().ok()?)),
+            _ => None
+        }
+    }
+}
+
+// f64的Display输出是能精确还原的最短表示，所以文本格式也能保证重放结果逐字节一致
+// 没有owner时写成"-"，字段数保持不变，owner按encode_owner转义；成交条件跟在side后面，比如"buy:aon"、"sell:min=0.5"
+pub(crate) fn encode_order(order: &LimitOrder) -> String {
+    let side = match order.side {
+        Side::Buy => "buy",
+        Side::Sell => "sell",
+    };
+    let side = match order.condition {
+        Condition::None => side.to_string(),
+        condition => format!("{}:{}", side, condition),
+    };
+    format!("{} {} {} {} {}", order.id, side, order.volume, order.price, encode_owner(&order.owner))
+}
+
+// fields是"<id> <side>[:condition] <volume> <price> [owner]"
+pub(crate) fn decode_order(fields: &[&str]) -> Option<LimitOrder> {
+    let mut side_fields = fields[1].splitn(2, ':');
+    let side = match side_fields.next()? {
+        "buy" => Side::Buy,
+        "sell" => Side::Sell,
+        _ => return None
+    };
+    let condition = match side_fields.next() {
+        None => Condition::None,
+        Some(condition) => Condition::parse(condition)?,
+    };
+    let order = LimitOrder::new(
+        fields[0].parse::<u64>().ok()?,
+        side,
+        fields[2].parse::<f64>().ok()?,
+        fields[3].parse::<f64>().ok()?,
+    ).with_condition(condition);
+    match fields.get(4) {
+        Some(owner) => Some(order.with_owner(&decode_owner(owner)?)),
+        None => Some(order),
+    }
+}
+
+// 空的owner写成"-"；owner里的'%'、空白和控制字符按字节写成%XX，owner本身是"-"时也转义，不会破坏字段的切分
+fn encode_owner(owner: &str) -> String {
+    if owner.is_empty() {
+        return "-".to_string();
+    }
+    if owner == "-" {
+        return "%2D".to_string();
+    }
+    let mut encoded = String::new();
+    for c in owner.chars() {
+        if c == '%' || c.is_whitespace() || c.is_control() {
+            let mut buffer = [0; 4];
+            for byte in c.encode_utf8(&mut buffer).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+fn decode_owner(field: &str) -> Option<String> {
+    if field == "-" {
+        return Some(String::new());
+    }
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            decoded.push(u8::from_str_radix(field.get(i + 1..i + 3)?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// "<reference> <offset> <limit> <price_decimals>"，没有limit时写成"-"
+pub(crate) fn encode_peg(peg: &Peg) -> String {
+    let limit = peg.limit.map_or("-".to_string(), |limit| limit.to_string());
+    format!("{} {} {} {}", peg.reference, peg.offset, limit, peg.price_decimals)
+}
+
+pub(crate) fn decode_peg(fields: &[&str]) -> Option<Peg> {
+    let mut peg = Peg::new(PegReference::parse(fields[0])?).with_offset(fields[1].parse::<f64>().ok()?);
+    if fields[2] != "-" {
+        peg = peg.with_limit(fields[2].parse::<f64>().ok()?);
+    }
+    peg.price_decimals = fields[3].parse::<u32>().ok()?;
+    Some(peg)
+}
+
+// "<owner> <side> <min_price> <max_price> [order_ids]"，不限的条件写成"-"，order_ids用逗号分隔，没有时省略
+fn encode_filter(filter: &CancelFilter) -> String {
+    let side = match filter.side {
+        Some(Side::Buy) => "buy",
+        Some(Side::Sell) => "sell",
+        None => "-",
+    };
+    let price = |price: Option<f64>| price.map_or("-".to_string(), |price| price.to_string());
+    let encoded = format!("{} {} {} {}", filter.owner.as_ref().map_or("-".to_string(), |owner| encode_owner(owner)), side, price(filter.min_price), price(filter.max_price));
+    match &filter.order_ids {
+        Some(order_ids) => format!("{} {}", encoded, order_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(",")),
+        None => encoded
+    }
+}
+
+fn decode_filter(fields: &[&str]) -> Option<CancelFilter> {
+    let side = match fields[1] {
+        "buy" => Some(Side::Buy),
+        "sell" => Some(Side::Sell),
+        "-" => None,
+        _ => return None
+    };
+    let price = |field: &str| match field {
+        "-" => Some(None),
+        field => field.parse::<f64>().ok().map(Some),
+    };
+    Some(CancelFilter {
+        owner: if fields[0] == "-" { None } else { Some(decode_owner(fields[0])?) },
+        side: side,
+        min_price: price(fields[2])?,
+        max_price: price(fields[3])?,
+        order_ids: match fields.get(4) {
+            // 空的列表编码成空字符串
+            Some(&"") => Some(Vec::new()),
+            Some(order_ids) => Some(order_ids.split(',').map(|id| id.parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?),
+            None => None,
+        },
+    })
+}
+
+// 每条记录一行: "<seq> <crc32> <command>"，crc32覆盖seq和command
+fn encode_record(seq: u64, command: &Command) -> String {
+    let body = format!("{} {}", seq, command.encode());
+    format!("{} {:08x} {}\n", seq, crc32fast::hash(body.as_bytes()), command.encode())
+}
+
+fn decode_record(line: &str) -> Option<(u64, Command)> {
+    let mut fields = line.splitn(3, ' ');
+    let seq = fields.next()?.parse::<u64>().ok()?;
+    let checksum = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let payload = fields.next()?;
+    if crc32fast::hash(format!("{} {}", seq, payload).as_bytes()) != checksum {
+        return None;
+    }
+    Some((seq, Command::decode(payload)?))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// 只追加的命令日志，目录下按起始序号命名的段文件
+// 写快照时切换到新的段文件，快照之前的段文件可以删除
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    next_seq: u64,
+}
+
+impl Journal {
+    // 打开（或创建）日志目录，继续往最后一个段文件追加
+    // 进程崩溃时最后一条记录可能只写了一半，这样的尾巴会被截掉，它对应的命令也没有被撮合
+    pub fn open(dir: &Path) -> io::Result<Journal> {
+        fs::create_dir_all(dir)?;
+        let (records, path, valid_len) = match segments(dir)?.pop() {
+            Some(path) => {
+                let (records, valid_len) = read_segment(&path, true)?;
+                (records, path, valid_len)
+            },
+            None => (Vec::new(), segment_path(dir, 1), 0)
+        };
+        let next_seq = match records.last() {
+            Some((seq, _command)) => seq + 1,
+            None => first_seq(&path).unwrap_or(1)
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(valid_len)?;
+        Ok(Journal {
+            dir: dir.to_path_buf(),
+            file: file,
+            next_seq: next_seq,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // 之后的记录写到以next_seq命名的新段文件
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.file = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, self.next_seq))?;
+        Ok(())
+    }
+
+    // 返回前已经落盘
+    pub fn append(&mut self, command: &Command) -> io::Result<u64> {
+        let seq = self.next_seq;
+        self.file.write_all(encode_record(seq, command).as_bytes())?;
+        self.file.sync_data()?;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+}
+
+// 读出目录下全部记录，校验checksum和序号的连续性
+pub fn read(dir: &Path) -> io::Result<Vec<(u64, Command)>> {
+    read_from(dir, 0)
+}
+
+// 读出序号大于after_seq的记录，用于从快照恢复
+// 需要的段文件已经被删除时返回错误
+pub fn read_from(dir: &Path, after_seq: u64) -> io::Result<Vec<(u64, Command)>> {
+    let paths = segments(dir)?;
+    let mut records: Vec<(u64, Command)> = Vec::new();
+    let mut expected_seq = None;
+    for (i, path) in paths.iter().enumerate() {
+        let last_segment = i == paths.len() - 1;
+        if !last_segment && first_seq(&paths[i + 1]).unwrap() <= after_seq + 1 {
+            continue;
+        }
+
+        let (segment_records, _valid_len) = read_segment(path, last_segment)?;
+        let mut seq = match expected_seq {
+            Some(seq) => seq,
+            None => {
+                let seq = first_seq(path).unwrap();
+                if seq > after_seq + 1 {
+                    return Err(invalid_data(format!("journal starts at {}, records after {} are missing", seq, after_seq)));
+                }
+                seq
+            }
+        };
+        for (record_seq, command) in segment_records {
+            if record_seq != seq {
+                return Err(invalid_data(format!("journal gap between {} and {}", seq, record_seq)));
+            }
+            if record_seq > after_seq {
+                records.push((record_seq, command));
+            }
+            seq += 1;
+        }
+        expected_seq = Some(seq);
+    }
+    Ok(records)
+}
+
+// 删除所有记录序号都不大于seq的段文件，最后一个段文件总是保留
+pub fn truncate(dir: &Path, seq: u64) -> io::Result<usize> {
+    let paths = segments(dir)?;
+    let mut removed = 0;
+    for i in 1..paths.len() {
+        if first_seq(&paths[i]).unwrap() <= seq + 1 {
+            fs::remove_file(&paths[i - 1])?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.journal", first_seq))
+}
+
+fn first_seq(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
+
+// 按起始序号排序的段文件
+fn segments(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    if !dir.exists() {
+        return Ok(paths);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "journal") && first_seq(&path).is_some() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// 返回段文件中的有效记录和有效部分的长度
+// 只有最后一个段文件允许有不完整的尾巴
+fn read_segment(path: &Path, allow_torn_tail: bool) -> io::Result<(Vec<(u64, Command)>, u64)> {
+    let content = fs::read(path)?;
+    let mut records = Vec::new();
+    let mut valid_len = 0;
+    let mut rest = &content[..];
+    while !rest.is_empty() {
+        let record = match rest.iter().position(|b| *b == b'\n') {
+            Some(end) => std::str::from_utf8(&rest[..end]).ok().and_then(decode_record).map(|record| (record, end + 1)),
+            None => None
+        };
+        match record {
+            Some((record, len)) => {
+                records.push(record);
+                valid_len += len;
+                rest = &rest[len..];
+            },
+            None => {
+                let torn_tail = !rest[..rest.len() - 1].contains(&b'\n');
+                if allow_torn_tail && torn_tail {
+                    break;
+                }
+                return Err(invalid_data(format!("corrupted journal record in {} at byte {}", path.display(), valid_len)));
+            }
+        }
+    }
+    Ok((records, valid_len as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use super::{read, read_from, truncate, Command, Journal};
+    use crate::engine::CancelFilter;
+    use crate::engine::LimitOrder;
+    use crate::engine::Condition;
+    use crate::engine::Side;
+    use crate::engine::Peg;
+    use crate::engine::PegReference;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("matching-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn can_append_and_read() {
+        let dir = temp_dir("journal-append");
+        let commands = vec![
+            Command::SetLastTradeId(7),
+            Command::Submit(LimitOrder::new(1, Side::Buy, 0.000003456, 11.00000003).with_owner("u1")),
+            Command::Amend(LimitOrder::new(1, Side::Buy, 0.000003456, 11.00000003), 11.1, 0.1),
+            Command::Cancel(LimitOrder::new(1, Side::Buy, 0.1, 11.1)),
+        ];
+
+        let mut journal = Journal::open(&dir).unwrap();
+        for command in &commands {
+            journal.append(command).unwrap();
+        }
+        assert_eq!(5, journal.next_seq());
+
+        let records = read(&dir).unwrap();
+        assert_eq!(vec![1, 2, 3, 4], records.iter().map(|(seq, _)| *seq).collect::<Vec<u64>>());
+        assert_eq!(commands, records.into_iter().map(|(_, command)| command).collect::<Vec<Command>>());
+
+        // 重新打开后序号继续
+        let mut journal = Journal::open(&dir).unwrap();
+        assert_eq!(5, journal.append(&commands[0]).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn can_encode_mass_cancel() {
+        let filter = CancelFilter::all().with_owner("u1").with_side(Side::Sell).with_price_range(Some(10.5), None);
+        assert_eq!("mass_cancel u1 sell 10.5 -", Command::MassCancel(filter.clone()).encode());
+        assert_eq!(Some(Command::MassCancel(filter)), Command::decode("mass_cancel u1 sell 10.5 -"));
+        assert_eq!(Some(Command::MassCancel(CancelFilter::all())), Command::decode("mass_cancel - - - -"));
+        assert_eq!(None, Command::decode("mass_cancel - up - -"));
+
+        let filter = CancelFilter::all().with_owner("u1").with_order_ids(vec![3, 5]);
+        assert_eq!("mass_cancel u1 - - - 3,5", Command::MassCancel(filter.clone()).encode());
+        assert_eq!(Some(Command::MassCancel(filter)), Command::decode("mass_cancel u1 - - - 3,5"));
+    }
+
+    #[test]
+    fn can_encode_order_conditions() {
+        for condition in &[Condition::MinFill(0.5), Condition::MinTotal(1.5), Condition::AllOrNone] {
+            let command = Command::Submit(LimitOrder::new(1, Side::Buy, 2.0, 1.5).with_owner("u1").with_condition(*condition));
+            assert_eq!(Some(command.clone()), Command::decode(&command.encode()));
+        }
+        assert_eq!("submit 1 sell:aon 2 1.5 -", Command::Submit(LimitOrder::new(1, Side::Sell, 2.0, 1.5).with_condition(Condition::AllOrNone)).encode());
+        assert_eq!(None, Command::decode("submit 1 sell:fok 2 1.5 -"));
+    }
+
+    #[test]
+    fn can_encode_pegged_orders() {
+        let mut peg = Peg::new(PegReference::Midpoint).with_offset(-0.01).with_limit(2.5);
+        peg.price_decimals = 2;
+        let command = Command::SubmitPeg(LimitOrder::new(1, Side::Buy, 2.0, 2.4).with_owner("u1"), peg);
+        assert_eq!("peg 1 buy 2 2.4 u1 midpoint -0.01 2.5 2", command.encode());
+        assert_eq!(Some(command), Command::decode("peg 1 buy 2 2.4 u1 midpoint -0.01 2.5 2"));
+
+        let command = Command::SubmitPeg(LimitOrder::new(2, Side::Sell, 1.0, 2.6), Peg::new(PegReference::Primary));
+        assert_eq!(Some(command.clone()), Command::decode(&command.encode()));
+        assert_eq!(None, Command::decode("peg 2 sell 1 2.6 - best 0 - 8"));
+
+        let command = Command::RestorePeg(LimitOrder::new(3, Side::Sell, 1.0, 2.6).with_owner("u2"), Peg::new(PegReference::Market).with_limit(2.6));
+        assert_eq!("restore_peg 3 sell 1 2.6 u2 market 0 2.6 8", command.encode());
+        assert_eq!(Some(command), Command::decode("restore_peg 3 sell 1 2.6 u2 market 0 2.6 8"));
+    }
+
+    #[test]
+    fn can_encode_order_groups() {
+        let oco = Command::SubmitOco(LimitOrder::new(1, Side::Sell, 0.5, 2.1).with_owner("u1"), LimitOrder::new(2, Side::Sell, 0.5, 1.9).with_owner("u1"));
+        assert_eq!("oco 1 sell 0.5 2.1 u1 2 sell 0.5 1.9 u1", oco.encode());
+        assert_eq!(Some(oco), Command::decode("oco 1 sell 0.5 2.1 u1 2 sell 0.5 1.9 u1"));
+
+        let bracket = Command::SubmitBracket(LimitOrder::new(3, Side::Buy, 0.5, 2.0), LimitOrder::new(0, Side::Sell, 0.5, 2.2), LimitOrder::new(0, Side::Sell, 0.5, 1.8));
+        assert_eq!(Some(bracket.clone()), Command::decode(&bracket.encode()));
+
+        assert_eq!(Some(Command::RestoreOco(1, 2)), Command::decode("restore_oco 1 2"));
+        let bracket = Command::RestoreBracket(3, LimitOrder::new(0, Side::Sell, 0.5, 2.2).with_owner("u1"), LimitOrder::new(0, Side::Sell, 0.5, 1.8).with_owner("u1"));
+        assert_eq!("restore_bracket 3 0 sell 0.5 2.2 u1 0 sell 0.5 1.8 u1", bracket.encode());
+        assert_eq!(Some(bracket), Command::decode("restore_bracket 3 0 sell 0.5 2.2 u1 0 sell 0.5 1.8 u1"));
+        assert_eq!(None, Command::decode("bracket 3 buy 0.5 2 -"));
+    }
+
+    #[test]
+    fn can_decode_orders_without_owner() {
+        assert_eq!(
+            Some(Command::Amend(LimitOrder::new(1, Side::Sell, 0.5, 2.0), 2.1, 0.4)),
+            Command::decode("amend 1 sell 0.5 2 2.1 0.4")
+        );
+        assert_eq!(
+            Some(Command::Submit(LimitOrder::new(2, Side::Buy, 0.5, 2.0).with_owner("u2"))),
+            Command::decode("submit 2 buy 0.5 2 u2")
+        );
+    }
+
+    #[test]
+    fn escapes_owners() {
+        for owner in &["-", "u 1", "u%1", "u\n1", "用户 1", "--"] {
+            let command = Command::Submit(LimitOrder::new(1, Side::Buy, 2.0, 1.5).with_owner(owner));
+            assert_eq!(6, command.encode().split(' ').count());
+            assert_eq!(Some(command.clone()), Command::decode(&command.encode()));
+
+            let filter = Command::MassCancel(CancelFilter::all().with_owner(owner));
+            assert_eq!(Some(filter.clone()), Command::decode(&filter.encode()));
+        }
+        assert_eq!("submit 1 buy 2 1.5 %2D", Command::Submit(LimitOrder::new(1, Side::Buy, 2.0, 1.5).with_owner("-")).encode());
+        assert_eq!(None, Command::decode("submit 1 buy 2 1.5 u%2"));
+    }
+
+    #[test]
+    fn truncates_torn_tail_and_rejects_corruption() {
+        let dir = temp_dir("journal-torn");
+        let mut journal = Journal::open(&dir).unwrap();
+        journal.append(&Command::Submit(LimitOrder::new(1, Side::Sell, 1.0, 2.0))).unwrap();
+        journal.append(&Command::Submit(LimitOrder::new(2, Side::Sell, 1.0, 2.0))).unwrap();
+        let path = dir.join(format!("{:020}.journal", 1));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"3 0000abcd submit 3 se").unwrap();
+
+        assert_eq!(2, read(&dir).unwrap().len());
+        let mut journal = Journal::open(&dir).unwrap();
+        assert_eq!(3, journal.append(&Command::Submit(LimitOrder::new(3, Side::Sell, 1.0, 2.0))).unwrap());
+        assert_eq!(3, read(&dir).unwrap().len());
+
+        // 中间的记录被改动
+        let content = fs::read_to_string(&path).unwrap().replacen("submit 2 sell 1", "submit 2 sell 9", 1);
+        fs::write(&path, content).unwrap();
+        assert!(read(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn can_rotate_and_truncate() {
+        let dir = temp_dir("journal-rotate");
+        let mut journal = Journal::open(&dir).unwrap();
+        for id in 1..=5 {
+            journal.append(&Command::Submit(LimitOrder::new(id, Side::Buy, 1.0, 2.0))).unwrap();
+            if id == 2 || id == 4 {
+                journal.rotate().unwrap();
+            }
+        }
+
+        assert_eq!(vec![3, 4, 5], read_from(&dir, 2).unwrap().iter().map(|(seq, _)| *seq).collect::<Vec<u64>>());
+        // 第二个段文件里还有序号4的记录，不能删
+        assert_eq!(1, truncate(&dir, 3).unwrap());
+        assert!(read(&dir).is_err());
+        assert_eq!(vec![4, 5], read_from(&dir, 3).unwrap().iter().map(|(seq, _)| *seq).collect::<Vec<u64>>());
+        assert!(read_from(&dir, 1).is_err());
+
+        assert_eq!(1, truncate(&dir, 5).unwrap());
+        assert!(read_from(&dir, 5).unwrap().is_empty());
+        let mut journal = Journal::open(&dir).unwrap();
+        assert_eq!(6, journal.append(&Command::Submit(LimitOrder::new(6, Side::Buy, 1.0, 2.0))).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,54 @@
+use std::fmt;
 use crate::engine::Side;
-use bigdecimal::BigDecimal;
-use bigdecimal::FromPrimitive;
-use bigdecimal::ToPrimitive;
+use crate::accounts::decimal;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct LimitOrder {
     pub id: u64,
     // pub timestamp: u64,
     pub side: Side,
     pub volume: f64,
     pub price: f64,
+    // 下单的用户，成交时带在TradeEvent里
+    pub owner: String,
+    pub condition: Condition,
+}
+
+// 成交条件，只在连续撮合时生效，集合竞价和批量撮合按普通限价单成交
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    None,
+    // 每次成交至少这么多
+    MinFill(f64),
+    // 进入撮合时一共至少能成交这么多才成交，否则整单挂单；挂单之后每次成交至少这么多
+    MinTotal(f64),
+    // 全部成交，否则不成交
+    AllOrNone,
+}
+
+impl Condition {
+    // 下单消息、日志和数据库里的写法: "aon"、"min=<数量>"、"min_total=<数量>"，数量必须大于0
+    pub fn parse(condition: &str) -> Option<Condition> {
+        let volume = |volume: &str| volume.parse::<f64>().ok().filter(|volume| *volume > 0.0);
+        match condition.splitn(2, '=').collect::<Vec<&str>>().as_slice() {
+            ["none"] => Some(Condition::None),
+            ["aon"] => Some(Condition::AllOrNone),
+            ["min", min] => Some(Condition::MinFill(volume(min)?)),
+            ["min_total", min] => Some(Condition::MinTotal(volume(min)?)),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::None => write!(f, "none"),
+            Condition::MinFill(volume) => write!(f, "min={}", volume),
+            Condition::MinTotal(volume) => write!(f, "min_total={}", volume),
+            Condition::AllOrNone => write!(f, "aon"),
+        }
+    }
 }
 
 impl LimitOrder {
@@ -20,13 +59,34 @@ impl LimitOrder {
             side: side,
             volume: volume,
             price: price,
+            owner: String::new(),
+            condition: Condition::None,
+        }
+    }
+
+    pub fn with_owner(mut self, owner: &str) -> LimitOrder {
+        self.owner = owner.to_string();
+        self
+    }
+
+    pub fn with_condition(mut self, condition: Condition) -> LimitOrder {
+        self.condition = condition;
+        self
+    }
+
+    // 作为挂单时一次成交至少要成交的数量，剩余数量不够时要一次全部成交
+    pub fn min_fill(&self) -> f64 {
+        match self.condition {
+            Condition::None => 0.0,
+            Condition::MinFill(volume) | Condition::MinTotal(volume) => volume.min(self.volume),
+            Condition::AllOrNone => self.volume,
         }
     }
 
     pub fn fill(&mut self, trade_volume: f64) {
         if self.volume >= trade_volume {
-            let result = BigDecimal::from_f64(self.volume).unwrap() - BigDecimal::from_f64(trade_volume).unwrap();
-            self.volume = result.to_f64().unwrap();
+            let result = decimal(self.volume) - decimal(trade_volume);
+            self.volume = result.to_string().parse::<f64>().unwrap();
         } 
     }
 
@@ -47,9 +107,9 @@ impl LimitOrder {
             let trade_price = counter_order.price;
             let trade_volume = self.volume.min(counter_order.volume);
             // println!("{:?}", (&big_trade_volume * &big_trade_price).to_f64());
-            let trade_funds = BigDecimal::from_f64(trade_volume).unwrap() * BigDecimal::from_f64(trade_price).unwrap();
+            let trade_funds = decimal(trade_volume) * decimal(trade_price);
             // println!("{0} - {1}", self.id, counter_order.id);
-            Some((trade_price, trade_volume, trade_funds.to_f64().unwrap()))
+            Some((trade_price, trade_volume, trade_funds.to_string().parse::<f64>().unwrap()))
         } else {
             None
         }
@@ -62,7 +122,7 @@ mod tests {
     use bigdecimal::BigDecimal;
     use bigdecimal::FromPrimitive;
     use bigdecimal::ToPrimitive;
-    use super::LimitOrder;
+    use super::{Condition, LimitOrder};
     use crate::engine::Side; 
 
     fn create_limit_order() -> LimitOrder {
@@ -115,4 +175,13 @@ mod tests {
         assert_eq!(0.3, result.to_f64().unwrap());
     }
 
+    #[test]
+    fn can_parse_condition() {
+        assert_eq!(Some(Condition::MinFill(0.5)), Condition::parse("min=0.5"));
+        assert_eq!(Some(Condition::AllOrNone), Condition::parse(&Condition::AllOrNone.to_string()));
+        assert_eq!(None, Condition::parse("min=0"));
+        assert_eq!(None, Condition::parse("min_total=-1"));
+        assert_eq!(None, Condition::parse("all"));
+    }
+
 }
@@ -0,0 +1,71 @@
+use std::fmt;
+
+// 市场的交易状态
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MarketState {
+    // 正常撮合
+    Continuous,
+    // 暂停，拒绝所有下单、改单和撤单
+    Halted,
+    // 只能撤单
+    CancelOnly,
+    // 只接受不会立即成交的挂单
+    PostOnly,
+    // 集合竞价: 订单只挂单不撮合，uncross时按统一价格成交
+    Auction,
+}
+
+impl MarketState {
+    pub fn parse(name: &str) -> Option<MarketState> {
+        match name {
+            "continuous" => Some(MarketState::Continuous),
+            "halted" => Some(MarketState::Halted),
+            "cancel_only" => Some(MarketState::CancelOnly),
+            "post_only" => Some(MarketState::PostOnly),
+            "auction" => Some(MarketState::Auction),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MarketState::Continuous => "continuous",
+            MarketState::Halted => "halted",
+            MarketState::CancelOnly => "cancel_only",
+            MarketState::PostOnly => "post_only",
+            MarketState::Auction => "auction",
+        }
+    }
+}
+
+impl fmt::Display for MarketState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// 市场状态的切换，在命令执行之后发出
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MarketStateEvent {
+    pub from: MarketState,
+    pub to: MarketState,
+}
+
+impl fmt::Display for MarketStateEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "market_state from={} to={}", self.from, self.to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MarketState;
+
+    #[test]
+    fn can_parse_names() {
+        for state in &[MarketState::Continuous, MarketState::Halted, MarketState::CancelOnly, MarketState::PostOnly, MarketState::Auction] {
+            assert_eq!(Some(*state), MarketState::parse(state.name()));
+        }
+        assert_eq!(None, MarketState::parse("closed"));
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+use bigdecimal::BigDecimal;
+
+use crate::accounts::decimal;
+
+// 同一价位上的分配规则: 把主动单的数量分给这一价位按时间排列的挂单
+// 返回和resting一一对应的分配数量，总和是volume和这一价位总量的较小值，结果只取决于输入
+pub trait MatchingPolicy: fmt::Debug + Send + Sync {
+    fn allocate(&self, volume: f64, resting: &[f64]) -> Vec<f64>;
+}
+
+// 价格优先、时间优先: 按时间顺序逐个成交
+#[derive(Debug, Clone)]
+pub struct PriceTime;
+
+impl MatchingPolicy for PriceTime {
+    fn allocate(&self, volume: f64, resting: &[f64]) -> Vec<f64> {
+        let resting = resting.iter().map(|volume| decimal(*volume)).collect::<Vec<BigDecimal>>();
+        to_f64(fifo(decimal(volume), &resting, vec![BigDecimal::from(0); resting.len()]))
+    }
+}
+
+// 按挂单数量的比例分配，向下取整到最小交易单位（volume_decimals位小数），零头按时间顺序分配
+#[derive(Debug, Clone)]
+pub struct ProRata {
+    pub volume_decimals: u32,
+}
+
+impl ProRata {
+    pub fn new(volume_decimals: u32) -> ProRata {
+        ProRata { volume_decimals: volume_decimals }
+    }
+}
+
+impl MatchingPolicy for ProRata {
+    fn allocate(&self, volume: f64, resting: &[f64]) -> Vec<f64> {
+        let resting = resting.iter().map(|volume| decimal(*volume)).collect::<Vec<BigDecimal>>();
+        to_f64(pro_rata(decimal(volume), &resting, self.volume_decimals, &BigDecimal::from(0)))
+    }
+}
+
+// 时间最早的挂单先成交，剩下的数量按比例分给其他挂单
+// 按比例分到的数量小于min_allocation时不分，和取整的零头一起按时间顺序分配
+#[derive(Debug, Clone)]
+pub struct Hybrid {
+    pub min_allocation: f64,
+    pub volume_decimals: u32,
+}
+
+impl Hybrid {
+    pub fn new(min_allocation: f64, volume_decimals: u32) -> Hybrid {
+        Hybrid {
+            min_allocation: min_allocation,
+            volume_decimals: volume_decimals,
+        }
+    }
+}
+
+impl MatchingPolicy for Hybrid {
+    fn allocate(&self, volume: f64, resting: &[f64]) -> Vec<f64> {
+        let resting = resting.iter().map(|volume| decimal(*volume)).collect::<Vec<BigDecimal>>();
+        if resting.is_empty() {
+            return Vec::new();
+        }
+        let volume = decimal(volume);
+        let top = if volume < resting[0] { volume.clone() } else { resting[0].clone() };
+        let mut allocations = vec![top.clone()];
+        allocations.extend(pro_rata(volume - top, &resting[1..], self.volume_decimals, &decimal(self.min_allocation)));
+        to_f64(allocations)
+    }
+}
+
+fn pro_rata(volume: BigDecimal, resting: &[BigDecimal], volume_decimals: u32, min_allocation: &BigDecimal) -> Vec<BigDecimal> {
+    let total = resting.iter().fold(BigDecimal::from(0), |sum, volume| sum + volume);
+    if volume >= total {
+        return resting.to_vec();
+    }
+    // with_scale是截断，正数时等于向下取整
+    let allocations = resting.iter()
+        .map(|resting_volume| (volume.clone() * resting_volume / &total).with_scale(volume_decimals as i64))
+        .map(|allocation| if allocation < *min_allocation { BigDecimal::from(0) } else { allocation })
+        .collect::<Vec<BigDecimal>>();
+    let allocated = allocations.iter().fold(BigDecimal::from(0), |sum, allocation| sum + allocation);
+    fifo(volume - allocated, resting, allocations)
+}
+
+// 把volume按时间顺序加到已有的分配上，每个挂单不超过它的数量
+fn fifo(mut volume: BigDecimal, resting: &[BigDecimal], mut allocations: Vec<BigDecimal>) -> Vec<BigDecimal> {
+    let zero = BigDecimal::from(0);
+    for (allocation, resting_volume) in allocations.iter_mut().zip(resting) {
+        if volume <= zero {
+            break;
+        }
+        let capacity = resting_volume.clone() - &*allocation;
+        let added = if volume < capacity { volume.clone() } else { capacity };
+        *allocation += &added;
+        volume -= added;
+    }
+    allocations
+}
+
+fn to_f64(allocations: Vec<BigDecimal>) -> Vec<f64> {
+    allocations.iter().map(|allocation| allocation.to_string().parse::<f64>().unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hybrid, MatchingPolicy, PriceTime, ProRata};
+
+    #[test]
+    fn price_time_fills_in_order() {
+        assert_eq!(vec![1.0, 2.5, 0.0], PriceTime.allocate(3.5, &[1.0, 3.0, 6.0]));
+        assert_eq!(vec![1.0, 3.0], PriceTime.allocate(5.0, &[1.0, 3.0]));
+        assert_eq!(vec![0.1, 0.2], PriceTime.allocate(0.3, &[0.1, 0.2]));
+    }
+
+    #[test]
+    fn pro_rata_rounds_to_lots_and_fills_remainder_in_order() {
+        // 0.4、1.2、2.4取整后是0、1、2，剩下的1给最早的挂单
+        assert_eq!(vec![1.0, 1.0, 2.0], ProRata::new(0).allocate(4.0, &[1.0, 3.0, 6.0]));
+        assert_eq!(vec![0.4, 1.2, 2.4], ProRata::new(1).allocate(4.0, &[1.0, 3.0, 6.0]));
+        assert_eq!(vec![1.0, 3.0, 6.0], ProRata::new(0).allocate(12.0, &[1.0, 3.0, 6.0]));
+        // 不够一个最小单位时也按时间顺序分配
+        assert_eq!(vec![0.5, 0.0], ProRata::new(0).allocate(0.5, &[1.0, 1.0]));
+    }
+
+    #[test]
+    fn hybrid_gives_top_order_priority() {
+        // 最早的挂单先成交2，剩下的5按4:4:1分成2、2、0，零头1按时间顺序给第二个挂单
+        assert_eq!(vec![2.0, 3.0, 2.0, 0.0], Hybrid::new(1.0, 0).allocate(7.0, &[2.0, 4.0, 4.0, 1.0]));
+        // 2.2、2.2、0.5都小于最小分配量，全部按时间顺序分配
+        assert_eq!(vec![2.0, 4.0, 1.0, 0.0], Hybrid::new(2.5, 1).allocate(7.0, &[2.0, 4.0, 4.0, 1.0]));
+        assert_eq!(vec![1.5, 0.0], Hybrid::new(1.0, 0).allocate(1.5, &[2.0, 4.0]));
+    }
+}
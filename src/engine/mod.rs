@@ -3,10 +3,42 @@ mod limit_order;
 mod order_book;
 mod order_book_pair;
 mod engine;
+mod market_state;
+mod price_band;
+mod auction;
+mod matching_policy;
+mod order_group;
+mod peg;
+pub mod journal;
+pub mod snapshot;
 
 pub use side::Side;
 pub use limit_order::LimitOrder;
+pub use limit_order::Condition;
 pub use order_book::OrderBook;
 pub use order_book_pair::OrderBookPair;
+pub use order_book_pair::Amount;
+pub use order_book_pair::Simulation;
 pub use engine::Engine;
 pub use engine::TradeEvent;
+pub use engine::CancelFilter;
+pub use engine::MassCancelEvent;
+pub use engine::ReduceEvent;
+pub use market_state::MarketState;
+pub use market_state::MarketStateEvent;
+pub use price_band::PriceBand;
+pub use price_band::BandAction;
+pub use price_band::BandReference;
+pub use auction::Uncross;
+pub use auction::equilibrium;
+pub use matching_policy::MatchingPolicy;
+pub use matching_policy::PriceTime;
+pub use matching_policy::ProRata;
+pub use matching_policy::Hybrid;
+pub use order_group::OrderGroups;
+pub use peg::Peg;
+pub use peg::PegReference;
+pub use peg::PeggedOrders;
+pub use journal::Journal;
+pub use journal::Command;
+pub use snapshot::Snapshots;
@@ -2,6 +2,10 @@ use crate::engine::Side;
 use crate::engine::LimitOrder;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::ops::Bound::Excluded;
+use std::ops::Bound::Unbounded;
+use bigdecimal::BigDecimal;
+use crate::accounts::decimal;
 
 #[derive(Debug)]
 pub struct OrderBook {
@@ -34,11 +38,13 @@ impl OrderBook {
     }
 
     pub fn remove(&mut self, order: &LimitOrder) -> Option<LimitOrder>{
-        let price_key = order.price.to_string();
+        let price_key = self.price_key(order);
         let result_order = match self.limit_orders.get_mut(&price_key) {
             Some(queue) => {
-                let index = queue.iter().position(|o| o.id == order.id).unwrap();
-                queue.remove(index)
+                match queue.iter().position(|o| o.id == order.id) {
+                    Some(index) => queue.remove(index),
+                    None => None
+                }
             },
             None => None
         };
@@ -55,6 +61,23 @@ impl OrderBook {
         return result_order;
     }
 
+    pub fn get_mut(&mut self, order: &LimitOrder) -> Option<&mut LimitOrder> {
+        let price_key = self.price_key(order);
+        match self.limit_orders.get_mut(&price_key) {
+            Some(queue) => queue.iter_mut().find(|o| o.id == order.id),
+            None => None
+        }
+    }
+
+    // 被价格带改过价格的挂单不在原来的价位上，按id找到实际的价位
+    fn price_key(&self, order: &LimitOrder) -> String {
+        let price_key = order.price.to_string();
+        match self.limit_orders.get(&price_key) {
+            Some(queue) if queue.iter().any(|o| o.id == order.id) => price_key,
+            _ => self.find(order.id).map_or(price_key, |o| o.price.to_string())
+        }
+    }
+
     pub fn top(&self) -> Option<&LimitOrder> {
         let line = match self.side {
             Side::Buy  => self.limit_orders.iter().last(),
@@ -79,6 +102,39 @@ impl OrderBook {
         }
     }
 
+    // 按撮合顺序排在price后面的价位，price为None时是最优价位
+    // 价位的顺序不会因为成交改变，撮合时可以跳过整个价位而不用重新从头找
+    pub fn next_price(&self, price: Option<f64>) -> Option<f64> {
+        let line = match (self.side, price) {
+            (Side::Buy, None) => self.limit_orders.iter().next_back(),
+            (Side::Sell, None) => self.limit_orders.iter().next(),
+            (Side::Buy, Some(price)) => self.limit_orders.range(..price.to_string()).next_back(),
+            (Side::Sell, Some(price)) => self.limit_orders.range((Excluded(price.to_string()), Unbounded)).next(),
+        };
+        line.and_then(|(_price_key, price_level)| price_level.front()).map(|order| order.price)
+    }
+
+    // 按撮合的先后顺序遍历订单: 价格优先，同价位时间优先
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &LimitOrder> + '_> {
+        match self.side {
+            Side::Buy => Box::new(self.limit_orders.values().rev().flat_map(|orders| orders.iter())),
+            Side::Sell => Box::new(self.limit_orders.values().flat_map(|orders| orders.iter())),
+        }
+    }
+
+    // 订单簿不按id索引，查询时按顺序扫描
+    // 一个价位上按时间排列的挂单
+    pub fn orders_at(&self, price: f64) -> Vec<LimitOrder> {
+        match self.limit_orders.get(&price.to_string()) {
+            Some(queue) => queue.iter().cloned().collect(),
+            None => Vec::new()
+        }
+    }
+
+    pub fn find(&self, id: u64) -> Option<&LimitOrder> {
+        self.iter().find(|order| order.id == id)
+    }
+
     // pub fn fill_top(&mut self, trade_volume: f64) {
         // match self.top_mut() {
             // Some(top_order) => {
@@ -98,6 +154,17 @@ impl OrderBook {
         self.limit_orders.len()
     }
 
+    // len()是价格档位数，这里是订单数
+    pub fn orders_count(&self) -> usize {
+        self.limit_orders.values().map(|orders| orders.len()).sum()
+    }
+
+    pub fn total_volume(&self) -> f64 {
+        let total = self.limit_orders.values()
+            .flat_map(|orders| orders.iter())
+            .fold(BigDecimal::from(0), |total, order| total + decimal(order.volume));
+        total.to_string().parse::<f64>().unwrap()
+    }
 
 }
 
@@ -105,6 +172,7 @@ impl OrderBook {
 mod tests {
     use super::OrderBook;
     use crate::engine::LimitOrder;
+    use crate::engine::Condition;
     use crate::engine::Side;
 
     #[test]
@@ -123,9 +191,11 @@ mod tests {
             price: 1.34,
             volume: 3.00,
             side: Side::Buy,
+            owner: "u1".to_string(),
+            condition: Condition::None,
             // timestamp: 12345678
         };
-        order_book.add(limit_order);
+        order_book.add(limit_order.clone());
         assert!(!order_book.is_empty());
 
         let order = order_book.top().unwrap();
@@ -141,9 +211,11 @@ mod tests {
             price: 1.34,
             volume: 3.00,
             side: Side::Buy,
+            owner: "u1".to_string(),
+            condition: Condition::None,
             // timestamp: 12345678
         };
-        order_book.add(limit_order);
+        order_book.add(limit_order.clone());
         assert!(!order_book.is_empty());
 
         let order = order_book.top().unwrap().clone();
@@ -153,4 +225,35 @@ mod tests {
 
         assert!(order_book.is_empty());
     }
+
+    #[test]
+    fn walks_price_levels_in_matching_order() {
+        let mut order_book = OrderBook::new(Side::Buy);
+        order_book.add(LimitOrder::new(1, Side::Buy, 0.1, 1.34));
+        order_book.add(LimitOrder::new(2, Side::Buy, 0.2, 1.36));
+        order_book.add(LimitOrder::new(3, Side::Buy, 0.3, 1.35));
+
+        assert_eq!(Some(1.36), order_book.next_price(None));
+        assert_eq!(Some(1.35), order_book.next_price(Some(1.36)));
+        assert_eq!(Some(1.34), order_book.next_price(Some(1.35)));
+        assert_eq!(None, order_book.next_price(Some(1.34)));
+
+        let mut order_book = OrderBook::new(Side::Sell);
+        order_book.add(LimitOrder::new(4, Side::Sell, 0.1, 1.34));
+        order_book.add(LimitOrder::new(5, Side::Sell, 0.2, 1.36));
+        assert_eq!(Some(1.34), order_book.next_price(None));
+        assert_eq!(Some(1.36), order_book.next_price(Some(1.35)));
+    }
+
+    #[test]
+    fn can_sum_orders() {
+        let mut order_book = OrderBook::new(Side::Sell);
+        order_book.add(LimitOrder::new(1, Side::Sell, 0.1, 1.34));
+        order_book.add(LimitOrder::new(2, Side::Sell, 0.2, 1.34));
+        order_book.add(LimitOrder::new(3, Side::Sell, 1.5, 1.36));
+
+        assert_eq!(2, order_book.len());
+        assert_eq!(3, order_book.orders_count());
+        assert_eq!(1.8, order_book.total_volume());
+    }
 }
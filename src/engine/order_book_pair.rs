@@ -1,5 +1,10 @@
+use bigdecimal::BigDecimal;
+use bigdecimal::Zero;
+
 use crate::engine::Side;
 use crate::engine::OrderBook;
+use crate::engine::LimitOrder;
+use crate::accounts::decimal;
 
 #[derive(Debug)]
 pub struct OrderBookPair {
@@ -7,6 +12,27 @@ pub struct OrderBookPair {
     pub buy_order_book: OrderBook
 }
 
+// 模拟成交的数量: 按base数量，或者按quote金额（买单花多少钱、卖单想换回多少钱）
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Amount {
+    Volume(f64),
+    Funds(f64),
+}
+
+// 模拟撮合的结果，fills是依次和每个对手单的(price, volume, funds)
+// slippage是平均成交价相对对手方最优价的不利偏离，百分比，保留4位小数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub fills: Vec<(f64, f64, f64)>,
+    pub volume: f64,
+    pub funds: f64,
+    pub best_price: Option<f64>,
+    pub average_price: Option<f64>,
+    pub slippage: f64,
+    // 对手盘不够（或者超出限价）时没有成交的部分，单位和Amount一致
+    pub remaining: f64,
+}
+
 impl OrderBookPair {
     pub fn new() -> OrderBookPair {
         let sell_order_book = OrderBook::new(Side::Sell);
@@ -30,11 +56,96 @@ impl OrderBookPair {
             Side::Buy => (&mut self.buy_order_book, &mut self.sell_order_book)
         }
     }
+
+    pub fn find(&self, id: u64) -> Option<&LimitOrder> {
+        self.buy_order_book.find(id).or_else(|| self.sell_order_book.find(id))
+    }
+
+    // 某个用户还在挂单的订单，先买后卖，各自按撮合的先后顺序
+    pub fn orders_by_owner(&self, owner: &str) -> Vec<&LimitOrder> {
+        self.buy_order_book.iter()
+            .chain(self.sell_order_book.iter())
+            .filter(|order| order.owner == owner)
+            .collect()
+    }
+
+    // 不修改订单簿也不产生事件，模拟一个side方向的订单按价格优先、时间优先吃掉对手单的结果
+    // 成交价和数量的规则和撮合一样（LimitOrder::trade_with）；没有限价时吃到对手盘为止
+    pub fn simulate(&self, side: Side, amount: Amount, limit_price: Option<f64>) -> Simulation {
+        let (_book, counter_book) = self.get_books(side);
+        let price = limit_price.unwrap_or(match side {
+            Side::Buy => std::f64::MAX,
+            Side::Sell => 0.0,
+        });
+        let mut taker = LimitOrder::new(0, side, 0.0, price);
+        let mut remaining = match amount {
+            Amount::Volume(volume) => decimal(volume),
+            Amount::Funds(funds) => decimal(funds),
+        };
+        let mut volume = BigDecimal::zero();
+        let mut funds = BigDecimal::zero();
+        let mut fills = Vec::new();
+
+        for counter_order in counter_book.iter() {
+            if remaining <= BigDecimal::zero() {
+                break;
+            }
+            // 按金额时，用剩余金额在对手价上能成交的数量作为这一次的数量
+            taker.volume = match amount {
+                Amount::Volume(_) => remaining.to_string().parse::<f64>().unwrap(),
+                Amount::Funds(_) => (remaining.clone() / decimal(counter_order.price)).with_scale(16).to_string().parse::<f64>().unwrap(),
+            };
+            if taker.filled() {
+                break;
+            }
+            match taker.trade_with(counter_order) {
+                Some((trade_price, trade_volume, trade_funds)) => {
+                    remaining = remaining - match amount {
+                        Amount::Volume(_) => decimal(trade_volume),
+                        Amount::Funds(_) => decimal(trade_funds),
+                    };
+                    volume = volume + decimal(trade_volume);
+                    funds = funds + decimal(trade_funds);
+                    fills.push((trade_price, trade_volume, trade_funds));
+                },
+                None => break
+            }
+        }
+
+        let best_price = counter_book.top().map(|order| order.price);
+        let average_price = if fills.is_empty() {
+            None
+        } else {
+            Some((funds.clone() / volume.clone()).with_scale(16))
+        };
+        let slippage = match (best_price, average_price.as_ref()) {
+            (Some(best_price), Some(average_price)) => {
+                let best_price = decimal(best_price);
+                let difference = match side {
+                    Side::Buy => average_price.clone() - best_price.clone(),
+                    Side::Sell => best_price.clone() - average_price.clone(),
+                };
+                (difference * BigDecimal::from(100) / best_price).with_scale(4)
+            },
+            _ => BigDecimal::zero()
+        };
+
+        Simulation {
+            fills: fills,
+            volume: volume.to_string().parse::<f64>().unwrap(),
+            funds: funds.to_string().parse::<f64>().unwrap(),
+            best_price: best_price,
+            average_price: average_price.map(|price| price.to_string().parse::<f64>().unwrap()),
+            slippage: slippage.to_string().parse::<f64>().unwrap(),
+            remaining: remaining.max(BigDecimal::zero()).to_string().parse::<f64>().unwrap(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::OrderBookPair;
+    use super::{Amount, OrderBookPair};
+    use crate::engine::LimitOrder;
     use crate::engine::Side;
 
     #[test]
@@ -45,4 +156,60 @@ mod tests {
         assert_eq!(sell_order_book.side, Side::Sell);
     }
 
+    fn create_order_book_pair() -> OrderBookPair {
+        let mut order_book_pair = OrderBookPair::new();
+        order_book_pair.sell_order_book.add(LimitOrder::new(1, Side::Sell, 1.0, 2.0));
+        order_book_pair.sell_order_book.add(LimitOrder::new(2, Side::Sell, 0.5, 2.1));
+        order_book_pair.sell_order_book.add(LimitOrder::new(3, Side::Sell, 2.0, 2.2));
+        order_book_pair.buy_order_book.add(LimitOrder::new(4, Side::Buy, 1.0, 1.9));
+        order_book_pair.buy_order_book.add(LimitOrder::new(5, Side::Buy, 1.0, 1.8));
+        order_book_pair
+    }
+
+    #[test]
+    fn can_simulate_by_volume() {
+        let order_book_pair = create_order_book_pair();
+        let simulation = order_book_pair.simulate(Side::Buy, Amount::Volume(2.0), None);
+        assert_eq!(vec![(2.0, 1.0, 2.0), (2.1, 0.5, 1.05), (2.2, 0.5, 1.1)], simulation.fills);
+        assert_eq!((2.0, 4.15, 0.0), (simulation.volume, simulation.funds, simulation.remaining));
+        assert_eq!((Some(2.0), Some(2.075), 3.75), (simulation.best_price, simulation.average_price, simulation.slippage));
+
+        // 限价以外的对手单不成交
+        let simulation = order_book_pair.simulate(Side::Sell, Amount::Volume(3.0), Some(1.85));
+        assert_eq!(vec![(1.9, 1.0, 1.9)], simulation.fills);
+        assert_eq!((2.0, 0.0), (simulation.remaining, simulation.slippage));
+
+        // 订单簿没有变化
+        assert_eq!(3, order_book_pair.sell_order_book.orders_count());
+        assert_eq!(2.0, order_book_pair.buy_order_book.total_volume());
+    }
+
+    #[test]
+    fn can_simulate_by_funds() {
+        let order_book_pair = create_order_book_pair();
+        let simulation = order_book_pair.simulate(Side::Buy, Amount::Funds(3.05), None);
+        assert_eq!(vec![(2.0, 1.0, 2.0), (2.1, 0.5, 1.05)], simulation.fills);
+        assert_eq!((1.5, 3.05, 0.0), (simulation.volume, simulation.funds, simulation.remaining));
+
+        let simulation = order_book_pair.simulate(Side::Buy, Amount::Funds(2.44), None);
+        assert_eq!(vec![(2.0, 1.0, 2.0), (2.1, 0.2095238095238095, 0.43999999999999995)], simulation.fills);
+
+        let simulation = OrderBookPair::new().simulate(Side::Sell, Amount::Volume(1.0), None);
+        assert_eq!((None, None, 1.0), (simulation.best_price, simulation.average_price, simulation.remaining));
+    }
+
+    #[test]
+    fn can_find_orders() {
+        let mut order_book_pair = OrderBookPair::new();
+        order_book_pair.sell_order_book.add(LimitOrder::new(1, Side::Sell, 1.0, 2.0).with_owner("u1"));
+        order_book_pair.sell_order_book.add(LimitOrder::new(2, Side::Sell, 0.5, 2.1).with_owner("u2"));
+        order_book_pair.buy_order_book.add(LimitOrder::new(3, Side::Buy, 1.0, 1.8).with_owner("u1"));
+        order_book_pair.buy_order_book.add(LimitOrder::new(4, Side::Buy, 1.0, 1.9).with_owner("u1"));
+
+        assert_eq!(Some(0.5), order_book_pair.find(2).map(|order| order.volume));
+        assert_eq!(Some(Side::Buy), order_book_pair.find(3).map(|order| order.side));
+        assert!(order_book_pair.find(5).is_none());
+        assert_eq!(vec![4, 3, 1], order_book_pair.orders_by_owner("u1").iter().map(|order| order.id).collect::<Vec<u64>>());
+        assert!(order_book_pair.orders_by_owner("u3").is_empty());
+    }
 }
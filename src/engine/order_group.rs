@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+
+use crate::engine::LimitOrder;
+
+// 互相关联的订单
+// OCO: 两条腿里一条有成交或者被撤销，另一条自动撤销
+// 括号单: 入场单全部成交之后才挂出两条出场单，出场单之间是OCO；入场单被撤销时出场单作废
+// 用BTreeMap保证遍历（快照）的顺序是确定的
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderGroups {
+    oco: BTreeMap<u64, u64>,
+    brackets: BTreeMap<u64, (LimitOrder, LimitOrder)>,
+}
+
+impl OrderGroups {
+    pub fn new() -> OrderGroups {
+        OrderGroups {
+            oco: BTreeMap::new(),
+            brackets: BTreeMap::new(),
+        }
+    }
+
+    pub fn link(&mut self, first: u64, second: u64) {
+        self.oco.insert(first, second);
+        self.oco.insert(second, first);
+    }
+
+    pub fn partner(&self, id: u64) -> Option<u64> {
+        self.oco.get(&id).cloned()
+    }
+
+    // 解除OCO关系，返回另一条腿
+    pub fn unlink(&mut self, id: u64) -> Option<u64> {
+        let partner = self.oco.remove(&id)?;
+        self.oco.remove(&partner);
+        Some(partner)
+    }
+
+    // 出场单的id由调用者在挂出时分配
+    pub fn add_bracket(&mut self, entry_id: u64, exits: (LimitOrder, LimitOrder)) {
+        self.brackets.insert(entry_id, exits);
+    }
+
+    pub fn take_bracket(&mut self, entry_id: u64) -> Option<(LimitOrder, LimitOrder)> {
+        self.brackets.remove(&entry_id)
+    }
+
+    // 每对OCO只出现一次，小的id在前
+    pub fn oco_pairs(&self) -> Vec<(u64, u64)> {
+        self.oco.iter().filter(|(first, second)| first < second).map(|(first, second)| (*first, *second)).collect()
+    }
+
+    pub fn brackets(&self) -> Vec<(u64, &LimitOrder, &LimitOrder)> {
+        self.brackets.iter().map(|(entry_id, (first, second))| (*entry_id, first, second)).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.oco.is_empty() && self.brackets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderGroups;
+    use crate::engine::LimitOrder;
+    use crate::engine::Side;
+
+    #[test]
+    fn links_and_unlinks_both_legs() {
+        let mut groups = OrderGroups::new();
+        groups.link(5, 2);
+        groups.link(7, 9);
+        assert_eq!(Some(2), groups.partner(5));
+        assert_eq!(vec![(2, 5), (7, 9)], groups.oco_pairs());
+
+        assert_eq!(Some(5), groups.unlink(2));
+        assert_eq!(None, groups.partner(5));
+        assert_eq!(None, groups.unlink(5));
+
+        groups.add_bracket(3, (LimitOrder::new(0, Side::Sell, 1.0, 11.0), LimitOrder::new(0, Side::Sell, 1.0, 9.0)));
+        groups.unlink(7);
+        assert!(!groups.is_empty());
+        assert_eq!(11.0, groups.take_bracket(3).unwrap().0.price);
+        assert!(groups.is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use bigdecimal::BigDecimal;
+
+use crate::engine::Side;
+use crate::accounts::decimal;
+
+// 挂钩订单跟随的价格
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PegReference {
+    // 本方最优价: 买单跟买一，卖单跟卖一
+    Primary,
+    // 对手方最优价: 买单跟卖一，卖单跟买一
+    Market,
+    // 买一和卖一的中间价
+    Midpoint,
+}
+
+impl PegReference {
+    pub fn parse(reference: &str) -> Option<PegReference> {
+        match reference {
+            "primary" => Some(PegReference::Primary),
+            "market" => Some(PegReference::Market),
+            "midpoint" => Some(PegReference::Midpoint),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for PegReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PegReference::Primary => write!(f, "primary"),
+            PegReference::Market => write!(f, "market"),
+            PegReference::Midpoint => write!(f, "midpoint"),
+        }
+    }
+}
+
+// 挂钩订单的价格 = 参考价 + offset（可以是负数），买单不高于limit，卖单不低于limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset: f64,
+    pub limit: Option<f64>,
+    // 价格按市场的价格精度取整，买单向下、卖单向上
+    pub price_decimals: u32,
+}
+
+impl Peg {
+    pub fn new(reference: PegReference) -> Peg {
+        Peg {
+            reference: reference,
+            offset: 0.0,
+            limit: None,
+            price_decimals: 8,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: f64) -> Peg {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: f64) -> Peg {
+        self.limit = Some(limit);
+        self
+    }
+
+    // best_bid和best_ask是不含挂钩订单的最优价，需要的最优价不存在或者算出的价格不是正数时返回None
+    pub fn price(&self, side: Side, best_bid: Option<f64>, best_ask: Option<f64>) -> Option<f64> {
+        let reference = match (self.reference, side) {
+            (PegReference::Primary, Side::Buy) | (PegReference::Market, Side::Sell) => decimal(best_bid?),
+            (PegReference::Primary, Side::Sell) | (PegReference::Market, Side::Buy) => decimal(best_ask?),
+            (PegReference::Midpoint, _) => (decimal(best_bid?) + decimal(best_ask?)) / BigDecimal::from(2),
+        };
+        let price = reference + decimal(self.offset);
+
+        // with_scale是截断，正数时等于向下取整
+        let floored = price.with_scale(self.price_decimals as i64);
+        let price = match side {
+            Side::Buy => floored,
+            Side::Sell if floored < price => floored + BigDecimal::new(1.into(), self.price_decimals as i64),
+            Side::Sell => floored,
+        };
+        let price = match (self.limit, side) {
+            (Some(limit), Side::Buy) if price > decimal(limit) => decimal(limit),
+            (Some(limit), Side::Sell) if price < decimal(limit) => decimal(limit),
+            _ => price
+        };
+        if price <= BigDecimal::from(0) {
+            return None;
+        }
+        Some(price.to_string().parse::<f64>().unwrap())
+    }
+}
+
+// 挂在订单簿上的挂钩订单，按id排列，重新定价时按id的顺序处理
+// 订单离开订单簿之后在下一次重新定价时删除
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeggedOrders {
+    pegs: BTreeMap<u64, (Side, Peg)>,
+}
+
+impl PeggedOrders {
+    pub fn new() -> PeggedOrders {
+        PeggedOrders {
+            pegs: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, id: u64, side: Side, peg: Peg) {
+        self.pegs.insert(id, (side, peg));
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<(Side, Peg)> {
+        self.pegs.remove(&id)
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.pegs.contains_key(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, Side, &Peg)> + '_ {
+        self.pegs.iter().map(|(id, (side, peg))| (*id, *side, peg))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pegs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Peg, PegReference, PeggedOrders};
+    use crate::engine::Side;
+
+    #[test]
+    fn follows_reference_with_offset_and_limit() {
+        let primary = Peg::new(PegReference::Primary).with_offset(0.01);
+        assert_eq!(Some(1.01), primary.price(Side::Buy, Some(1.0), Some(1.1)));
+        assert_eq!(Some(1.11), primary.price(Side::Sell, Some(1.0), Some(1.1)));
+        assert_eq!(None, primary.price(Side::Buy, None, Some(1.1)));
+
+        let market = Peg::new(PegReference::Market).with_offset(-0.02).with_limit(1.05);
+        assert_eq!(Some(1.05), market.price(Side::Buy, Some(1.0), Some(1.1)));
+        assert_eq!(Some(1.05), market.price(Side::Sell, Some(1.0), Some(1.1)));
+    }
+
+    #[test]
+    fn rounds_midpoint_away_from_the_other_side() {
+        let mut midpoint = Peg::new(PegReference::Midpoint);
+        midpoint.price_decimals = 1;
+        assert_eq!(Some(1.0), midpoint.price(Side::Buy, Some(1.0), Some(1.1)));
+        assert_eq!(Some(1.1), midpoint.price(Side::Sell, Some(1.0), Some(1.1)));
+        assert_eq!(None, midpoint.price(Side::Sell, Some(1.0), None));
+
+        midpoint.price_decimals = 2;
+        assert_eq!(Some(1.05), midpoint.price(Side::Buy, Some(1.0), Some(1.1)));
+    }
+
+    #[test]
+    fn iterates_pegs_by_id() {
+        let mut pegs = PeggedOrders::new();
+        pegs.add(7, Side::Sell, Peg::new(PegReference::Primary));
+        pegs.add(3, Side::Buy, Peg::new(PegReference::Midpoint));
+        assert_eq!(vec![3, 7], pegs.iter().map(|(id, _side, _peg)| id).collect::<Vec<u64>>());
+
+        assert_eq!(Some(Side::Sell), pegs.remove(7).map(|(side, _peg)| side));
+        assert!(!pegs.contains(7));
+        pegs.remove(3);
+        assert!(pegs.is_empty());
+    }
+}
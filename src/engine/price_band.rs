@@ -0,0 +1,90 @@
+use bigdecimal::BigDecimal;
+
+use crate::accounts::decimal;
+
+// 价格带的参考价: 最新成交价，或者外部推送的指数价格
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BandReference {
+    LastTrade,
+    Index,
+}
+
+// 主动单碰到价格带之后，剩余部分撤销，或者以价格带的边界价格挂单
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BandAction {
+    Cancel,
+    Rest,
+}
+
+// 只允许在参考价上下limit（比如0.05是±5%）之内成交
+// 被挡住的对手价偏离超过halt_limit时暂停交易halt_seconds秒
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriceBand {
+    pub reference: BandReference,
+    pub limit: f64,
+    pub action: BandAction,
+    pub halt_limit: Option<f64>,
+    pub halt_seconds: i64,
+    // 边界价格按市场的价格精度取整
+    pub price_decimals: u32,
+}
+
+impl PriceBand {
+    pub fn new(reference: BandReference, limit: f64, action: BandAction) -> PriceBand {
+        PriceBand {
+            reference: reference,
+            limit: limit,
+            action: action,
+            halt_limit: None,
+            halt_seconds: 0,
+            price_decimals: 8,
+        }
+    }
+
+    pub fn with_halt(mut self, halt_limit: f64, halt_seconds: i64) -> PriceBand {
+        self.halt_limit = Some(halt_limit);
+        self.halt_seconds = halt_seconds;
+        self
+    }
+
+    // 参考价reference下可以成交的价格范围[low, high]，向带内取整到价格精度
+    pub fn bounds(&self, reference: f64) -> (f64, f64) {
+        let reference = decimal(reference);
+        let one = BigDecimal::from(1);
+        let low = reference.clone() * (one.clone() - decimal(self.limit));
+        let high = reference * (one + decimal(self.limit));
+        let tick = BigDecimal::new(1.into(), self.price_decimals as i64);
+
+        // with_scale是截断，正数时等于向下取整
+        let floored = low.with_scale(self.price_decimals as i64);
+        let low = if floored < low { floored + tick } else { floored };
+        let high = high.with_scale(self.price_decimals as i64);
+        (low.to_string().parse::<f64>().unwrap(), high.to_string().parse::<f64>().unwrap())
+    }
+
+    // 是否偏离到需要暂停交易
+    pub fn should_halt(&self, reference: f64, price: f64) -> bool {
+        match self.halt_limit {
+            Some(halt_limit) => {
+                let deviation = (decimal(price) - decimal(reference)).abs() / decimal(reference);
+                deviation > decimal(halt_limit)
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BandAction, BandReference, PriceBand};
+
+    #[test]
+    fn rounds_bounds_into_the_band() {
+        let mut band = PriceBand::new(BandReference::LastTrade, 0.05, BandAction::Cancel).with_halt(0.1, 300);
+        band.price_decimals = 2;
+        assert_eq!((95.0, 105.0), band.bounds(100.0));
+        assert_eq!((1.17, 1.29), band.bounds(1.23));
+        assert!(!band.should_halt(100.0, 110.0));
+        assert!(band.should_halt(100.0, 89.99));
+    }
+}
@@ -0,0 +1,378 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::engine::Side;
+use crate::engine::LimitOrder;
+use crate::engine::OrderBookPair;
+use crate::engine::OrderGroups;
+use crate::engine::PeggedOrders;
+use crate::engine::Journal;
+use crate::engine::journal;
+
+const MAGIC: &str = "matching-snapshot";
+const VERSION: u32 = 7;
+
+// 订单簿在某个日志序号之后的完整状态
+// 订单按订单簿内的顺序（价格档位、同价位的时间优先）保存，按顺序加回去就能得到完全相同的订单簿
+//
+// 格式(v7):
+//   matching-snapshot 7
+//   seq <最后一条已应用的日志序号>
+//   last_trade_id <最后一笔成交的编号>
+//   last_order_id <进入过引擎的最大订单id>
+//   reference_price <价格带和集合竞价的参考价，没有时是"-">
+//   sell <订单数>
+//   <id> <side>[:成交条件] <volume> <price> <owner>
+//   ...
+//   buy <订单数>
+//   ...
+//   groups <OCO和括号单的个数>
+//   oco <id> <id>
+//   bracket <入场单id> <出场单> <出场单>
+//   ...
+//   pegs <挂钩订单数>
+//   peg <id> <side> <reference> <offset> <limit> <price_decimals>
+//   ...
+//   crc <前面所有内容的crc32>
+// v6没有last_order_id（按订单簿里最大的id），v5没有reference_price，v4没有pegs，v3的订单没有成交条件，v2没有groups，v1也没有last_trade_id这一行，订单没有owner，仍然可以读取
+#[derive(Debug)]
+pub struct Snapshot {
+    pub seq: u64,
+    pub last_trade_id: u64,
+    pub last_order_id: u64,
+    pub reference_price: Option<f64>,
+    pub order_book_pair: OrderBookPair,
+    pub groups: OrderGroups,
+    pub pegs: PeggedOrders,
+}
+
+impl Snapshot {
+    fn encode(seq: u64, order_book_pair: &OrderBookPair, last_trade_id: u64, last_order_id: u64, reference_price: Option<f64>, groups: &OrderGroups, pegs: &PeggedOrders) -> String {
+        let mut content = format!("{} {}\nseq {}\nlast_trade_id {}\nlast_order_id {}\n", MAGIC, VERSION, seq, last_trade_id, last_order_id);
+        content.push_str(&format!("reference_price {}\n", reference_price.map_or("-".to_string(), |price| price.to_string())));
+        for book in &[&order_book_pair.sell_order_book, &order_book_pair.buy_order_book] {
+            content.push_str(&format!("{} {}\n", book.side.to_string().to_lowercase(), book.orders_count()));
+            for order in book.limit_orders.values().flat_map(|orders| orders.iter()) {
+                content.push_str(&format!("{}\n", journal::encode_order(order)));
+            }
+        }
+        let oco_pairs = groups.oco_pairs();
+        let brackets = groups.brackets();
+        content.push_str(&format!("groups {}\n", oco_pairs.len() + brackets.len()));
+        for (first, second) in oco_pairs {
+            content.push_str(&format!("oco {} {}\n", first, second));
+        }
+        for (entry_id, first, second) in brackets {
+            content.push_str(&format!("bracket {} {} {}\n", entry_id, journal::encode_order(first), journal::encode_order(second)));
+        }
+        content.push_str(&format!("pegs {}\n", pegs.iter().count()));
+        for (id, side, peg) in pegs.iter() {
+            content.push_str(&format!("peg {} {} {}\n", id, side.to_string().to_lowercase(), journal::encode_peg(peg)));
+        }
+        let checksum = crc32fast::hash(content.as_bytes());
+        content.push_str(&format!("crc {:08x}\n", checksum));
+        content
+    }
+
+    fn decode(content: &str) -> Option<Snapshot> {
+        let crc_start = content.rfind("crc ")?;
+        let checksum = u32::from_str_radix(content[crc_start + 4..].trim_end(), 16).ok()?;
+        if crc32fast::hash(content[..crc_start].as_bytes()) != checksum {
+            return None;
+        }
+
+        let mut lines = content[..crc_start].lines();
+        let version = lines.next()?.strip_prefix(&format!("{} ", MAGIC))?.parse::<u32>().ok()?;
+        if version < 1 || version > VERSION {
+            return None;
+        }
+        let seq = lines.next()?.strip_prefix("seq ")?.parse::<u64>().ok()?;
+        let last_trade_id = if version >= 2 {
+            lines.next()?.strip_prefix("last_trade_id ")?.parse::<u64>().ok()?
+        } else {
+            0
+        };
+        let last_order_id = if version >= 7 {
+            Some(lines.next()?.strip_prefix("last_order_id ")?.parse::<u64>().ok()?)
+        } else {
+            None
+        };
+        let reference_price = if version >= 6 {
+            match lines.next()?.strip_prefix("reference_price ")? {
+                "-" => None,
+                price => Some(price.parse::<f64>().ok()?),
+            }
+        } else {
+            None
+        };
+
+        let mut order_book_pair = OrderBookPair::new();
+        for side in &[Side::Sell, Side::Buy] {
+            let header = lines.next()?;
+            let count = header.strip_prefix(&format!("{} ", side.to_string().to_lowercase()))?.parse::<usize>().ok()?;
+            let (book, _counter_book) = order_book_pair.get_books_mut(*side);
+            for _ in 0..count {
+                book.add(decode_order(lines.next()?, *side, version)?);
+            }
+        }
+        let mut groups = OrderGroups::new();
+        if version >= 3 {
+            let count = lines.next()?.strip_prefix("groups ")?.parse::<usize>().ok()?;
+            for _ in 0..count {
+                let fields = lines.next()?.split(' ').collect::<Vec<&str>>();
+                match (fields[0], fields.len()) {
+                    ("oco", 3) => groups.link(fields[1].parse::<u64>().ok()?, fields[2].parse::<u64>().ok()?),
+                    ("bracket", 12) => groups.add_bracket(fields[1].parse::<u64>().ok()?, (journal::decode_order(&fields[2..7])?, journal::decode_order(&fields[7..12])?)),
+                    _ => return None
+                }
+            }
+        }
+        let mut pegs = PeggedOrders::new();
+        if version >= 5 {
+            let count = lines.next()?.strip_prefix("pegs ")?.parse::<usize>().ok()?;
+            for _ in 0..count {
+                let fields = lines.next()?.split(' ').collect::<Vec<&str>>();
+                let side = match (fields[0], fields.len()) {
+                    ("peg", 7) if fields[2] == "buy" => Side::Buy,
+                    ("peg", 7) if fields[2] == "sell" => Side::Sell,
+                    _ => return None
+                };
+                pegs.add(fields[1].parse::<u64>().ok()?, side, journal::decode_peg(&fields[3..7])?);
+            }
+        }
+        if lines.next().is_some() {
+            return None;
+        }
+
+        let last_order_id = last_order_id.unwrap_or_else(|| {
+            order_book_pair.buy_order_book.iter().chain(order_book_pair.sell_order_book.iter()).map(|order| order.id).max().unwrap_or(0)
+        });
+        Some(Snapshot {
+            seq: seq,
+            last_trade_id: last_trade_id,
+            last_order_id: last_order_id,
+            reference_price: reference_price,
+            order_book_pair: order_book_pair,
+            groups: groups,
+            pegs: pegs,
+        })
+    }
+}
+
+fn decode_order(line: &str, side: Side, version: u32) -> Option<LimitOrder> {
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    let expected_len = if version >= 2 { 5 } else { 4 };
+    if fields.len() != expected_len {
+        return None;
+    }
+    journal::decode_order(&fields).filter(|order| order.side == side)
+}
+
+// 定期给订单簿做快照，然后删除已经不需要的日志段文件
+pub struct Snapshots {
+    dir: PathBuf,
+    // 每隔多少条日志做一次快照
+    interval: u64,
+    // 保留的快照个数，最新的快照损坏时可以退回到更早的快照，所以日志只删到最早保留的那个快照
+    keep: usize,
+}
+
+impl Snapshots {
+    pub fn new(dir: &Path, interval: u64) -> Snapshots {
+        Snapshots {
+            dir: dir.to_path_buf(),
+            interval: interval,
+            keep: 2,
+        }
+    }
+
+    pub fn is_due(&self, seq: u64) -> bool {
+        self.interval > 0 && seq % self.interval == 0
+    }
+
+    // 写入seq时刻的快照，切换日志段文件，删除多余的快照和已经被快照覆盖的日志段文件
+    pub fn take(&self, seq: u64, order_book_pair: &OrderBookPair, last_trade_id: u64, last_order_id: u64, reference_price: Option<f64>, groups: &OrderGroups, pegs: &PeggedOrders, journal: &mut Journal) -> io::Result<()> {
+        write(&self.dir, seq, order_book_pair, last_trade_id, last_order_id, reference_price, groups, pegs)?;
+        journal.rotate()?;
+
+        let mut paths = snapshots(&self.dir)?;
+        while paths.len() > self.keep {
+            fs::remove_file(paths.remove(0))?;
+        }
+        match paths.first().and_then(|path| snapshot_seq(path)) {
+            Some(oldest_seq) => {
+                journal::truncate(journal.dir(), oldest_seq)?;
+                Ok(())
+            },
+            None => Ok(())
+        }
+    }
+}
+
+// 先写临时文件，落盘后再改名，保证快照文件要么完整要么不存在
+pub fn write(dir: &Path, seq: u64, order_book_pair: &OrderBookPair, last_trade_id: u64, last_order_id: u64, reference_price: Option<f64>, groups: &OrderGroups, pegs: &PeggedOrders) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{:020}.snapshot", seq));
+    let tmp_path = path.with_extension("snapshot.tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(Snapshot::encode(seq, order_book_pair, last_trade_id, last_order_id, reference_price, groups, pegs).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(dir)?.sync_all()?;
+    Ok(path)
+}
+
+// 从新到旧找第一个完整的快照，同时返回跳过的损坏的快照文件，由调用者记录
+pub fn load_latest(dir: &Path) -> io::Result<(Option<Snapshot>, Vec<PathBuf>)> {
+    load_newest(dir, |_snapshot| true)
+}
+
+// 从新到旧找第一个完整的、成交都已经写入数据库的快照（last_trade_id不超过数据库里最后一笔成交）
+// 快照之前的成交没有写入时不能从这个快照开始，否则重放日志时补不回这些成交
+pub fn load_persisted(dir: &Path, persisted_trade_id: u64) -> io::Result<(Option<Snapshot>, Vec<PathBuf>)> {
+    load_newest(dir, |snapshot| snapshot.last_trade_id <= persisted_trade_id)
+}
+
+fn load_newest(dir: &Path, accept: impl Fn(&Snapshot) -> bool) -> io::Result<(Option<Snapshot>, Vec<PathBuf>)> {
+    let mut invalid = Vec::new();
+    for path in snapshots(dir)?.iter().rev() {
+        let snapshot = fs::read_to_string(path).ok().and_then(|content| Snapshot::decode(&content));
+        match snapshot {
+            Some(snapshot) if Some(snapshot.seq) == snapshot_seq(path) => if accept(&snapshot) {
+                return Ok((Some(snapshot), invalid));
+            },
+            _ => invalid.push(path.clone())
+        }
+    }
+    Ok((None, invalid))
+}
+
+fn snapshot_seq(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse::<u64>().ok()
+}
+
+// 按序号排序的快照文件
+fn snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    if !dir.exists() {
+        return Ok(paths);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "snapshot") && snapshot_seq(&path).is_some() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use super::{load_latest, load_persisted, write, Snapshot, Snapshots};
+    use crate::engine::LimitOrder;
+    use crate::engine::Side;
+    use crate::engine::OrderBookPair;
+    use crate::engine::OrderGroups;
+    use crate::engine::Peg;
+    use crate::engine::PegReference;
+    use crate::engine::PeggedOrders;
+    use crate::engine::Journal;
+    use crate::engine::Command;
+    use crate::engine::journal;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("matching-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn create_order_book_pair() -> OrderBookPair {
+        let mut order_book_pair = OrderBookPair::new();
+        order_book_pair.buy_order_book.add(LimitOrder::new(1, Side::Buy, 1.2, 1.34).with_owner("u1"));
+        order_book_pair.buy_order_book.add(LimitOrder::new(2, Side::Buy, 0.000003456, 1.34));
+        order_book_pair.buy_order_book.add(LimitOrder::new(3, Side::Buy, 0.9, 1.33));
+        order_book_pair.sell_order_book.add(LimitOrder::new(4, Side::Sell, 0.3, 1.345));
+        order_book_pair
+    }
+
+    #[test]
+    fn can_write_and_load() {
+        let dir = temp_dir("snapshot-load");
+        let order_book_pair = create_order_book_pair();
+        let mut groups = OrderGroups::new();
+        groups.link(3, 1);
+        groups.add_bracket(4, (LimitOrder::new(0, Side::Buy, 0.3, 1.2).with_owner("u2"), LimitOrder::new(0, Side::Buy, 0.3, 1.4)));
+        let mut pegs = PeggedOrders::new();
+        pegs.add(2, Side::Sell, Peg::new(PegReference::Midpoint).with_limit(1.3));
+        write(&dir, 7, &OrderBookPair::new(), 0, 0, None, &OrderGroups::new(), &PeggedOrders::new()).unwrap();
+        let path = write(&dir, 42, &order_book_pair, 5, 9, Some(1.335), &groups, &pegs).unwrap();
+
+        let snapshot = load_latest(&dir).unwrap().0.unwrap();
+        assert_eq!((42, 5, 9, Some(1.335)), (snapshot.seq, snapshot.last_trade_id, snapshot.last_order_id, snapshot.reference_price));
+        assert_eq!(format!("{:?}", order_book_pair), format!("{:?}", snapshot.order_book_pair));
+        assert_eq!(groups, snapshot.groups);
+        assert_eq!(pegs, snapshot.pegs);
+
+        // 最新的快照损坏时退回到上一个
+        let content = fs::read_to_string(&path).unwrap().replacen("1.2 1.34", "1.3 1.34", 1);
+        fs::write(&path, content).unwrap();
+        let (snapshot, invalid) = load_latest(&dir).unwrap();
+        assert_eq!((Some(7), None), (snapshot.as_ref().map(|snapshot| snapshot.seq), snapshot.unwrap().reference_price));
+        assert_eq!(vec![path], invalid);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_snapshots_ahead_of_database() {
+        let dir = temp_dir("snapshot-persisted");
+        write(&dir, 7, &OrderBookPair::new(), 3, 4, None, &OrderGroups::new(), &PeggedOrders::new()).unwrap();
+        write(&dir, 42, &create_order_book_pair(), 5, 9, None, &OrderGroups::new(), &PeggedOrders::new()).unwrap();
+
+        assert_eq!(42, load_persisted(&dir, 5).unwrap().0.unwrap().seq);
+        assert_eq!(7, load_persisted(&dir, 4).unwrap().0.unwrap().seq);
+        assert_eq!((None, Vec::new()), load_persisted(&dir, 2).map(|(snapshot, invalid)| (snapshot.map(|snapshot| snapshot.seq), invalid)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn can_decode_v1() {
+        let body = "matching-snapshot 1\nseq 3\nsell 1\n4 sell 0.3 1.345\nbuy 0\n";
+        let content = format!("{}crc {:08x}\n", body, crc32fast::hash(body.as_bytes()));
+
+        let snapshot = Snapshot::decode(&content).unwrap();
+        assert_eq!((3, 0, 4), (snapshot.seq, snapshot.last_trade_id, snapshot.last_order_id));
+        assert_eq!(&LimitOrder::new(4, Side::Sell, 0.3, 1.345), snapshot.order_book_pair.sell_order_book.top().unwrap());
+    }
+
+    #[test]
+    fn keeps_journal_needed_by_oldest_snapshot() {
+        let journal_dir = temp_dir("snapshot-journal");
+        let snapshot_dir = temp_dir("snapshot-dir");
+        let snapshots = Snapshots::new(&snapshot_dir, 2);
+        let order_book_pair = create_order_book_pair();
+
+        let mut journal = Journal::open(&journal_dir).unwrap();
+        for id in 1..=6 {
+            let seq = journal.append(&Command::Submit(LimitOrder::new(id, Side::Buy, 1.0, 2.0))).unwrap();
+            if snapshots.is_due(seq) {
+                snapshots.take(seq, &order_book_pair, 0, 0, None, &OrderGroups::new(), &PeggedOrders::new(), &mut journal).unwrap();
+            }
+        }
+
+        // 保留序号4和6的快照，日志从5开始
+        assert_eq!(2, fs::read_dir(&snapshot_dir).unwrap().count());
+        assert!(journal::read_from(&journal_dir, 3).is_err());
+        assert_eq!(2, journal::read_from(&journal_dir, 4).unwrap().len());
+        assert_eq!(0, journal::read_from(&journal_dir, 6).unwrap().len());
+        fs::remove_dir_all(&journal_dir).unwrap();
+        fs::remove_dir_all(&snapshot_dir).unwrap();
+    }
+}
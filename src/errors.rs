@@ -1,5 +1,6 @@
 use std::error::Error;
 use std::fmt;
+use bigdecimal::BigDecimal;
 
 #[derive(Debug)]
 pub struct TinyError {
@@ -23,3 +24,60 @@ impl Error for TinyError {
         &self.details
     }
 }
+
+// 提交订单失败的原因，决定了消息应该被重新入队还是进入死信队列
+#[derive(Debug)]
+pub enum SubmitError {
+    // 订单本身不合法，重试也不会成功
+    Rejected(String),
+    // 订单没有写入数据库，也没有进入撮合引擎，可以安全重试
+    NotPersisted(Box<dyn Error>),
+    // 订单已进入撮合引擎，但成交没有全部写入数据库，不能重试；OCO是两条腿的id
+    PartiallyPersisted(Vec<u64>, Box<dyn Error>),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::Rejected(reason) => write!(f, "order rejected: {}", reason),
+            SubmitError::NotPersisted(err) => write!(f, "order not persisted: {}", err),
+            SubmitError::PartiallyPersisted(ids, err) => {
+                let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(",");
+                write!(f, "order {} matched but not fully persisted: {}", ids, err)
+            },
+        }
+    }
+}
+
+impl Error for SubmitError {}
+
+// 可用余额不足以冻结下单需要的资金，订单直接拒绝
+#[derive(Debug)]
+pub struct InsufficientBalance {
+    pub user_id: String,
+    pub asset: String,
+    pub amount: BigDecimal,
+}
+
+impl fmt::Display for InsufficientBalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} has not enough {} to freeze {}", self.user_id, self.asset, self.amount)
+    }
+}
+
+impl Error for InsufficientBalance {}
+
+// 一起创建的订单（OCO的两条腿）只有一部分已经存在，client_order_id和别的订单重复，整组拒绝
+#[derive(Debug)]
+pub struct PartiallyExists {
+    pub user_id: String,
+    pub client_order_ids: Vec<String>,
+}
+
+impl fmt::Display for PartiallyExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "only some of the orders {} of {} already exist", self.client_order_ids.join(","), self.user_id)
+    }
+}
+
+impl Error for PartiallyExists {}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use bigdecimal::BigDecimal;
+
+use crate::accounts::decimal;
+use crate::engine::Side;
+use crate::engine::TradeEvent;
+use crate::storage::DECIMALS;
+
+// 手续费率，按成交额的比例收取；maker可以是负数，表示返佣
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeeRate {
+    pub maker: BigDecimal,
+    pub taker: BigDecimal,
+}
+
+impl FeeRate {
+    pub fn new(maker: f64, taker: f64) -> FeeRate {
+        FeeRate {
+            maker: decimal(maker),
+            taker: decimal(taker),
+        }
+    }
+
+    pub fn zero() -> FeeRate {
+        FeeRate::new(0.0, 0.0)
+    }
+}
+
+// 一笔成交双方的手续费，各自从收到的资产里扣: 卖方收到quote，按quote收；买方收到base，按base收
+// 负数是返给用户的佣金
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fees {
+    pub ask: BigDecimal,
+    pub bid: BigDecimal,
+}
+
+// 一个市场的费率表: 默认费率，加上按用户等级的费率
+// 手续费记到account这个账户，返佣也从这个账户付出
+#[derive(Debug, Clone)]
+pub struct FeeSchedule {
+    pub account: String,
+    default: FeeRate,
+    tiers: HashMap<String, FeeRate>,
+    user_tiers: HashMap<String, String>,
+}
+
+impl FeeSchedule {
+    pub fn new(account: &str, default: FeeRate) -> FeeSchedule {
+        FeeSchedule {
+            account: account.to_string(),
+            default: default,
+            tiers: HashMap::new(),
+            user_tiers: HashMap::new(),
+        }
+    }
+
+    pub fn add_tier(&mut self, tier: &str, rate: FeeRate) {
+        self.tiers.insert(tier.to_string(), rate);
+    }
+
+    pub fn set_user_tier(&mut self, user_id: &str, tier: &str) {
+        self.user_tiers.insert(user_id.to_string(), tier.to_string());
+    }
+
+    // 用户没有等级或者等级没有配置费率时用默认费率
+    pub fn rate(&self, user_id: &str) -> &FeeRate {
+        self.user_tiers.get(user_id)
+            .and_then(|tier| self.tiers.get(tier))
+            .unwrap_or(&self.default)
+    }
+
+    // 截断到数据库的精度，扣给用户的和记到手续费账户的是同一个数，余额总量不变
+    pub fn fees(&self, trade: &TradeEvent, ask_user_id: &str, bid_user_id: &str) -> Fees {
+        let (ask_rate, bid_rate) = match trade.taker_side {
+            Side::Sell => (&self.rate(ask_user_id).taker, &self.rate(bid_user_id).maker),
+            Side::Buy => (&self.rate(ask_user_id).maker, &self.rate(bid_user_id).taker),
+        };
+        let volume = decimal(trade.volume);
+        let funds = decimal(trade.price) * volume.clone();
+        Fees {
+            ask: truncate(funds * ask_rate.clone()),
+            bid: truncate(volume * bid_rate.clone()),
+        }
+    }
+}
+
+fn truncate(amount: BigDecimal) -> BigDecimal {
+    amount.with_scale(DECIMALS as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FeeRate, FeeSchedule, Fees};
+    use crate::accounts::decimal;
+    use crate::engine::Side;
+    use crate::engine::TradeEvent;
+
+    fn trade(taker_side: Side) -> TradeEvent {
+        TradeEvent {
+            id: 1,
+            price: 0.03,
+            volume: 2.0,
+            funds: 0.06,
+            ask_order_id: 1,
+            ask_order_filled: true,
+            bid_order_id: 2,
+            bid_order_filled: true,
+            taker_side: taker_side,
+            maker_order_id: if taker_side == Side::Sell { 2 } else { 1 },
+            taker_order_id: if taker_side == Side::Sell { 1 } else { 2 },
+            ask_owner: "u1".to_string(),
+            bid_owner: "u2".to_string(),
+        }
+    }
+
+    #[test]
+    fn charges_taker_and_rebates_maker() {
+        let mut schedule = FeeSchedule::new("fees", FeeRate::new(0.001, 0.002));
+        schedule.add_tier("vip", FeeRate::new(-0.0001, 0.0015));
+        schedule.set_user_tier("u2", "vip");
+        schedule.set_user_tier("u3", "unknown");
+
+        // 卖方是taker，按quote收0.06 * 0.002；买方是vip maker，按base返2 * 0.0001
+        assert_eq!(Fees { ask: decimal(0.00012), bid: decimal(-0.0002) }, schedule.fees(&trade(Side::Sell), "u1", "u2"));
+        assert_eq!(Fees { ask: decimal(0.00006), bid: decimal(0.003) }, schedule.fees(&trade(Side::Buy), "u1", "u2"));
+        assert_eq!(&FeeRate::new(0.001, 0.002), schedule.rate("u3"));
+    }
+
+    #[test]
+    fn truncates_to_schema_precision() {
+        let schedule = FeeSchedule::new("fees", FeeRate::new(0.0, 0.0000003));
+        let mut trade = trade(Side::Sell);
+        trade.price = 0.00000001;
+        trade.volume = 0.33333333;
+        // 0.0000000033333333 * 0.0000003 = 0.00000000000000099999999
+        assert_eq!(decimal(0.0000000000000009), schedule.fees(&trade, "u1", "u2").ask);
+    }
+}
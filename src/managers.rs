@@ -1,51 +1,142 @@
+use std::collections::HashSet;
 use std::error::Error;
-use mysql::Pool;
+use std::fmt;
+use std::path::Path;
 use bigdecimal::BigDecimal;
 use bigdecimal::FromPrimitive;
 use bigdecimal::ToPrimitive;
 
+use crate::accounts;
+use crate::market::Market;
+use crate::storage;
+use crate::storage::Storage;
+use crate::storage::OrderQuery;
 use crate::models::Order;
+use crate::models::WAIT;
+use crate::models::DONE;
 use crate::engine::Side;
 use crate::engine::LimitOrder;
+use crate::engine::Condition;
+use crate::engine::Peg;
+use crate::engine::PegReference;
 use crate::engine::Engine;
+use crate::engine::OrderBookPair;
 use crate::engine::TradeEvent;
+use crate::engine::CancelFilter;
+use crate::engine::MassCancelEvent;
+use crate::engine::ReduceEvent;
+use crate::engine::MarketState;
+use crate::engine::MarketStateEvent;
+use crate::engine::Uncross;
+use crate::engine::Command;
+use crate::engine::Journal;
+use crate::engine::journal;
+use crate::engine::snapshot;
+use crate::engine::Snapshots;
 
+use crate::sessions::Clock;
+use crate::sessions::Session;
+use crate::sessions::Sessions;
+use crate::sessions::SystemClock;
+
+use crate::errors::SubmitError;
 use crate::errors::TinyError;
+use crate::errors::InsufficientBalance;
+use crate::errors::PartiallyExists;
+
+// 括号单的入场单已经全部成交，但余额不足以冻结出场单，两条出场单都没有创建
+#[derive(Debug, Clone, PartialEq)]
+pub struct BracketCancelEvent {
+    pub entry_id: u64,
+    pub created_by: String,
+    pub reason: String,
+}
+
+impl fmt::Display for BracketCancelEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bracket_cancel entry={} owner={} reason={}", self.entry_id, self.created_by, self.reason)
+    }
+}
 
 // 每个成员的生命周期小于等于'a
 pub struct OrderManager<'a> 
 {
-    pool: &'a Pool,
+    storage: &'a dyn Storage,
     engine: Engine<'a>,
+    // 恢复时补发崩溃前没有写入数据库的撤单和减量
+    on_cancel: &'a dyn Fn(u64) -> Result<(), Box<dyn Error>>,
+    on_reduce: Option<&'a dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>>,
+    on_bracket_cancel: Option<&'a dyn Fn(BracketCancelEvent) -> Result<(), Box<dyn Error>>>,
+    on_snapshot_error: Option<&'a dyn Fn(Box<dyn Error>)>,
 
-    volume_decimals: u32,
-    price_decimals: u32,
+    market: Market,
+    sessions: Sessions<'a>,
+    clock: &'a dyn Clock,
+    // 价格带触发的暂停交易（以及之后的集合竞价）到这个时间（毫秒）进入下一个阶段
+    resume_at: Option<i64>,
+    // 批量撮合模式下一次清算的时间（毫秒）
+    next_batch_at: Option<i64>,
 }
 
+static SYSTEM_CLOCK: SystemClock = SystemClock;
+
 impl<'a> OrderManager<'a> {
-    // 返回的对象的成员的生命周期小于等于'b, 'b的实际值是pool和on_trade两者生命周期的小值
-    pub fn new<'b>(pool: &'b Pool, volume_decimals: u32, price_decimals: u32, on_trade: &'b dyn Fn(TradeEvent), on_cancel: &'b dyn Fn(u64)) -> OrderManager<'b>
+    // 返回的对象的成员的生命周期小于等于'b, 'b的实际值是storage和on_trade两者生命周期的小值
+    pub fn new<'b>(storage: &'b dyn Storage, market: Market, on_trade: &'b dyn Fn(TradeEvent) -> Result<(), Box<dyn Error>>, on_cancel: &'b dyn Fn(u64) -> Result<(), Box<dyn Error>>) -> OrderManager<'b>
     {
-        let engine = Engine::new(on_trade, on_cancel);
+        // 成交额 price * volume 的小数位数是两者之和，不能超过数据库的精度
+        assert!(market.price_decimals + market.volume_decimals <= storage::DECIMALS, "market decimals exceed the schema precision");
+        let mut engine = Engine::new(on_trade, on_cancel);
+        if let Some(price_band) = &market.price_band {
+            let mut price_band = price_band.clone();
+            price_band.price_decimals = market.price_decimals;
+            engine.set_price_band(price_band);
+        }
+        engine.set_batch_mode(market.batch_interval > 0);
+        engine.set_matching_policy(market.matching_policy.clone());
 
         OrderManager {
             engine: engine,
-            pool: pool,
-            volume_decimals: volume_decimals,
-            price_decimals: price_decimals,
+            storage: storage,
+            on_cancel: on_cancel,
+            on_reduce: None,
+            on_bracket_cancel: None,
+            on_snapshot_error: None,
+            market: market,
+            sessions: Sessions::new(&SYSTEM_CLOCK),
+            clock: &SYSTEM_CLOCK,
+            resume_at: None,
+            next_batch_at: None,
         }
     }
 
-    pub fn submit(&mut self, price: f64, volume: f64, side: u8, created_by: &str) -> Result<u64, Box<Error>>{
+    // 返回Ok时，订单已经创建并冻结了资金，它产生的成交交给on_trade持久化
+    // 同一个用户重复提交同一个client_order_id（比如消息被重新投递）时，直接返回原订单的id，不会再次进入撮合引擎
+    // 可用余额不足时拒绝
+    pub fn submit(&mut self, client_order_id: &str, price: f64, volume: f64, side: u8, created_by: &str) -> Result<u64, SubmitError> {
+        self.submit_with_condition(client_order_id, price, volume, side, created_by, Condition::None)
+    }
+
+    // 带成交条件（最小成交量、全部成交）的订单，成交条件和订单一起写数据库
+    pub fn submit_with_condition(&mut self, client_order_id: &str, price: f64, volume: f64, side: u8, created_by: &str, condition: Condition) -> Result<u64, SubmitError> {
         // price 采用四舍五入
-        let price = OrderManager::round(price, self.price_decimals);
+        let price = OrderManager::round(price, self.market.price_decimals);
         // volume 采用截断
-        let volume = OrderManager::floor(volume, self.volume_decimals);
-        println!("{}", volume);
+        let volume = OrderManager::floor(volume, self.market.volume_decimals);
 
         if price != 0.0 && volume != 0.0 {
-            // 创建订单
-            let id: u64 = Order::create(self.pool, price, volume, side, created_by);
+            // 先按市场状态检查，不接受的订单不写数据库
+            let order_side: Side = if side == 0 { Side::Sell } else { Side::Buy };
+            self.engine.check(&Command::Submit(LimitOrder::new(0, order_side, volume, price)))
+                .map_err(|err| SubmitError::Rejected(err.to_string()))?;
+
+            // 创建订单，冻结资金
+            let freeze = accounts::freeze(&self.market, created_by, side, price, volume);
+            let (id, created) = self.storage.create_order(client_order_id, &self.market.name, price, volume, side, &condition, None, None, created_by, &freeze)
+                .map_err(OrderManager::create_error)?;
+            if !created {
+                return Ok(id);
+            }
 
             // 入撮合引擎
             let side: Side = if side == 0 { Side::Sell } else { Side::Buy };
@@ -54,16 +145,135 @@ impl<'a> OrderManager<'a> {
                 side,
                 volume,
                 price,
-            );
-            &(self.engine).submit(limit_order);
+            ).with_owner(created_by).with_condition(condition);
+            let state = self.engine.state();
+            let result = self.engine.submit(limit_order);
+            self.schedule_resume(state);
+            result.map_err(|err| SubmitError::PartiallyPersisted(vec![id], err))?;
             Ok(id)
         } else {
-            Err(Box::new(TinyError::new("")))
+            Err(SubmitError::Rejected(format!("price {} or volume {} rounds to zero", price, volume)))
+        }
+    }
+
+    // 挂钩订单: 必须有限价，数据库里的价格和冻结都按限价，还没有参考价时也按限价挂单
+    pub fn submit_pegged(&mut self, client_order_id: &str, volume: f64, side: u8, created_by: &str, mut peg: Peg) -> Result<u64, SubmitError> {
+        let limit = match peg.limit {
+            Some(limit) => limit,
+            None => return Err(SubmitError::Rejected("pegged order without limit price".to_string())),
+        };
+        let order = self.prepare(limit, volume, side)?;
+        peg.limit = Some(order.price);
+        peg.offset = OrderManager::round(peg.offset, self.market.price_decimals);
+        peg.price_decimals = self.market.price_decimals;
+        self.engine.check(&Command::SubmitPeg(order.clone(), peg.clone()))
+            .map_err(|err| SubmitError::Rejected(err.to_string()))?;
+
+        let (id, created) = self.create(client_order_id, &order, Some(&peg), None, created_by)?;
+        if !created {
+            return Ok(id);
+        }
+
+        let state = self.engine.state();
+        let result = self.engine.submit_pegged(LimitOrder { id: id, ..order }.with_owner(created_by), peg);
+        self.schedule_resume(state);
+        result.map_err(|err| SubmitError::PartiallyPersisted(vec![id], err))?;
+        Ok(id)
+    }
+
+    // OCO: 两条腿在一个事务里创建订单并冻结资金，一条腿有成交或被撤销时另一条腿自动撤销（退回冻结）
+    // legs是(client_order_id, price, volume, side)，两条腿都按可用余额冻结，余额要够同时冻结两条腿
+    // 重复投递时两条腿都已经存在；只有一条腿已经存在（client_order_id和别的订单重复）时拒绝
+    // 两条腿互相记在orders.oco_id里，从数据库恢复时还原OCO关系
+    pub fn submit_oco(&mut self, legs: [(&str, f64, f64, u8); 2], created_by: &str) -> Result<(u64, u64), SubmitError> {
+        let first = self.prepare(legs[0].1, legs[0].2, legs[0].3)?;
+        let second = self.prepare(legs[1].1, legs[1].2, legs[1].3)?;
+        self.engine.check(&Command::SubmitOco(first.clone(), second.clone()))
+            .map_err(|err| SubmitError::Rejected(err.to_string()))?;
+
+        let ((first_id, second_id), created) = self.create_oco([legs[0].0, legs[1].0], &first, &second, created_by)?;
+        if !created {
+            return Ok((first_id, second_id));
+        }
+
+        let state = self.engine.state();
+        let result = self.engine.submit_oco(LimitOrder { id: first_id, ..first }.with_owner(created_by), LimitOrder { id: second_id, ..second }.with_owner(created_by));
+        self.schedule_resume(state);
+        result.map_err(|err| SubmitError::PartiallyPersisted(vec![first_id, second_id], err))?;
+        Ok((first_id, second_id))
+    }
+
+    // 括号单: 先只创建入场单，入场单全部成交之后place_activated挂出两条出场单（OCO）
+    // exits是(price, volume, side)，出场单在挂出时才冻结资金，余额不足时出场单不挂出
+    // 还没挂出的出场单和入场单一起写数据库（bracket_exits），从数据库恢复时还原
+    pub fn submit_bracket(&mut self, client_order_id: &str, entry: (f64, f64, u8), exits: [(f64, f64, u8); 2], created_by: &str) -> Result<u64, SubmitError> {
+        let entry = self.prepare(entry.0, entry.1, entry.2)?;
+        let first = self.prepare(exits[0].0, exits[0].1, exits[0].2)?.with_owner(created_by);
+        let second = self.prepare(exits[1].0, exits[1].1, exits[1].2)?.with_owner(created_by);
+        self.engine.check(&Command::SubmitBracket(entry.clone(), first.clone(), second.clone()))
+            .map_err(|err| SubmitError::Rejected(err.to_string()))?;
+
+        let exits = [OrderManager::exit(&first), OrderManager::exit(&second)];
+        let (id, created) = self.create(client_order_id, &entry, None, Some(&exits), created_by)?;
+        if !created {
+            return Ok(id);
+        }
+
+        let state = self.engine.state();
+        let result = self.engine.submit_bracket(LimitOrder { id: id, ..entry }.with_owner(created_by), (first, second));
+        self.schedule_resume(state);
+        result.map_err(|err| SubmitError::PartiallyPersisted(vec![id], err))?;
+        Ok(id)
+    }
+
+    // 挂出入场单已经全部成交的括号单的出场单，返回挂出的组数
+    // 入场单的成交要先写数据库，需要在persister.flush之后调用
+    // 出场单的client_order_id由入场单的id确定，重复调用不会重复创建
+    pub fn place_activated(&mut self) -> Result<usize, Box<dyn Error>> {
+        let mut placed = 0;
+        for (entry_id, first, second) in self.engine.take_activated() {
+            if self.place_exits(entry_id, first, second)? {
+                placed += 1;
+            }
+        }
+        Ok(placed)
+    }
+
+    // 出场单因为余额不足放弃时通知调用者，不设置时放弃出场单会返回错误
+    pub fn set_on_bracket_cancel(&mut self, on_bracket_cancel: &'a dyn Fn(BracketCancelEvent) -> Result<(), Box<dyn Error>>) {
+        self.on_bracket_cancel = Some(on_bracket_cancel);
+    }
+
+    // 两条出场单在一个事务里创建并冻结，余额不足时放弃并交给on_bracket_cancel；挂出或者放弃之后删除数据库里的出场单
+    fn place_exits(&mut self, entry_id: u64, first: LimitOrder, second: LimitOrder) -> Result<bool, Box<dyn Error>> {
+        let created_by = first.owner.clone();
+        let client_order_ids = [format!("bracket-{}-1", entry_id), format!("bracket-{}-2", entry_id)];
+        let ((first_id, second_id), created) = match self.create_oco([&client_order_ids[0], &client_order_ids[1]], &first, &second, &created_by) {
+            Ok(result) => result,
+            Err(SubmitError::Rejected(reason)) => {
+                let event = BracketCancelEvent { entry_id: entry_id, created_by: created_by, reason: reason };
+                match self.on_bracket_cancel {
+                    Some(on_bracket_cancel) => on_bracket_cancel(event)?,
+                    None => return Err(Box::new(TinyError::new(&format!("bracket {} exits were discarded but on_bracket_cancel is not set", entry_id)))),
+                }
+                self.storage.delete_bracket_exits(entry_id)?;
+                return Ok(false);
+            },
+            Err(err) => return Err(Box::new(err)),
+        };
+        self.storage.delete_bracket_exits(entry_id)?;
+        if !created {
+            return Ok(false);
         }
+        let state = self.engine.state();
+        let result = self.engine.submit_oco(LimitOrder { id: first_id, ..first }, LimitOrder { id: second_id, ..second });
+        self.schedule_resume(state);
+        result?;
+        Ok(true)
     }
 
-    pub fn cancel(&mut self, id: u64, price: f64, volume: f64, side: u8, created_by: &str) {
-        let price = OrderManager::round(price, self.price_decimals);
+    pub fn cancel(&mut self, id: u64, price: f64, volume: f64, side: u8, created_by: &str) -> Result<(), Box<dyn Error>> {
+        let price = OrderManager::round(price, self.market.price_decimals);
 
         if price != 0.0 {
             let side: Side = if side == 0 { Side::Sell } else { Side::Buy };
@@ -72,9 +282,438 @@ impl<'a> OrderManager<'a> {
                 side,
                 volume,
                 price,
-            );
-            &(self.engine).cancel(limit_order);
+            ).with_owner(created_by);
+            self.engine.cancel(limit_order)
+        } else {
+            Ok(())
+        }
+    }
+
+    // 撤掉这个市场所有符合条件的挂单（按用户、方向、价格范围），返回撤掉的订单数，包括跟着撤掉的OCO的另一条腿
+    // 撤单事件逐个交给on_cancel，调用者应该用Persister::begin/commit把它们放进同一个事务
+    pub fn mass_cancel(&mut self, filter: CancelFilter) -> Result<usize, Box<dyn Error>> {
+        Ok(self.engine.mass_cancel(filter)?.order_ids.len())
+    }
+
+    pub fn set_on_mass_cancel(&mut self, on_mass_cancel: &'a dyn Fn(MassCancelEvent) -> Result<(), Box<dyn Error>>) {
+        self.engine.set_on_mass_cancel(on_mass_cancel);
+    }
+
+    // 改单: 只能减少挂单的剩余数量，价格和时间优先不变；改价或者加量要撤单重下
+    // 新数量为0等同于撤单
+    pub fn amend(&mut self, id: u64, volume: f64, created_by: &str) -> Result<(), Box<dyn Error>> {
+        let volume = OrderManager::floor(volume, self.market.volume_decimals);
+        let order = match self.engine.order_book_pair.find(id) {
+            Some(order) if order.owner == created_by => order.clone(),
+            _ => return Err(Box::new(TinyError::new(&format!("order {} of {} is not open", id, created_by)))),
+        };
+        if volume >= order.volume {
+            return Err(Box::new(TinyError::new(&format!("amend can only reduce order {} below {}", id, order.volume))));
         }
+        let price = order.price;
+        self.engine.amend(order, price, volume)
+    }
+
+    // 改单减少的数量交给on_reduce写数据库（扣减订单的剩余数量，退回冻结），要在open_journal之前设置
+    pub fn set_on_reduce(&mut self, on_reduce: &'a dyn Fn(ReduceEvent) -> Result<(), Box<dyn Error>>) {
+        self.engine.set_on_reduce(on_reduce);
+        self.on_reduce = Some(on_reduce);
+    }
+
+    // 替换会话超时和恢复交易用的时钟，用于测试
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.sessions.set_clock(clock);
+        self.clock = clock;
+    }
+
+    // 做市商的连接建立或者重连，timeout毫秒内没有心跳时撤掉这个会话的挂单
+    // 会话id已经属于别的用户时返回false，不能用别人的会话下单
+    pub fn open_session(&mut self, session_id: &str, created_by: &str, timeout: i64) -> bool {
+        self.sessions.open(session_id, created_by, timeout)
+    }
+
+    // 会话已经超时时返回false，调用者应该重新open_session
+    pub fn heartbeat(&mut self, session_id: &str) -> bool {
+        let order_book_pair = &self.engine.order_book_pair;
+        self.sessions.retain_orders(session_id, |order_id| order_book_pair.find(order_id).is_some());
+        self.sessions.heartbeat(session_id)
+    }
+
+    // 在会话里下单，订单属于会话的用户；会话不存在或者已经超时时拒绝
+    pub fn submit_in_session(&mut self, session_id: &str, client_order_id: &str, price: f64, volume: f64, side: u8) -> Result<u64, SubmitError> {
+        let owner = match self.sessions.get(session_id) {
+            Some(session) => session.owner.clone(),
+            None => return Err(SubmitError::Rejected(format!("session {} is not open", session_id))),
+        };
+        let result = self.submit(client_order_id, price, volume, side, &owner);
+        match &result {
+            Ok(id) => {
+                self.sessions.track(session_id, *id);
+            },
+            Err(SubmitError::PartiallyPersisted(ids, _)) => {
+                for id in ids {
+                    self.sessions.track(session_id, *id);
+                }
+            },
+            Err(_) => ()
+        }
+        result
+    }
+
+    // 登出时撤掉会话的挂单，返回撤掉的订单数
+    pub fn logout(&mut self, session_id: &str) -> Result<usize, Box<dyn Error>> {
+        match self.sessions.close(session_id) {
+            Some(session) => self.cancel_session(session),
+            None => Ok(0)
+        }
+    }
+
+    // 撤掉所有心跳超时的会话的挂单，需要定时调用，返回超时的会话: (会话id, 用户, 撤掉的订单数)
+    // 暂停交易时不能撤单，会话等恢复之后再处理
+    pub fn expire_sessions(&mut self) -> Result<Vec<(String, String, usize)>, Box<dyn Error>> {
+        if self.engine.state() == MarketState::Halted {
+            return Ok(Vec::new());
+        }
+        let mut expired = Vec::new();
+        for session in self.sessions.expire() {
+            let (session_id, owner) = (session.id.clone(), session.owner.clone());
+            let count = self.cancel_session(session)?;
+            expired.push((session_id, owner, count));
+        }
+        Ok(expired)
+    }
+
+    fn cancel_session(&mut self, session: Session) -> Result<usize, Box<dyn Error>> {
+        let order_book_pair = &self.engine.order_book_pair;
+        let order_ids = session.order_ids.into_iter()
+            .filter(|order_id| order_book_pair.find(*order_id).is_some())
+            .collect::<Vec<u64>>();
+        // 没有挂单时不写日志
+        if order_ids.is_empty() {
+            return Ok(0);
+        }
+        self.mass_cancel(CancelFilter::all().with_owner(&session.owner).with_order_ids(order_ids))
+    }
+
+    // 管理命令: 先写数据库再切换引擎的状态，重启后按数据库里的状态恢复
+    // 管理员切换状态之后不再自动恢复交易
+    pub fn set_state(&mut self, state: MarketState) -> Result<(), Box<dyn Error>> {
+        self.storage.set_market_state(&self.market.name, state)?;
+        self.resume_at = None;
+        self.engine.set_state(state)
+    }
+
+    // 管理命令: 结束集合竞价（比如开盘），成交之后回到连续撮合
+    pub fn uncross(&mut self) -> Result<(), Box<dyn Error>> {
+        self.storage.set_market_state(&self.market.name, MarketState::Continuous)?;
+        self.resume_at = None;
+        self.engine.uncross()
+    }
+
+    pub fn set_on_indicative(&mut self, on_indicative: &'a dyn Fn(Option<Uncross>) -> Result<(), Box<dyn Error>>) {
+        self.engine.set_on_indicative(on_indicative);
+    }
+
+    pub fn state(&self) -> MarketState {
+        self.engine.state()
+    }
+
+    pub fn set_on_state_change(&mut self, on_state_change: &'a dyn Fn(MarketStateEvent) -> Result<(), Box<dyn Error>>) {
+        self.engine.set_on_state_change(on_state_change);
+    }
+
+    // 快照写入失败，以及打开日志时跳过了损坏的快照
+    pub fn set_on_snapshot_error(&mut self, on_snapshot_error: &'a dyn Fn(Box<dyn Error>)) {
+        self.on_snapshot_error = Some(on_snapshot_error);
+        self.engine.set_on_snapshot_error(on_snapshot_error);
+    }
+
+    // 外部推送的指数价格，作为价格带的参考价
+    pub fn set_reference_price(&mut self, price: f64) -> Result<(), Box<dyn Error>> {
+        self.engine.set_reference_price(price)
+    }
+
+    // 价格带触发的暂停交易到期后进入集合竞价（没有配置时直接恢复连续撮合），集合竞价到期后uncross
+    // 需要定时调用，返回是否切换了状态
+    pub fn resume_if_due(&mut self) -> Result<bool, Box<dyn Error>> {
+        let now = self.clock.now();
+        match (self.resume_at, self.engine.state()) {
+            (Some(at), MarketState::Halted) if now >= at && self.market.auction_seconds > 0 => {
+                self.resume_at = Some(now + self.market.auction_seconds * 1000);
+                self.engine.set_state(MarketState::Auction)?;
+                Ok(true)
+            },
+            (Some(at), MarketState::Halted) if now >= at => {
+                self.resume_at = None;
+                self.engine.set_state(MarketState::Continuous)?;
+                Ok(true)
+            },
+            (Some(at), MarketState::Auction) if now >= at => {
+                self.resume_at = None;
+                self.engine.uncross()?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    // 价格带触发的暂停交易（或者之后的集合竞价）结束的时间（毫秒），没有自动恢复时是None
+    pub fn resume_at(&self) -> Option<i64> {
+        self.resume_at
+    }
+
+    // 批量撮合模式下到时间就清算这一批，需要在处理每条消息之前调用，返回是否清算了
+    // 暂停交易和集合竞价期间不清算，恢复之后接着按间隔清算
+    pub fn clear_batch_if_due(&mut self) -> Result<bool, Box<dyn Error>> {
+        if self.market.batch_interval <= 0 {
+            return Ok(false);
+        }
+        let now = self.clock.now();
+        match self.next_batch_at {
+            Some(at) if now >= at => {
+                self.next_batch_at = Some(now + self.market.batch_interval);
+                match self.engine.state() {
+                    MarketState::Continuous | MarketState::PostOnly => {
+                        self.engine.clear_batch()?;
+                        Ok(true)
+                    },
+                    _ => Ok(false)
+                }
+            },
+            Some(_) => Ok(false),
+            None => {
+                self.next_batch_at = Some(now + self.market.batch_interval);
+                Ok(false)
+            }
+        }
+    }
+
+    // 下单触发了暂停交易时，记下自动恢复的时间；这个暂停不写数据库
+    fn schedule_resume(&mut self, before: MarketState) {
+        if before != MarketState::Halted && self.engine.state() == MarketState::Halted {
+            let halt_seconds = self.market.price_band.as_ref().map_or(0, |price_band| price_band.halt_seconds);
+            self.resume_at = Some(self.clock.now() + halt_seconds * 1000);
+        }
+    }
+
+    // 快照里没有市场状态，恢复订单簿之后按数据库里的状态设置
+    // 价格带触发的暂停没有写数据库，重启后直接恢复交易
+    fn restore_state(&mut self) -> Result<(), Box<dyn Error>> {
+        let state = self.storage.market_state(&self.market.name)?.unwrap_or(MarketState::Continuous);
+        if state != self.engine.state() {
+            self.engine.set_state(state)?;
+        }
+        Ok(())
+    }
+
+    // 打开命令日志和快照并恢复订单簿，返回重放的命令数或从数据库恢复的订单数
+    // 从成交都已经写入数据库的最新快照开始重放之后的日志，崩溃前还没有写入数据库的成交和撤单重新交给on_trade/on_cancel，
+    // 写了数据库、还没写日志的订单重新提交；快照和日志都没有时从数据库恢复，恢复出的挂单也会写入日志
+    // 补发的成交和撤单写入数据库之后才能核对，调用者要在persister.flush之后调用verify
+    pub fn open_journal(&mut self, journal_dir: &Path, snapshot_dir: &Path, snapshot_interval: u64) -> Result<usize, Box<dyn Error>> {
+        let persisted_trade_id = self.storage.last_trade_id(&self.market.name)?;
+        let (snapshot, invalid) = snapshot::load_persisted(snapshot_dir, persisted_trade_id)?;
+        if let Some(on_snapshot_error) = self.on_snapshot_error {
+            for path in invalid {
+                on_snapshot_error(Box::new(TinyError::new(&format!("skip invalid snapshot {}", path.display()))));
+            }
+        }
+        let after_seq = snapshot.as_ref().map_or(0, |snapshot| snapshot.seq);
+        let records = journal::read_from(journal_dir, after_seq)?;
+        self.engine.set_journal(Journal::open(journal_dir)?);
+        self.engine.set_snapshots(Snapshots::new(snapshot_dir, snapshot_interval));
+
+        match snapshot {
+            Some(snapshot) => self.engine.load(snapshot),
+            None if records.is_empty() => return self.recover(),
+            None => ()
+        }
+
+        // 快照之前的成交都已经写入（见load_persisted），数据库和快照的差别只能是没有写入的撤单和减量:
+        // 快照之前就结束了、数据库里还是挂单的订单补发撤单，数据库里剩余数量更多的挂单补发减量
+        let last_order_id = self.engine.last_order_id();
+        let volume_decimals = self.market.volume_decimals;
+        for order in self.storage.find_open_orders(&self.market.name)? {
+            match self.engine.order_book_pair.find(order.id) {
+                None if order.id <= last_order_id => (self.on_cancel)(order.id)?,
+                Some(open_order) if OrderManager::round(order.volume, volume_decimals) > OrderManager::round(open_order.volume, volume_decimals) => {
+                    let reduced = OrderManager::round(order.volume - open_order.volume, volume_decimals);
+                    let event = ReduceEvent { order_id: order.id, reduced: reduced, volume: open_order.volume };
+                    match self.on_reduce {
+                        Some(on_reduce) => on_reduce(event)?,
+                        None => return Err(Box::new(TinyError::new(&format!("order {} was reduced but on_reduce is not set", order.id)))),
+                    }
+                },
+                _ => ()
+            }
+        }
+
+        let count = records.len();
+        let storage = self.storage;
+        let unpersisted_cancel = |order_id: u64| -> Result<bool, Box<dyn Error>> {
+            Ok(storage.find_order(order_id)?.map_or(false, |order| order.state == WAIT as u16))
+        };
+        // 减量写入之后，数据库里的剩余数量不会超过减量之后的数量（之后的事件只会让它更少）；没写入时比减量之前的数量还多
+        let unpersisted_reduce = |event: &ReduceEvent| -> Result<bool, Box<dyn Error>> {
+            Ok(storage.find_order(event.order_id)?.map_or(false, |order| {
+                order.state == WAIT as u16 && OrderManager::round(order.volume, volume_decimals) > OrderManager::round(event.volume, volume_decimals)
+            }))
+        };
+        self.engine.recover(records, persisted_trade_id, &unpersisted_cancel, &unpersisted_reduce)?;
+        // 旧的日志里没有成交编号，不能让新成交的编号和数据库里已有的重复
+        if persisted_trade_id > self.engine.last_trade_id() {
+            self.engine.set_last_trade_id(persisted_trade_id)?;
+        }
+
+        // 写了数据库、还没写日志就崩溃的订单，重新投递的消息按client_order_id去重，不会再提交，在这里补交
+        // OCO的两条腿一起提交，括号单带上数据库里的出场单；现在的市场状态不接受时撤销，退回冻结
+        let last_order_id = self.engine.last_order_id();
+        let unjournaled = self.storage.find_open_orders(&self.market.name)?.into_iter()
+            .filter(|order| order.id > last_order_id)
+            .collect::<Vec<Order>>();
+        let brackets = self.storage.find_bracket_exits(&self.market.name)?;
+        for order in &unjournaled {
+            let partner = order.oco_id.and_then(|oco_id| unjournaled.iter().find(|other| other.id == oco_id));
+            let limit_order = OrderManager::limit_order(order)?;
+            let command = match (partner, self.peg(order)?, brackets.iter().find(|(entry_id, _exits)| *entry_id == order.id)) {
+                (Some(partner), _, _) if partner.id < order.id => continue,
+                (Some(partner), _, _) => Command::SubmitOco(limit_order, OrderManager::limit_order(partner)?),
+                (None, Some(peg), _) => Command::SubmitPeg(limit_order, peg),
+                (None, None, Some((_entry_id, exits))) => {
+                    let (first, second) = OrderManager::exit_orders(exits, &limit_order.owner);
+                    Command::SubmitBracket(limit_order, first, second)
+                },
+                (None, None, None) => Command::Submit(limit_order),
+            };
+            if self.engine.check(&command).is_err() {
+                (self.on_cancel)(order.id)?;
+                if let Some(partner) = partner {
+                    (self.on_cancel)(partner.id)?;
+                }
+                continue;
+            }
+            let state = self.engine.state();
+            let result = match command {
+                Command::SubmitOco(first, second) => self.engine.submit_oco(first, second),
+                Command::SubmitPeg(order, peg) => self.engine.submit_pegged(order, peg),
+                Command::SubmitBracket(entry, first, second) => self.engine.submit_bracket(entry, (first, second)),
+                _ => self.engine.submit(OrderManager::limit_order(order)?),
+            };
+            self.schedule_resume(state);
+            result?;
+        }
+        self.restore_brackets()?;
+        self.restore_state()?;
+        Ok(count)
+    }
+
+    // 重启后从数据库恢复订单簿: 按创建顺序把挂单放回引擎（不撮合）
+    // 挂钩订单的价格跟着普通挂单的最优价，等普通挂单都放回之后再放回
+    // OCO的两条腿都还在挂单时恢复OCO关系；另一条腿已经结束而自己没有成交的腿本该被撤销，不放回，交给on_cancel
+    // 成交编号接着数据库里最后一笔成交继续。返回恢复的订单数
+    // 补发的撤单写入数据库之后才能核对，调用者要在persister.flush之后调用verify
+    pub fn recover(&mut self) -> Result<usize, Box<dyn Error>> {
+        let last_trade_id = self.storage.last_trade_id(&self.market.name)?;
+        self.engine.set_last_trade_id(last_trade_id)?;
+        let orders = self.storage.find_open_orders(&self.market.name)?;
+        let open_ids = orders.iter().map(|order| order.id).collect::<HashSet<u64>>();
+        let mut pegged = Vec::new();
+        let mut links = Vec::new();
+        let mut count = 0;
+        for order in &orders {
+            match order.oco_id {
+                Some(oco_id) if open_ids.contains(&oco_id) => {
+                    if order.id < oco_id {
+                        links.push((order.id, oco_id));
+                    }
+                },
+                Some(_oco_id) if order.trades_count == 0 => {
+                    (self.on_cancel)(order.id)?;
+                    continue;
+                },
+                _ => ()
+            }
+            match self.peg(order)? {
+                Some(peg) => pegged.push((OrderManager::limit_order(order)?, peg)),
+                None => self.engine.restore(OrderManager::limit_order(order)?)?,
+            }
+            count += 1;
+        }
+        for (order, peg) in pegged {
+            self.engine.restore_pegged(order, peg)?;
+        }
+        for (first_id, second_id) in links {
+            self.engine.restore_oco(first_id, second_id)?;
+        }
+        self.restore_brackets()?;
+        self.restore_state()?;
+        Ok(count)
+    }
+
+    // 数据库里还没挂出的出场单: 入场单还在订单簿里时放回引擎，已经全部成交（成交已经写入）时挂出，已经撤销时删除
+    // 快照和日志里已经有的括号单不再放回
+    fn restore_brackets(&mut self) -> Result<(), Box<dyn Error>> {
+        let restored = self.engine.groups().brackets().iter().map(|(entry_id, _first, _second)| *entry_id).collect::<HashSet<u64>>();
+        for (entry_id, exits) in self.storage.find_bracket_exits(&self.market.name)? {
+            let entry = match self.storage.find_order(entry_id)? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let (first, second) = OrderManager::exit_orders(&exits, entry.created_by.as_ref().map_or("", |created_by| created_by.as_str()));
+            if entry.state == DONE as u16 {
+                self.place_exits(entry_id, first, second)?;
+            } else if entry.state != WAIT as u16 {
+                self.storage.delete_bracket_exits(entry_id)?;
+            } else if self.engine.order_book_pair.find(entry_id).is_some() && !restored.contains(&entry_id) {
+                self.engine.restore_bracket(entry_id, (first, second))?;
+            }
+        }
+        Ok(())
+    }
+
+    // 核对内存中的订单簿和数据库中挂单的订单数、剩余数量
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        let mut totals = vec![(Side::Sell, 0, 0.0), (Side::Buy, 0, 0.0)];
+        for (side, count, volume) in self.storage.open_totals(&self.market.name)? {
+            totals[side.min(1) as usize] = (if side == 0 { Side::Sell } else { Side::Buy }, count, volume);
+        }
+
+        for (side, count, volume) in totals {
+            let (book, _counter_book) = self.engine.order_book_pair.get_books(side);
+            let book_volume = OrderManager::round(book.total_volume(), self.market.volume_decimals);
+            let volume = OrderManager::round(volume, self.market.volume_decimals);
+            if book.orders_count() as u64 != count || book_volume != volume {
+                let message = format!(
+                    "{} {} book mismatch: {} orders / {} volume in memory, {} orders / {} volume in database",
+                    self.market.name, side, book.orders_count(), book_volume, count, volume
+                );
+                return Err(Box::new(TinyError::new(&message)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn order_book_pair(&self) -> &OrderBookPair {
+        &self.engine.order_book_pair
+    }
+
+    // 撮合引擎里的挂单，已经结束的订单查不到
+    pub fn open_order(&self, id: u64) -> Option<&LimitOrder> {
+        self.engine.order_book_pair.find(id)
+    }
+
+    pub fn open_orders(&self, created_by: &str) -> Vec<&LimitOrder> {
+        self.engine.order_book_pair.orders_by_owner(created_by)
+    }
+
+    // 数据库里的订单，包括已经成交和撤销的
+    pub fn find_order(&self, id: u64) -> Result<Option<Order>, Box<dyn Error>> {
+        self.storage.find_order(id)
+    }
+
+    // 只查这个市场的订单
+    pub fn find_orders(&self, query: OrderQuery) -> Result<Vec<Order>, Box<dyn Error>> {
+        self.storage.find_orders(&query.with_market(&self.market.name))
     }
 
     pub fn print_orderbook(&self) {
@@ -83,6 +722,93 @@ impl<'a> OrderManager<'a> {
         println!("{:?}", self.engine.order_book_pair.buy_order_book);
     }
 
+    // 按市场精度取整，id在写数据库之后才有
+    fn prepare(&self, price: f64, volume: f64, side: u8) -> Result<LimitOrder, SubmitError> {
+        let price = OrderManager::round(price, self.market.price_decimals);
+        let volume = OrderManager::floor(volume, self.market.volume_decimals);
+        if price == 0.0 || volume == 0.0 {
+            return Err(SubmitError::Rejected(format!("price {} or volume {} rounds to zero", price, volume)));
+        }
+        let side: Side = if side == 0 { Side::Sell } else { Side::Buy };
+        Ok(LimitOrder::new(0, side, volume, price))
+    }
+
+    // 创建订单并冻结资金，返回(id, 是否新建)
+    fn create(&self, client_order_id: &str, order: &LimitOrder, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str) -> Result<(u64, bool), SubmitError> {
+        let side = OrderManager::side_code(order.side);
+        let freeze = accounts::freeze(&self.market, created_by, side, order.price, order.volume);
+        self.storage.create_order(client_order_id, &self.market.name, order.price, order.volume, side, &order.condition, peg, exits, created_by, &freeze)
+            .map_err(OrderManager::create_error)
+    }
+
+    // 在一个事务里创建OCO的两条腿并冻结资金，返回((id, id), 是否新建)
+    fn create_oco(&self, client_order_ids: [&str; 2], first: &LimitOrder, second: &LimitOrder, created_by: &str) -> Result<((u64, u64), bool), SubmitError> {
+        let orders = [(client_order_ids[0], first), (client_order_ids[1], second)].iter()
+            .map(|(client_order_id, order)| {
+                let side = OrderManager::side_code(order.side);
+                (*client_order_id, order.price, order.volume, side, accounts::freeze(&self.market, created_by, side, order.price, order.volume))
+            })
+            .collect::<Vec<_>>();
+        let results = self.storage.create_orders(&self.market.name, created_by, &orders).map_err(OrderManager::create_error)?;
+        Ok(((results[0].0, results[1].0), results[0].1))
+    }
+
+    // 余额不足和client_order_id冲突重试也不会成功，其他错误（数据库连接等）可以重试
+    fn create_error(err: Box<dyn Error>) -> SubmitError {
+        if let Some(insufficient) = err.downcast_ref::<InsufficientBalance>() {
+            return SubmitError::Rejected(insufficient.to_string());
+        }
+        if let Some(exists) = err.downcast_ref::<PartiallyExists>() {
+            return SubmitError::Rejected(exists.to_string());
+        }
+        SubmitError::NotPersisted(err)
+    }
+
+    // 数据库里的订单在引擎里的样子，数量是剩余数量
+    fn limit_order(order: &Order) -> Result<LimitOrder, Box<dyn Error>> {
+        let side: Side = if order.side == 0 { Side::Sell } else { Side::Buy };
+        let condition = match &order.fill_condition {
+            Some(condition) => Condition::parse(condition)
+                .ok_or_else(|| TinyError::new(&format!("order {} has invalid fill condition {}", order.id, condition)))?,
+            None => Condition::None,
+        };
+        Ok(LimitOrder::new(order.id, side, order.volume, order.price)
+            .with_owner(order.created_by.as_ref().map_or("", |created_by| created_by.as_str()))
+            .with_condition(condition))
+    }
+
+    // 数据库里挂钩订单的设置，限价就是订单的价格；普通订单是None
+    fn peg(&self, order: &Order) -> Result<Option<Peg>, Box<dyn Error>> {
+        let reference = match &order.peg_reference {
+            Some(reference) => PegReference::parse(reference)
+                .ok_or_else(|| TinyError::new(&format!("order {} has invalid peg reference {}", order.id, reference)))?,
+            None => return Ok(None),
+        };
+        let mut peg = Peg::new(reference).with_offset(order.peg_offset.unwrap_or(0.0)).with_limit(order.price);
+        peg.price_decimals = self.market.price_decimals;
+        Ok(Some(peg))
+    }
+
+    // 出场单在数据库里保存为(price, volume, side)
+    fn exit(order: &LimitOrder) -> (f64, f64, u8) {
+        (order.price, order.volume, OrderManager::side_code(order.side))
+    }
+
+    // 数据库里还没挂出的出场单在引擎里的样子，id在挂出时才有
+    fn exit_orders(exits: &[(f64, f64, u8); 2], created_by: &str) -> (LimitOrder, LimitOrder) {
+        let exit = |(price, volume, side): (f64, f64, u8)| {
+            LimitOrder::new(0, if side == 0 { Side::Sell } else { Side::Buy }, volume, price).with_owner(created_by)
+        };
+        (exit(exits[0]), exit(exits[1]))
+    }
+
+    fn side_code(side: Side) -> u8 {
+        match side {
+            Side::Sell => 0,
+            Side::Buy => 1,
+        }
+    }
+
     // tool
     fn round(value: f64, decimals: u32) -> f64 {
         let t = 10_u32.pow(decimals) as f64;
@@ -104,3 +830,335 @@ impl<'a> OrderManager<'a> {
         (floored_big / t_big).to_f64().unwrap()
     }
 }
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::fs;
+    use std::path::PathBuf;
+    use super::OrderManager;
+    use super::BracketCancelEvent;
+    use crate::sessions::ManualClock;
+    use crate::engine::PriceBand;
+    use crate::engine::BandReference;
+    use crate::engine::BandAction;
+    use crate::accounts;
+    use crate::accounts::Change;
+    use crate::market::Market;
+    use crate::models::WAIT;
+    use crate::models::DONE;
+    use crate::models::CANCEL;
+    use crate::persister::Batch;
+    use crate::storage::SqliteStorage;
+    use crate::storage::OrderQuery;
+    use crate::storage::Storage;
+    use crate::engine::TradeEvent;
+    use crate::engine::ReduceEvent;
+    use crate::engine::Condition;
+    use crate::engine::Peg;
+    use crate::engine::PegReference;
+    use crate::errors::SubmitError;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("matching-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn create_storage() -> SqliteStorage {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        for user_id in &["u1", "u2"] {
+            storage.change_balance(&Change::deposit(user_id, "btc", 10.0)).unwrap();
+            storage.change_balance(&Change::deposit(user_id, "eth", 10.0)).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn recovers_events_lost_in_a_crash() {
+        let journal_dir = temp_dir("manager-journal");
+        let snapshot_dir = temp_dir("manager-snapshot");
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let batch = RefCell::new(Batch::default());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().trades.push(event);
+            Ok(())
+        };
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().cancels.push(order_id);
+            Ok(())
+        };
+        let persist = || storage.write_batch(&market, &batch.replace(Batch::default())).unwrap();
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        manager.open_journal(&journal_dir, &snapshot_dir, 0).unwrap();
+        let ask = manager.submit("c1", 1.5, 1.0, 0, "u2").unwrap();
+        let bid = manager.submit("c1", 1.5, 0.5, 1, "u1").unwrap();
+        persist();
+        // 崩溃: 日志已经写了，成交和撤单还没有写入数据库
+        manager.submit("c2", 1.5, 0.25, 1, "u1").unwrap();
+        let other = manager.submit("c3", 1.7, 0.5, 0, "u2").unwrap();
+        manager.cancel(other, 1.7, 0.5, 0, "u2").unwrap();
+        drop(manager);
+        batch.replace(Batch::default());
+        // 崩溃: 订单写了数据库，还没有写日志
+        let freeze = accounts::freeze(&market, "u1", 1, 1.5, 0.125);
+        let (late, _) = storage.create_order("c4", "ethbtc", 1.5, 0.125, 1, &Condition::None, None, None, "u1", &freeze).unwrap();
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        assert_eq!(6, manager.open_journal(&journal_dir, &snapshot_dir, 0).unwrap());
+        persist();
+        manager.verify().unwrap();
+        assert_eq!(3, storage.last_trade_id("ethbtc").unwrap());
+        assert_eq!(CANCEL as u16, storage.find_order(other).unwrap().unwrap().state);
+        assert_eq!(DONE as u16, storage.find_order(late).unwrap().unwrap().state);
+        assert_eq!(0.125, manager.open_order(ask).unwrap().volume);
+        // 重新投递的消息不会再进入引擎
+        assert_eq!(bid + 1, manager.submit("c2", 1.5, 0.25, 1, "u1").unwrap());
+        assert!(batch.borrow().trades.is_empty());
+        // 冻结都退回或者结算了，只剩卖单剩余的0.125
+        assert_eq!((8.6875, 0.0), storage.balance("u1", "btc").unwrap());
+        assert_eq!((9.0, 0.125), storage.balance("u2", "eth").unwrap());
+        fs::remove_dir_all(&journal_dir).unwrap();
+    }
+
+    #[test]
+    fn can_amend_and_recover_lost_amends() {
+        let journal_dir = temp_dir("manager-amend-journal");
+        let snapshot_dir = temp_dir("manager-amend-snapshot");
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let batch = RefCell::new(Batch::default());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().trades.push(event);
+            Ok(())
+        };
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().cancels.push(order_id);
+            Ok(())
+        };
+        let on_reduce = |event: ReduceEvent| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().reductions.push((event.order_id, event.reduced));
+            Ok(())
+        };
+        let persist = || storage.write_batch(&market, &batch.replace(Batch::default())).unwrap();
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        manager.set_on_reduce(&on_reduce);
+        manager.open_journal(&journal_dir, &snapshot_dir, 0).unwrap();
+        let ask = manager.submit("c1", 1.5, 1.0, 0, "u2").unwrap();
+        // 只能由下单的人减少数量
+        assert!(manager.amend(ask, 0.5, "u1").is_err());
+        assert!(manager.amend(ask, 1.0, "u2").is_err());
+        manager.amend(ask, 0.5, "u2").unwrap();
+        persist();
+        assert_eq!(0.5, storage.find_order(ask).unwrap().unwrap().volume);
+        assert_eq!((9.5, 0.5), storage.balance("u2", "eth").unwrap());
+        // 崩溃: 日志已经写了，减量还没有写入数据库
+        manager.amend(ask, 0.25, "u2").unwrap();
+        drop(manager);
+        batch.replace(Batch::default());
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        manager.set_on_reduce(&on_reduce);
+        manager.open_journal(&journal_dir, &snapshot_dir, 0).unwrap();
+        assert_eq!(vec![(ask, 0.25)], batch.borrow().reductions);
+        persist();
+        manager.verify().unwrap();
+        assert_eq!(0.25, storage.find_order(ask).unwrap().unwrap().volume);
+        assert_eq!((9.75, 0.25), storage.balance("u2", "eth").unwrap());
+        fs::remove_dir_all(&journal_dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_oco_with_only_one_existing_leg() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let on_cancel = |_order_id: u64| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut manager = OrderManager::new(&storage, market, &on_trade, &on_cancel);
+        manager.submit("c1", 1.5, 1.0, 1, "u1").unwrap();
+
+        let legs = [("c2", 1.4, 1.0, 1), ("c3", 1.7, 1.0, 0)];
+        let (first, second) = manager.submit_oco(legs, "u1").unwrap();
+        // 重复投递时返回已有的两条腿
+        assert_eq!((first, second), manager.submit_oco(legs, "u1").unwrap());
+        match manager.submit_oco([("c4", 1.4, 1.0, 1), ("c1", 1.7, 1.0, 0)], "u1") {
+            Err(SubmitError::Rejected(reason)) => assert_eq!("only some of the orders c4,c1 of u1 already exist", reason),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(3, storage.find_open_orders("ethbtc").unwrap().len());
+        assert_eq!((7.1, 2.9), storage.balance("u1", "btc").unwrap());
+    }
+
+    #[test]
+    fn restores_fill_conditions_from_database() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let on_cancel = |_order_id: u64| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        let aon = manager.submit_with_condition("c1", 1.5, 2.0, 1, "u1", Condition::AllOrNone).unwrap();
+        let min = manager.submit_with_condition("c2", 1.4, 2.0, 1, "u1", Condition::MinFill(0.5)).unwrap();
+        assert_eq!(Some("aon".to_string()), storage.find_order(aon).unwrap().unwrap().fill_condition);
+        drop(manager);
+
+        let mut manager = OrderManager::new(&storage, market, &on_trade, &on_cancel);
+        assert_eq!(2, manager.recover().unwrap());
+        assert_eq!(Condition::AllOrNone, manager.open_order(aon).unwrap().condition);
+        assert_eq!(Condition::MinFill(0.5), manager.open_order(min).unwrap().condition);
+    }
+
+    #[test]
+    fn restores_pegged_orders_from_database() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let on_cancel = |_order_id: u64| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        let peg = Peg::new(PegReference::Primary).with_offset(0.01).with_limit(1.6);
+        let pegged = manager.submit_pegged("c1", 1.0, 1, "u1", peg).unwrap();
+        manager.submit("c2", 1.5, 1.0, 1, "u1").unwrap();
+        manager.submit("c3", 1.7, 1.0, 0, "u2").unwrap();
+        assert_eq!(1.51, manager.open_order(pegged).unwrap().price);
+        let order = storage.find_order(pegged).unwrap().unwrap();
+        assert_eq!((Some("primary".to_string()), Some(0.01), 1.6), (order.peg_reference, order.peg_offset, order.price));
+        drop(manager);
+
+        // 挂钩订单先于参考的买单创建，放回时还是按买一定价
+        let mut manager = OrderManager::new(&storage, market, &on_trade, &on_cancel);
+        assert_eq!(3, manager.recover().unwrap());
+        assert!(manager.engine.pegs().contains(pegged));
+        assert_eq!(1.51, manager.open_order(pegged).unwrap().price);
+    }
+
+    #[test]
+    fn restores_order_groups_from_database() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let batch = RefCell::new(Batch::default());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().trades.push(event);
+            Ok(())
+        };
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().cancels.push(order_id);
+            Ok(())
+        };
+        let persist = || storage.write_batch(&market, &batch.replace(Batch::default())).unwrap();
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        let oco = manager.submit_oco([("c1", 1.4, 1.0, 1), ("c2", 1.7, 1.0, 0)], "u1").unwrap();
+        let waiting = manager.submit_bracket("c3", (1.3, 1.0, 1), [(1.8, 1.0, 0), (1.2, 1.0, 0)], "u1").unwrap();
+        let filled = manager.submit_bracket("c4", (1.45, 1.0, 1), [(1.9, 1.0, 0), (1.6, 1.0, 0)], "u1").unwrap();
+        manager.submit("c5", 1.45, 1.0, 0, "u2").unwrap();
+        let (canceled, orphan) = manager.submit_oco([("c6", 1.1, 1.0, 1), ("c7", 1.2, 1.0, 1)], "u2").unwrap();
+        persist();
+        assert_eq!(Some(oco.1), storage.find_order(oco.0).unwrap().unwrap().oco_id);
+        assert_eq!(2, storage.find_bracket_exits("ethbtc").unwrap().len());
+        // 崩溃: 入场单的出场单还没挂出，OCO的一条腿撤销了，另一条腿的撤单没有写入
+        storage.write_batch(&market, &Batch { cancels: vec![canceled], ..Batch::default() }).unwrap();
+        drop(manager);
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        assert_eq!(3, manager.recover().unwrap());
+        assert_eq!(vec![orphan], batch.borrow().cancels);
+        persist();
+        manager.verify().unwrap();
+
+        let exits = storage.find_orders(&OrderQuery::new().with_user("u1").with_state(WAIT)).unwrap().iter()
+            .filter(|order| order.client_order_id.starts_with(&format!("bracket-{}-", filled)))
+            .map(|order| order.id)
+            .collect::<Vec<u64>>();
+        assert_eq!(vec![oco, (exits[1], exits[0])], manager.engine.groups().oco_pairs());
+        assert_eq!(vec![waiting], manager.engine.groups().brackets().iter().map(|(entry_id, _first, _second)| *entry_id).collect::<Vec<u64>>());
+        assert_eq!(vec![waiting], storage.find_bracket_exits("ethbtc").unwrap().iter().map(|(entry_id, _exits)| *entry_id).collect::<Vec<u64>>());
+
+        // 恢复的OCO一条腿成交之后另一条腿撤销
+        manager.submit("c8", 1.4, 1.0, 0, "u2").unwrap();
+        assert_eq!(vec![oco.1], batch.borrow().cancels);
+    }
+
+    #[test]
+    fn returns_expired_sessions() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let on_cancel = |_order_id: u64| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let clock = ManualClock::new(1_000);
+        let mut manager = OrderManager::new(&storage, market, &on_trade, &on_cancel);
+        manager.set_clock(&clock);
+        assert!(manager.open_session("s1", "u1", 5_000));
+        assert!(manager.open_session("s2", "u2", 5_000));
+        let bid = manager.submit_in_session("s1", "c1", 1.5, 1.0, 1).unwrap();
+
+        assert!(manager.expire_sessions().unwrap().is_empty());
+        clock.advance(6_000);
+        let expired = vec![("s1".to_string(), "u1".to_string(), 1), ("s2".to_string(), "u2".to_string(), 0)];
+        assert_eq!(expired, manager.expire_sessions().unwrap());
+        assert!(manager.open_order(bid).is_none());
+    }
+
+    #[test]
+    fn schedules_resume_after_price_band_halt() {
+        let storage = create_storage();
+        let mut market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        market.price_band = Some(PriceBand::new(BandReference::LastTrade, 0.05, BandAction::Cancel).with_halt(0.08, 300));
+        let on_trade = |_event: TradeEvent| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let on_cancel = |_order_id: u64| -> Result<(), Box<dyn Error>> { Ok(()) };
+        let clock = ManualClock::new(1_000);
+        let mut manager = OrderManager::new(&storage, market, &on_trade, &on_cancel);
+        manager.set_clock(&clock);
+        manager.submit("c1", 1.0, 1.0, 0, "u2").unwrap();
+        manager.submit("c2", 1.04, 1.0, 0, "u2").unwrap();
+        manager.submit("c3", 1.1, 1.0, 0, "u2").unwrap();
+        manager.set_reference_price(1.0).unwrap();
+        assert_eq!(None, manager.resume_at());
+
+        manager.submit("c4", 1.2, 3.0, 1, "u1").unwrap();
+        assert_eq!(Some(301_000), manager.resume_at());
+        clock.advance(300_000);
+        assert!(manager.resume_if_due().unwrap());
+        assert_eq!(None, manager.resume_at());
+    }
+
+    #[test]
+    fn reports_bracket_exits_without_enough_balance() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let batch = RefCell::new(Batch::default());
+        let discarded = RefCell::new(Vec::new());
+        let on_trade = |event: TradeEvent| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().trades.push(event);
+            Ok(())
+        };
+        let on_cancel = |order_id: u64| -> Result<(), Box<dyn Error>> {
+            batch.borrow_mut().cancels.push(order_id);
+            Ok(())
+        };
+        let on_bracket_cancel = |event: BracketCancelEvent| -> Result<(), Box<dyn Error>> {
+            discarded.borrow_mut().push(event);
+            Ok(())
+        };
+
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        // 出场单一共要冻结12个eth，入场单成交之后只有11个
+        let entry = manager.submit_bracket("c1", (1.5, 1.0, 1), [(1.8, 6.0, 0), (1.2, 6.0, 0)], "u1").unwrap();
+        manager.submit("c2", 1.5, 1.0, 0, "u2").unwrap();
+        storage.write_batch(&market, &batch.replace(Batch::default())).unwrap();
+        assert!(manager.place_activated().is_err());
+        assert!(discarded.borrow().is_empty());
+
+        // 没有通知到调用者时出场单还在数据库里，重启之后再处理
+        let mut manager = OrderManager::new(&storage, market.clone(), &on_trade, &on_cancel);
+        manager.set_on_bracket_cancel(&on_bracket_cancel);
+        assert_eq!(0, manager.recover().unwrap());
+        assert_eq!(vec![BracketCancelEvent { entry_id: entry, created_by: "u1".to_string(), reason: "u1 has not enough eth to freeze 6".to_string() }], *discarded.borrow());
+        assert_eq!("bracket_cancel entry=1 owner=u1 reason=u1 has not enough eth to freeze 6", discarded.borrow()[0].to_string());
+        assert!(storage.find_bracket_exits("ethbtc").unwrap().is_empty());
+        assert!(manager.order_book_pair().buy_order_book.iter().chain(manager.order_book_pair().sell_order_book.iter()).next().is_none());
+        assert_eq!((11.0, 0.0), storage.balance("u1", "eth").unwrap());
+    }
+}
@@ -0,0 +1,44 @@
+use crate::fees::FeeRate;
+use crate::fees::FeeSchedule;
+use std::sync::Arc;
+use crate::engine::PriceBand;
+use crate::engine::MatchingPolicy;
+use crate::engine::PriceTime;
+
+// 一个交易对的配置
+// base是交易的标的（ethbtc里的eth），quote是计价的资产（ethbtc里的btc）
+#[derive(Debug, Clone)]
+pub struct Market {
+    pub name: String,
+    pub base: String,
+    pub quote: String,
+    pub price_decimals: u32,
+    pub volume_decimals: u32,
+    pub fees: FeeSchedule,
+    // 价格带保护，None时不限制成交价格
+    pub price_band: Option<PriceBand>,
+    // 价格带暂停交易结束后先集合竞价多少秒再恢复连续撮合，0表示直接恢复
+    pub auction_seconds: i64,
+    // 大于0时按这个间隔（毫秒）批量撮合，否则连续撮合
+    pub batch_interval: i64,
+    // 同一价位上的分配规则
+    pub matching_policy: Arc<dyn MatchingPolicy>,
+}
+
+impl Market {
+    pub fn new(name: &str, base: &str, quote: &str, price_decimals: u32, volume_decimals: u32) -> Market {
+        Market {
+            name: name.to_string(),
+            base: base.to_string(),
+            quote: quote.to_string(),
+            price_decimals: price_decimals,
+            volume_decimals: volume_decimals,
+            // 默认不收手续费
+            fees: FeeSchedule::new("fees", FeeRate::zero()),
+            price_band: None,
+            auction_seconds: 0,
+            batch_interval: 0,
+            matching_policy: Arc::new(PriceTime),
+        }
+    }
+}
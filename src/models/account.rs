@@ -0,0 +1,51 @@
+use mysql::prelude::GenericConnection;
+
+#[derive(Debug)]
+pub struct Account {
+    pub user_id: String,
+    pub asset: String,
+    pub available: f64,
+    pub frozen: f64,
+}
+
+impl Account {
+    // 可用和冻结余额各加上一个增量（可以是负数），任何一个会变成负数时不修改，返回false
+    // overdraft时可用余额允许变成负数；增量以字符串传入，在数据库里按decimal计算
+    pub fn change<T>(conn: &mut T, user_id: &str, asset: &str, available: &str, frozen: &str, overdraft: bool) -> mysql::Result<bool>
+    where T: GenericConnection
+    {
+        conn.prep_exec(
+            "INSERT IGNORE INTO accounts (user_id, asset, available, frozen) VALUES (:user_id, :asset, 0, 0)",
+            (user_id, asset)
+        )?;
+        let result = conn.prep_exec(
+            r"UPDATE accounts
+              SET available=available+cast(:available as decimal(32,16)), frozen=frozen+cast(:frozen as decimal(32,16))
+              WHERE user_id=:user_id AND asset=:asset
+                AND (:overdraft OR available+cast(:available as decimal(32,16))>=0) AND frozen+cast(:frozen as decimal(32,16))>=0",
+            (available, frozen, user_id, asset, overdraft, available, frozen)
+        )?;
+        Ok(result.affected_rows() == 1)
+    }
+
+    pub fn find<T>(conn: &mut T, user_id: &str, asset: &str) -> mysql::Result<Option<Account>>
+    where T: GenericConnection
+    {
+        let mut result = conn.prep_exec(
+            "SELECT user_id, asset, available, frozen FROM accounts WHERE user_id=:user_id AND asset=:asset",
+            (user_id, asset)
+        )?;
+        match result.next() {
+            Some(row) => {
+                let (user_id, asset, available, frozen) = mysql::from_row(row?);
+                Ok(Some(Account {
+                    user_id: user_id,
+                    asset: asset,
+                    available: available,
+                    frozen: frozen,
+                }))
+            },
+            None => Ok(None),
+        }
+    }
+}
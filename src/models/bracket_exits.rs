@@ -0,0 +1,34 @@
+use mysql::prelude::GenericConnection;
+
+// 括号单还没挂出的出场单: (price, volume, side)
+pub fn save_bracket_exits<T>(conn: &mut T, entry_id: u64, market: &str, exits: &[(f64, f64, u8); 2]) -> mysql::Result<()>
+where T: GenericConnection
+{
+    conn.prep_exec(
+        r"INSERT INTO bracket_exits (entry_id, market, first_price, first_volume, first_side, second_price, second_volume, second_side)
+          VALUES (?, ?, cast(? as decimal(32,16)), cast(? as decimal(32,16)), ?, cast(? as decimal(32,16)), cast(? as decimal(32,16)), ?)",
+        (entry_id, market, exits[0].0, exits[0].1, exits[0].2, exits[1].0, exits[1].1, exits[1].2)
+    )?;
+    Ok(())
+}
+
+pub fn find_bracket_exits<T>(conn: &mut T, market: &str) -> mysql::Result<Vec<(u64, [(f64, f64, u8); 2])>>
+where T: GenericConnection
+{
+    let result = conn.prep_exec(
+        r"SELECT entry_id, first_price, first_volume, first_side, second_price, second_volume, second_side
+          FROM bracket_exits WHERE market=:market ORDER BY entry_id",
+        (market,)
+    )?;
+    result.map(|row| row.map(|row| {
+        let (entry_id, first_price, first_volume, first_side, second_price, second_volume, second_side) = mysql::from_row(row);
+        (entry_id, [(first_price, first_volume, first_side), (second_price, second_volume, second_side)])
+    })).collect()
+}
+
+pub fn delete_bracket_exits<T>(conn: &mut T, entry_id: u64) -> mysql::Result<()>
+where T: GenericConnection
+{
+    conn.prep_exec("DELETE FROM bracket_exits WHERE entry_id=:entry_id", (entry_id,))?;
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use mysql::prelude::GenericConnection;
+
+use crate::candles::Candle;
+
+// 多行INSERT，(market, period, time)已经存在时覆盖，回补K线时可以重复执行
+pub fn save_candles<T>(conn: &mut T, candles: &[Candle]) -> mysql::Result<()>
+where T: GenericConnection
+{
+    if candles.is_empty() {
+        return Ok(());
+    }
+
+    let rows = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"; candles.len()].join(", ");
+    let mut params: Vec<mysql::Value> = Vec::with_capacity(candles.len() * 10);
+    for candle in candles {
+        params.push(candle.market.as_str().into());
+        params.push(candle.period.into());
+        params.push(candle.time.into());
+        params.push(candle.open.into());
+        params.push(candle.high.into());
+        params.push(candle.low.into());
+        params.push(candle.close.into());
+        params.push(candle.volume.into());
+        params.push(candle.funds.into());
+        params.push(candle.trades_count.into());
+    }
+    conn.prep_exec(format!(
+        r"INSERT INTO candles (market, period, time, open, high, low, close, volume, funds, trades_count) VALUES {}
+          ON DUPLICATE KEY UPDATE open=VALUES(open), high=VALUES(high), low=VALUES(low), close=VALUES(close),
+            volume=VALUES(volume), funds=VALUES(funds), trades_count=VALUES(trades_count)",
+        rows
+    ), params)?;
+    Ok(())
+}
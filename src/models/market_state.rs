@@ -0,0 +1,22 @@
+use mysql::prelude::GenericConnection;
+
+// market_states表里的状态名，见engine::MarketState
+pub fn find_market_state<T>(conn: &mut T, market: &str) -> mysql::Result<Option<String>>
+where T: GenericConnection
+{
+    let mut result = conn.prep_exec("SELECT state FROM market_states WHERE market=:market", (market,))?;
+    match result.next() {
+        Some(row) => Ok(Some(mysql::from_row(row?))),
+        None => Ok(None),
+    }
+}
+
+pub fn save_market_state<T>(conn: &mut T, market: &str, state: &str) -> mysql::Result<()>
+where T: GenericConnection
+{
+    conn.prep_exec(
+        r"INSERT INTO market_states (market, state) VALUES (:market, :state) ON DUPLICATE KEY UPDATE state=VALUES(state)",
+        (market, state)
+    )?;
+    Ok(())
+}
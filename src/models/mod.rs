@@ -1,5 +1,19 @@
 mod order;
 mod trade;
+mod account;
+mod candle;
+mod market_state;
+mod bracket_exits;
 
 pub use order::Order;
 pub use trade::Trade;
+pub use account::Account;
+pub use candle::save_candles;
+pub use market_state::find_market_state;
+pub use market_state::save_market_state;
+pub use bracket_exits::save_bracket_exits;
+pub use bracket_exits::find_bracket_exits;
+pub use bracket_exits::delete_bracket_exits;
+pub use order::WAIT;
+pub use order::DONE;
+pub use order::CANCEL;
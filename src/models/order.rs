@@ -1,101 +1,223 @@
 use chrono::prelude::NaiveDateTime;
 use mysql::prelude::GenericConnection;
 
+use crate::storage::OrderQuery;
+use crate::storage::QueryParam;
+
 #[derive(Debug)]
 pub struct Order {
-    id: u64,
-    price: f64,
-    volume: f64,
-    origin_volume: f64,
-    state: u16,
-    side: u8, //0: ask, 1: buy
-    trades_count: u16,
-    created_by: Option<String>,
-    created_at: Option<NaiveDateTime>,
-    updated_at: Option<NaiveDateTime>,
+    pub id: u64,
+    pub client_order_id: String,
+    pub market: String,
+    pub price: f64,
+    pub volume: f64,
+    pub origin_volume: f64,
+    pub state: u16,
+    pub side: u8, //0: ask, 1: buy
+    pub trades_count: u16,
+    pub created_by: Option<String>,
+    pub created_at: Option<NaiveDateTime>,
+    pub updated_at: Option<NaiveDateTime>,
+    // 成交条件，写法见engine::Condition::parse，普通限价单是None
+    pub fill_condition: Option<String>,
+    // 挂钩订单的参考价和偏移，限价就是price，普通订单是None
+    pub peg_reference: Option<String>,
+    pub peg_offset: Option<f64>,
+    // OCO的另一条腿，不是OCO时是None
+    pub oco_id: Option<u64>,
 }
 
-const WAIT: u8 = 100; 
-const DONE: u8 = 200; 
-const CANCEL: u8 = 0; 
+const COLUMNS: &str = "id, client_order_id, market, price, volume, origin_volume, state, side, trades_count, created_by, created_at, updated_at, fill_condition, peg_reference, peg_offset, oco_id";
+
+pub const WAIT: u8 = 100; 
+pub const DONE: u8 = 200; 
+pub const CANCEL: u8 = 0; 
+
+// ER_DUP_ENTRY
+const DUPLICATE_ENTRY: u16 = 1062;
 
 impl Order {
-    pub fn create(pool: &mysql::Pool, price: f64, volume: f64, side: u8,  created_by: &str) -> u64 {
-        let mut stmt = pool.prepare(r"INSERT INTO orders 
-                            (price, volume, origin_volume, state, side, created_by)
+    // (created_by, client_order_id) 有唯一索引，同一个用户重复提交同一个client_order_id时返回已有订单的id
+    // 返回值的第二项表示订单是否是这次新建的
+    pub fn create<T>(conn: &mut T, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, fill_condition: Option<String>, peg: (Option<String>, Option<f64>), created_by: &str) -> mysql::Result<(u64, bool)>
+    where T: GenericConnection
+    {
+        if let Some(id) = Order::find_by_client_order_id(conn, created_by, client_order_id)? {
+            return Ok((id, false));
+        }
+
+        let mut stmt = conn.prepare(r"INSERT INTO orders 
+                            (client_order_id, market, price, volume, origin_volume, state, side, fill_condition, peg_reference, peg_offset, created_by)
                         VALUES
-                            (cast(:price as decimal(32,16)), cast(:volume as decimal(32,16)), :origin_volume, :state, :side, :created_by)").unwrap();
-        let id = stmt.execute((
+                            (:client_order_id, :market, cast(:price as decimal(32,16)), cast(:volume as decimal(32,16)), :origin_volume, :state, :side, :fill_condition,
+                             :peg_reference, cast(:peg_offset as decimal(32,16)), :created_by)")?;
+        let result = stmt.execute((
+            client_order_id,
+            market,
             price,
             volume,
             volume,
             WAIT,
             side,
+            fill_condition,
+            peg.0,
+            peg.1,
             created_by,
-        )).unwrap().last_insert_id();
+        ));
+
+        let result = result.map(|result| result.last_insert_id());
+        drop(stmt);
+        match result {
+            Ok(id) => Ok((id, true)),
+            // 并发插入时，唯一索引兜底
+            Err(mysql::Error::MySqlError(err)) if err.code == DUPLICATE_ENTRY => {
+                match Order::find_by_client_order_id(conn, created_by, client_order_id)? {
+                    Some(id) => Ok((id, false)),
+                    None => Err(mysql::Error::MySqlError(err)),
+                }
+            },
+            Err(err) => Err(err),
+        }
+    }
 
-        id
+    pub fn find_by_client_order_id<T>(conn: &mut T, created_by: &str, client_order_id: &str) -> mysql::Result<Option<u64>>
+    where T: GenericConnection
+    {
+        let mut result = conn.prep_exec(
+            "SELECT id FROM orders WHERE created_by=:created_by AND client_order_id=:client_order_id",
+            (created_by, client_order_id)
+        )?;
+        match result.next() {
+            Some(row) => Ok(Some(mysql::from_row(row?))),
+            None => Ok(None),
+        }
     }
 
-    pub fn set_canceled<T>(conn: &mut T, id: u64)
+    pub fn set_canceled<T>(conn: &mut T, id: u64) -> mysql::Result<()>
     where T: GenericConnection
     {
-        let mut stmt = conn.prepare(r"UPDATE orders SET state=:state WHERE id=:id").unwrap();
+        let mut stmt = conn.prepare(r"UPDATE orders SET state=:state WHERE id=:id")?;
         stmt.execute((
             CANCEL,
             id,
-        ));
+        ))?;
+        Ok(())
     }
 
-    pub fn sub_volume<T>(conn: &mut T, id: u64, delta_volume: f64, filled: bool)
+    // OCO的两条腿互相记录另一条腿
+    pub fn link_oco<T>(conn: &mut T, first_id: u64, second_id: u64) -> mysql::Result<()>
     where T: GenericConnection
     {
-        let mut stmt = conn.prepare(r"UPDATE orders SET trades_count=trades_count+1, volume=volume-cast(:trade_volume as decimal(32,16)), state=:state WHERE id=:id").unwrap();
-        let state = if filled { DONE } else { WAIT };
+        let mut stmt = conn.prepare(r"UPDATE orders SET oco_id=:oco_id WHERE id=:id")?;
+        stmt.execute((second_id, first_id))?;
+        stmt.execute((first_id, second_id))?;
+        Ok(())
+    }
+
+    // 改单减少挂单的剩余数量
+    pub fn reduce_volume<T>(conn: &mut T, id: u64, volume: f64) -> mysql::Result<()>
+    where T: GenericConnection
+    {
+        let mut stmt = conn.prepare(r"UPDATE orders SET volume=volume-cast(:volume as decimal(32,16)) WHERE id=:id")?;
         stmt.execute((
-            delta_volume,
-            state,
+            volume,
             id,
-        ));
+        ))?;
+        Ok(())
     }
 
-    pub fn add_volume<T>(conn: &mut T, id: u64, delta_volume: f64, filled: bool)
+    // 一次写入同一个订单的多笔成交
+    pub fn sub_volumes<T>(conn: &mut T, id: u64, delta_volume: f64, trades_count: u32, filled: bool) -> mysql::Result<()>
     where T: GenericConnection
     {
-        let mut stmt = conn.prepare(r"update orders set trades_count=trades_count+1, volume=volume+cast(:trade_volume as decimal(32,16)), state=:state where id=:id").unwrap();
+        let mut stmt = conn.prepare(r"UPDATE orders SET trades_count=trades_count+:trades_count, volume=volume-cast(:trade_volume as decimal(32,16)), state=:state WHERE id=:id")?;
         let state = if filled { DONE } else { WAIT };
         stmt.execute((
+            trades_count,
             delta_volume,
             state,
             id,
-        ));
+        ))?;
+        Ok(())
+    }
+
+    pub fn find_by_id<T>(conn: &mut T, id: u64) -> mysql::Result<Option<Order>>
+    where T: GenericConnection
+    {
+        let mut result = conn.prep_exec(format!("SELECT {} FROM orders WHERE id=:id", COLUMNS), (id,))?;
+        match result.next() {
+            Some(row) => Ok(Some(Order::from_row(row?))),
+            None => Ok(None),
+        }
+    }
+
+    // 按条件查订单，按id从新到旧排列
+    pub fn find_by_query(pool: &mysql::Pool, query: &OrderQuery) -> mysql::Result<Vec<Order>> {
+        let (sql, params) = query.to_sql(COLUMNS, |_| "?".to_string(), |value| format!("FROM_UNIXTIME({})", value));
+        let params = params.into_iter().map(|param| match param {
+            QueryParam::Text(text) => mysql::Value::from(text),
+            QueryParam::State(state) => mysql::Value::from(state),
+            QueryParam::Id(id) => mysql::Value::from(id),
+            QueryParam::Time(time) => mysql::Value::from(time),
+        }).collect::<Vec<mysql::Value>>();
+        let result = pool.prep_exec(sql, params)?;
+        result.map(|row| row.map(Order::from_row)).collect()
+    }
+
+    // 某个市场所有还在挂单的订单，按进入撮合引擎的先后顺序排列，用于重启后恢复订单簿
+    pub fn find_open(pool: &mysql::Pool, market: &str) -> mysql::Result<Vec<Order>> {
+        let result = pool.prep_exec(
+            format!("SELECT {} FROM orders WHERE market=:market AND state=:state ORDER BY created_at, id", COLUMNS),
+            (market, WAIT)
+        )?;
+        result.map(|row| row.map(Order::from_row)).collect()
+    }
+
+    // 按id查订单并加行锁，用在写成交的事务里
+    pub fn find_for_update<T>(conn: &mut T, ids: &[u64]) -> mysql::Result<Vec<Order>>
+    where T: GenericConnection
+    {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let result = conn.prep_exec(
+            format!("SELECT {} FROM orders WHERE id IN ({}) FOR UPDATE", COLUMNS, placeholders),
+            ids.to_vec()
+        )?;
+        result.map(|row| row.map(Order::from_row)).collect()
+    }
+
+    // 某个市场挂单的汇总: (side, 订单数, 剩余数量之和)
+    pub fn open_totals(pool: &mysql::Pool, market: &str) -> mysql::Result<Vec<(u8, u64, f64)>> {
+        let result = pool.prep_exec(
+            r"SELECT side, COUNT(*), SUM(volume) FROM orders WHERE market=:market AND state=:state GROUP BY side",
+            (market, WAIT)
+        )?;
+        result.map(|row| row.map(|row| mysql::from_row(row))).collect()
     }
 
-    pub fn find(pool: &mysql::Pool) -> Vec<Order> {
-        let selected_payments: Vec<Order> =
-            pool.prep_exec("SELECT id, price, volume, origin_volume, state, side, trades_count, created_by, created_at, updated_at from orders", ())
-            .map(|result| { // In this closure we will map `QueryResult` to `Vec<Order>`
-                // `QueryResult` is iterator over `MyResult<row, err>` so first call to `map`
-                // will map each `MyResult` to contained `row` (no proper error handling)
-                // and second call to `map` will map each `row` to `Order`
-                result.map(|x| x.unwrap()).map(|row| {
-                    // ⚠️ Note that from_row will panic if you don't follow your schema
-                    let (id, price, volume, origin_volume, state, side, trades_count, created_by, created_at, updated_at) = mysql::from_row(row);
-                    Order {
-                        id: id,
-                        price: price,
-                        volume: volume,
-                        origin_volume: origin_volume,
-                        state: state,
-                        side: side,
-                        trades_count: trades_count,
-                        created_by: created_by,
-                        created_at: created_at,
-                        updated_at: updated_at,
-                    }
-                }).collect() // Collect payments so now `QueryResult` is mapped to `Vec<Order>`
-            }).unwrap(); // Unwrap `Vec<Order>`
-        selected_payments
+    // ⚠️ Note that from_row will panic if you don't follow your schema
+    // 列数超过了mysql::from_row支持的元组长度，按COLUMNS的顺序逐列取
+    fn from_row(mut row: mysql::Row) -> Order {
+        Order {
+            id: row.take(0).unwrap(),
+            client_order_id: row.take(1).unwrap(),
+            market: row.take(2).unwrap(),
+            price: row.take(3).unwrap(),
+            volume: row.take(4).unwrap(),
+            origin_volume: row.take(5).unwrap(),
+            state: row.take(6).unwrap(),
+            side: row.take(7).unwrap(),
+            trades_count: row.take(8).unwrap(),
+            created_by: row.take(9).unwrap(),
+            created_at: row.take(10).unwrap(),
+            updated_at: row.take(11).unwrap(),
+            fill_condition: row.take(12).unwrap(),
+            peg_reference: row.take(13).unwrap(),
+            peg_offset: row.take(14).unwrap(),
+            oco_id: row.take(15).unwrap(),
+        }
     }
 
 }
@@ -14,23 +14,52 @@ pub struct Trade {
 }
 
 impl Trade {
-    pub fn create<T>(conn: &mut T, price: f64, volume: f64, ask_order_id: u64, bid_order_id: u64) -> u64 
+    // 多行INSERT，一条语句写入一个市场的一批成交: (seq, price, volume, trend, ask_order_id, bid_order_id, ask_fee, bid_fee)
+    // seq是撮合引擎分配的成交编号，手续费是十进制文本，在数据库里转成decimal
+    pub fn create_batch<T>(conn: &mut T, market: &str, trades: &[(u64, f64, f64, u16, u64, u64, String, String)]) -> mysql::Result<()>
     where T: GenericConnection
     {
-        let mut stmt = conn.prepare(
-            r"INSERT INTO trades 
-                (price, volume, ask_order_id, bid_order_id)
-              VALUES
-                (:price, :volume, :ask_order_id, :bid_order_id)"
-        ).unwrap();
-        let id = stmt.execute((
-            price,
-            volume,
-            ask_order_id,
-            bid_order_id,
-        )).unwrap().last_insert_id();
+        if trades.is_empty() {
+            return Ok(());
+        }
 
-        id
+        let rows = vec!["(?, ?, ?, ?, ?, ?, ?, cast(? as decimal(32,16)), cast(? as decimal(32,16)))"; trades.len()].join(", ");
+        let mut params: Vec<mysql::Value> = Vec::with_capacity(trades.len() * 9);
+        for (seq, price, volume, trend, ask_order_id, bid_order_id, ask_fee, bid_fee) in trades {
+            params.push(market.into());
+            params.push((*seq).into());
+            params.push((*price).into());
+            params.push((*volume).into());
+            params.push((*trend).into());
+            params.push((*ask_order_id).into());
+            params.push((*bid_order_id).into());
+            params.push(ask_fee.as_str().into());
+            params.push(bid_fee.as_str().into());
+        }
+        conn.prep_exec(format!("INSERT INTO trades (market, seq, price, volume, trend, ask_order_id, bid_order_id, ask_fee, bid_fee) VALUES {}", rows), params)?;
+        Ok(())
+    }
+
+    // 某个市场从since（unix秒）开始的成交: (price, volume, 成交时间)
+    pub fn find_since<T>(conn: &mut T, market: &str, since: i64) -> mysql::Result<Vec<(f64, f64, i64)>>
+    where T: GenericConnection
+    {
+        let result = conn.prep_exec(
+            r"SELECT price, volume, UNIX_TIMESTAMP(created_at) FROM trades
+              WHERE market=:market AND created_at>=FROM_UNIXTIME(:since) ORDER BY seq",
+            (market, since)
+        )?;
+        result.map(|row| row.map(|row| mysql::from_row(row))).collect()
+    }
+
+    pub fn last_seq<T>(conn: &mut T, market: &str) -> mysql::Result<u64>
+    where T: GenericConnection
+    {
+        let mut result = conn.prep_exec("SELECT COALESCE(MAX(seq), 0) FROM trades WHERE market=:market", (market,))?;
+        match result.next() {
+            Some(row) => Ok(mysql::from_row(row?)),
+            None => Ok(0),
+        }
     }
 
 }
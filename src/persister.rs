@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use bigdecimal::BigDecimal;
+
+use crate::accounts::decimal;
+use crate::candles::Candle;
+use crate::engine::TradeEvent;
+use crate::engine::ReduceEvent;
+use crate::errors::TinyError;
+
+// 一次写入（一个数据库事务）的内容
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub trades: Vec<TradeEvent>,
+    pub cancels: Vec<u64>,
+    // 改单减少的数量: (order_id, 减少的数量)
+    pub reductions: Vec<(u64, f64)>,
+    // 已经结束的K线
+    pub candles: Vec<Candle>,
+}
+
+impl Batch {
+    // 按订单合并成交: (order_id, 成交数量之和, 成交笔数, 最后是否已完全成交)，按订单第一次出现的顺序
+    pub fn order_fills(&self) -> Vec<(u64, f64, u32, bool)> {
+        let mut fills: Vec<(u64, BigDecimal, u32, bool)> = Vec::new();
+        let mut index: HashMap<u64, usize> = HashMap::new();
+        for trade in &self.trades {
+            let volume = decimal(trade.volume);
+            for (order_id, filled) in &[(trade.ask_order_id, trade.ask_order_filled), (trade.bid_order_id, trade.bid_order_filled)] {
+                match index.get(order_id) {
+                    Some(i) => {
+                        let fill = &mut fills[*i];
+                        fill.1 = fill.1.clone() + volume.clone();
+                        fill.2 += 1;
+                        fill.3 = *filled;
+                    },
+                    None => {
+                        index.insert(*order_id, fills.len());
+                        fills.push((*order_id, volume.clone(), 1, *filled));
+                    }
+                }
+            }
+        }
+        fills.into_iter().map(|(id, volume, trades, filled)| (id, volume.to_string().parse::<f64>().unwrap(), trades, filled)).collect()
+    }
+
+    // 这一批涉及的所有订单
+    pub fn order_ids(&self) -> Vec<u64> {
+        let mut ids = Vec::new();
+        let reduced = self.reductions.iter().map(|(order_id, _volume)| *order_id);
+        for id in self.trades.iter().flat_map(|trade| vec![trade.ask_order_id, trade.bid_order_id]).chain(self.cancels.iter().cloned()).chain(reduced) {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        ids
+    }
+
+    fn len(&self) -> usize {
+        self.trades.len() + self.cancels.len() + self.reductions.len() + self.candles.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+enum Event {
+    Trade(TradeEvent),
+    Cancel(u64),
+    Reduce(ReduceEvent),
+    Candle(Candle),
+    // Begin和Commit之间的事件写进同一批
+    Begin,
+    Commit,
+    // 之前的事件都写入后回复
+    Barrier(mpsc::Sender<Result<(), String>>),
+}
+
+// 写入失败时的重试次数，超过之后停止写入，之后所有的flush都返回带重试次数的错误
+const RETRIES: u32 = 5;
+// 第一次重试之前等待的毫秒数，之后每次翻倍，一共等1.5秒，足够数据库主从切换或者死锁超时
+const BACKOFF_MILLIS: u64 = 100;
+
+// 把成交和撤单从撮合线程移到单独的写线程，按批写入数据库
+// 队列是有界的，写线程跟不上时撮合线程在trade/cancel上阻塞
+pub struct Persister {
+    sender: Option<SyncSender<Event>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Persister {
+    // write在写线程里执行，负责在一个事务里写入整个batch
+    pub fn start<F>(capacity: usize, max_batch: usize, write: F) -> Persister
+    where F: FnMut(&Batch) -> Result<(), Box<dyn Error>> + Send + 'static
+    {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let writer = thread::spawn(move || Persister::run(receiver, max_batch, write));
+        Persister {
+            sender: Some(sender),
+            writer: Some(writer),
+        }
+    }
+
+    pub fn trade(&self, event: TradeEvent) -> Result<(), Box<dyn Error>> {
+        self.send(Event::Trade(event))
+    }
+
+    pub fn cancel(&self, order_id: u64) -> Result<(), Box<dyn Error>> {
+        self.send(Event::Cancel(order_id))
+    }
+
+    pub fn reduce(&self, event: ReduceEvent) -> Result<(), Box<dyn Error>> {
+        self.send(Event::Reduce(event))
+    }
+
+    pub fn candle(&self, candle: Candle) -> Result<(), Box<dyn Error>> {
+        self.send(Event::Candle(candle))
+    }
+
+    // begin和commit之间提交的事件在同一个事务里写入，不受max_batch的限制，比如一次批量撤单
+    pub fn begin(&self) -> Result<(), Box<dyn Error>> {
+        self.send(Event::Begin)
+    }
+
+    pub fn commit(&self) -> Result<(), Box<dyn Error>> {
+        self.send(Event::Commit)
+    }
+
+    // 等待之前提交的所有事件都写入数据库，有任何一批写入失败时返回错误
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let (sender, receiver) = mpsc::channel();
+        self.send(Event::Barrier(sender))?;
+        match receiver.recv() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(message)) => Err(Box::new(TinyError::new(&message))),
+            Err(_) => Err(Box::new(TinyError::new("persister stopped")))
+        }
+    }
+
+    fn send(&self, event: Event) -> Result<(), Box<dyn Error>> {
+        match self.sender.as_ref().unwrap().send(event) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(Box::new(TinyError::new("persister stopped")))
+        }
+    }
+
+    fn run<F>(receiver: Receiver<Event>, max_batch: usize, mut write: F)
+    where F: FnMut(&Batch) -> Result<(), Box<dyn Error>>
+    {
+        let mut failure: Option<String> = None;
+        while let Ok(event) = receiver.recv() {
+            let mut batch = Batch::default();
+            let mut barriers = Vec::new();
+            let mut open = false;
+            let mut next = Some(event);
+            while let Some(event) = next {
+                match event {
+                    Event::Trade(trade) => batch.trades.push(trade),
+                    Event::Cancel(order_id) => batch.cancels.push(order_id),
+                    Event::Reduce(event) => batch.reductions.push((event.order_id, event.reduced)),
+                    Event::Candle(candle) => batch.candles.push(candle),
+                    Event::Begin => open = true,
+                    Event::Commit => open = false,
+                    Event::Barrier(reply) => barriers.push(reply),
+                }
+                // 分组还没结束时等下一个事件
+                next = if open {
+                    receiver.recv().ok()
+                } else if batch.len() < max_batch {
+                    receiver.try_recv().ok()
+                } else {
+                    None
+                };
+            }
+
+            if failure.is_none() && !batch.is_empty() {
+                let mut attempts = 0;
+                while let Err(err) = write(&batch) {
+                    attempts += 1;
+                    if attempts >= RETRIES {
+                        failure = Some(format!("persist batch failed after {} attempts: {}", attempts, err));
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(BACKOFF_MILLIS << (attempts - 1)));
+                }
+            }
+
+            for reply in barriers {
+                let _ = reply.send(match &failure {
+                    Some(message) => Err(message.clone()),
+                    None => Ok(())
+                });
+            }
+        }
+    }
+}
+
+// 关闭队列并等待写线程处理完剩下的事件
+impl Drop for Persister {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use super::{Batch, Persister};
+    use crate::engine::Side;
+    use crate::engine::TradeEvent;
+    use crate::errors::TinyError;
+
+    fn trade(ask_order_id: u64, bid_order_id: u64, volume: f64, bid_order_filled: bool) -> TradeEvent {
+        TradeEvent {
+            id: 1,
+            price: 1.5,
+            volume: volume,
+            funds: 1.5 * volume,
+            ask_order_id: ask_order_id,
+            ask_order_filled: false,
+            bid_order_id: bid_order_id,
+            bid_order_filled: bid_order_filled,
+            taker_side: Side::Buy,
+            maker_order_id: ask_order_id,
+            taker_order_id: bid_order_id,
+            ask_owner: "u1".to_string(),
+            bid_owner: "u2".to_string(),
+        }
+    }
+
+    #[test]
+    fn can_merge_fills_by_order() {
+        let batch = Batch {
+            trades: vec![trade(1, 2, 0.1, false), trade(1, 3, 0.2, true), trade(4, 2, 0.3, true)],
+            cancels: vec![],
+            reductions: vec![],
+            candles: vec![],
+        };
+        assert_eq!(vec![
+            (1, 0.3, 2, false),
+            (2, 0.4, 2, true),
+            (3, 0.2, 1, true),
+            (4, 0.3, 1, false),
+        ], batch.order_fills());
+    }
+
+    #[test]
+    fn flush_waits_for_all_batches() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writer_written = written.clone();
+        let persister = Persister::start(4, 2, move |batch: &Batch| -> Result<(), Box<dyn Error>> {
+            writer_written.lock().unwrap().push((batch.trades.len(), batch.cancels.clone()));
+            Ok(())
+        });
+
+        for id in 0..5 {
+            persister.trade(trade(id, 100 + id, 0.1, true)).unwrap();
+        }
+        persister.cancel(7).unwrap();
+        persister.flush().unwrap();
+
+        let written = written.lock().unwrap();
+        assert!(written.iter().all(|(trades, cancels)| trades + cancels.len() <= 2));
+        assert_eq!(5, written.iter().map(|(trades, _)| trades).sum::<usize>());
+        assert_eq!(vec![7], written.iter().flat_map(|(_, cancels)| cancels.clone()).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn flush_reports_failed_batches() {
+        let persister = Persister::start(4, 10, |_batch: &Batch| -> Result<(), Box<dyn Error>> {
+            Err(Box::new(TinyError::new("deadlock found")))
+        });
+        persister.flush().unwrap();
+        persister.trade(trade(1, 2, 0.1, true)).unwrap();
+        assert_eq!("persist batch failed after 5 attempts: deadlock found", persister.flush().unwrap_err().to_string());
+        assert!(persister.flush().is_err());
+    }
+
+    #[test]
+    fn retries_failed_batches() {
+        let attempts = Arc::new(Mutex::new(0));
+        let writer_attempts = attempts.clone();
+        let persister = Persister::start(4, 10, move |_batch: &Batch| -> Result<(), Box<dyn Error>> {
+            let mut attempts = writer_attempts.lock().unwrap();
+            *attempts += 1;
+            if *attempts < 3 {
+                return Err(Box::new(TinyError::new("lock wait timeout")));
+            }
+            Ok(())
+        });
+        persister.trade(trade(1, 2, 0.1, true)).unwrap();
+        persister.flush().unwrap();
+        assert_eq!(3, *attempts.lock().unwrap());
+    }
+
+    #[test]
+    fn writes_groups_in_one_batch() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writer_written = written.clone();
+        let persister = Persister::start(4, 2, move |batch: &Batch| -> Result<(), Box<dyn Error>> {
+            writer_written.lock().unwrap().push(batch.cancels.clone());
+            Ok(())
+        });
+
+        persister.cancel(1).unwrap();
+        persister.flush().unwrap();
+        persister.begin().unwrap();
+        for id in 2..7 {
+            persister.cancel(id).unwrap();
+        }
+        persister.commit().unwrap();
+        persister.flush().unwrap();
+
+        assert_eq!(vec![vec![1], vec![2, 3, 4, 5, 6]], *written.lock().unwrap());
+    }
+}
@@ -0,0 +1,193 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use chrono::Utc;
+
+// 会话超时用的时钟，单位毫秒；测试里用ManualClock代替系统时间
+pub trait Clock {
+    fn now(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
+
+// 手动拨动的时钟
+pub struct ManualClock {
+    now: Cell<i64>,
+}
+
+impl ManualClock {
+    pub fn new(now: i64) -> ManualClock {
+        ManualClock { now: Cell::new(now) }
+    }
+
+    pub fn advance(&self, millis: i64) {
+        self.now.set(self.now.get() + millis);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        self.now.get()
+    }
+}
+
+// 一个连接的会话，超过timeout毫秒没有心跳就认为连接已经断开
+// order_ids是这个会话下过的订单，可能包含已经成交或撤销的订单
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub owner: String,
+    pub timeout: i64,
+    pub last_heartbeat: i64,
+    pub order_ids: Vec<u64>,
+}
+
+// 断线自动撤单的会话表，会话过期或者登出时由调用者撤掉它的挂单
+pub struct Sessions<'a> {
+    clock: &'a dyn Clock,
+    sessions: HashMap<String, Session>,
+}
+
+impl<'a> Sessions<'a> {
+    pub fn new(clock: &'a dyn Clock) -> Sessions<'a> {
+        Sessions {
+            clock: clock,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: &'a dyn Clock) {
+        self.clock = clock;
+    }
+
+    // 同一个会话重连时保留它的订单，只刷新心跳和超时
+    // 会话id已经属于别的用户时不修改，返回false
+    pub fn open(&mut self, id: &str, owner: &str, timeout: i64) -> bool {
+        if self.sessions.get(id).map_or(false, |session| session.owner != owner) {
+            return false;
+        }
+        let now = self.clock.now();
+        let session = self.sessions.entry(id.to_string()).or_insert_with(|| Session {
+            id: id.to_string(),
+            owner: owner.to_string(),
+            timeout: timeout,
+            last_heartbeat: now,
+            order_ids: Vec::new(),
+        });
+        session.timeout = timeout;
+        session.last_heartbeat = now;
+        true
+    }
+
+    // 会话不存在或者已经超时（等待expire处理）时返回None
+    pub fn get(&self, id: &str) -> Option<&Session> {
+        let now = self.clock.now();
+        self.sessions.get(id).filter(|session| now - session.last_heartbeat <= session.timeout)
+    }
+
+    pub fn heartbeat(&mut self, id: &str) -> bool {
+        if self.get(id).is_none() {
+            return false;
+        }
+        let now = self.clock.now();
+        self.sessions.get_mut(id).unwrap().last_heartbeat = now;
+        true
+    }
+
+    pub fn track(&mut self, id: &str, order_id: u64) -> bool {
+        match self.sessions.get_mut(id) {
+            Some(session) => {
+                session.order_ids.push(order_id);
+                true
+            },
+            None => false
+        }
+    }
+
+    // 去掉已经不在订单簿里的订单，避免长时间的会话越积越多
+    pub fn retain_orders<F>(&mut self, id: &str, resting: F)
+    where F: Fn(u64) -> bool
+    {
+        if let Some(session) = self.sessions.get_mut(id) {
+            session.order_ids.retain(|order_id| resting(*order_id));
+        }
+    }
+
+    // 登出
+    pub fn close(&mut self, id: &str) -> Option<Session> {
+        self.sessions.remove(id)
+    }
+
+    // 移除并返回所有超时的会话，按id排列
+    pub fn expire(&mut self) -> Vec<Session> {
+        let now = self.clock.now();
+        let mut expired = self.sessions.values()
+            .filter(|session| now - session.last_heartbeat > session.timeout)
+            .map(|session| session.id.clone())
+            .collect::<Vec<String>>();
+        expired.sort();
+        expired.iter().map(|id| self.sessions.remove(id).unwrap()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ManualClock, Sessions};
+
+    #[test]
+    fn expires_sessions_without_heartbeat() {
+        let clock = ManualClock::new(1_000);
+        let mut sessions = Sessions::new(&clock);
+        sessions.open("s1", "u1", 5_000);
+        sessions.open("s2", "u2", 10_000);
+        assert!(sessions.track("s1", 1));
+        assert!(sessions.track("s1", 2));
+        assert!(!sessions.track("s3", 3));
+
+        clock.advance(4_000);
+        assert!(sessions.heartbeat("s1"));
+        clock.advance(4_000);
+        assert!(sessions.expire().is_empty());
+        assert!(sessions.heartbeat("s1"));
+
+        // s2没有心跳，超时后不能再续
+        clock.advance(3_000);
+        assert!(!sessions.heartbeat("s2"));
+        let expired = sessions.expire();
+        assert_eq!(vec!["s2"], expired.iter().map(|session| session.id.as_str()).collect::<Vec<&str>>());
+
+        clock.advance(2_001);
+        let expired = sessions.expire();
+        assert_eq!(("u1", vec![1, 2]), (expired[0].owner.as_str(), expired[0].order_ids.clone()));
+        assert_eq!(0, sessions.len());
+    }
+
+    #[test]
+    fn reconnect_keeps_orders() {
+        let clock = ManualClock::new(0);
+        let mut sessions = Sessions::new(&clock);
+        sessions.open("s1", "u1", 1_000);
+        sessions.track("s1", 1);
+        sessions.track("s1", 2);
+        sessions.retain_orders("s1", |order_id| order_id != 1);
+
+        clock.advance(900);
+        assert!(sessions.open("s1", "u1", 2_000));
+        // 别的用户不能接管这个会话
+        assert!(!sessions.open("s1", "u2", 5_000));
+        assert_eq!(Some("u1"), sessions.get("s1").map(|session| session.owner.as_str()));
+        clock.advance(1_500);
+        assert_eq!(Some(vec![2]), sessions.get("s1").map(|session| session.order_ids.clone()));
+        assert_eq!(vec![2], sessions.close("s1").unwrap().order_ids);
+        assert!(sessions.get("s1").is_none());
+    }
+}
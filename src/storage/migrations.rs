@@ -0,0 +1,150 @@
+// 编译进程序的数据库迁移，每种数据库一套，版本号从1开始连续递增
+// 已经执行过的版本记录在schema_migrations表里，migrate只执行还没执行过的版本
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// 价格和数量在数据库里保留的小数位数，市场的price_decimals/volume_decimals不能超过它
+pub const DECIMALS: u32 = 16;
+
+pub const MYSQL: &[Migration] = &[
+    Migration { version: 1, name: "create_orders", sql: include_str!("../../migrations/mysql/0001_create_orders.sql") },
+    Migration { version: 2, name: "create_trades", sql: include_str!("../../migrations/mysql/0002_create_trades.sql") },
+    Migration { version: 3, name: "create_accounts", sql: include_str!("../../migrations/mysql/0003_create_accounts.sql") },
+    Migration { version: 4, name: "add_trade_fees", sql: include_str!("../../migrations/mysql/0004_add_trade_fees.sql") },
+    Migration { version: 5, name: "add_trade_seq", sql: include_str!("../../migrations/mysql/0005_add_trade_seq.sql") },
+    Migration { version: 6, name: "create_candles", sql: include_str!("../../migrations/mysql/0006_create_candles.sql") },
+    Migration { version: 7, name: "add_orders_user_index", sql: include_str!("../../migrations/mysql/0007_add_orders_user_index.sql") },
+    Migration { version: 8, name: "create_market_states", sql: include_str!("../../migrations/mysql/0008_create_market_states.sql") },
+    Migration { version: 9, name: "add_order_condition", sql: include_str!("../../migrations/mysql/0009_add_order_condition.sql") },
+    Migration { version: 10, name: "add_order_peg", sql: include_str!("../../migrations/mysql/0010_add_order_peg.sql") },
+    Migration { version: 11, name: "add_order_groups", sql: include_str!("../../migrations/mysql/0011_add_order_groups.sql") },
+];
+
+#[cfg(feature = "postgres")]
+pub const POSTGRES: &[Migration] = &[
+    Migration { version: 1, name: "create_orders", sql: include_str!("../../migrations/postgres/0001_create_orders.sql") },
+    Migration { version: 2, name: "create_trades", sql: include_str!("../../migrations/postgres/0002_create_trades.sql") },
+    Migration { version: 3, name: "create_accounts", sql: include_str!("../../migrations/postgres/0003_create_accounts.sql") },
+    Migration { version: 4, name: "add_trade_fees", sql: include_str!("../../migrations/postgres/0004_add_trade_fees.sql") },
+    Migration { version: 5, name: "add_trade_seq", sql: include_str!("../../migrations/postgres/0005_add_trade_seq.sql") },
+    Migration { version: 6, name: "create_candles", sql: include_str!("../../migrations/postgres/0006_create_candles.sql") },
+    Migration { version: 7, name: "add_orders_user_index", sql: include_str!("../../migrations/postgres/0007_add_orders_user_index.sql") },
+    Migration { version: 8, name: "create_market_states", sql: include_str!("../../migrations/postgres/0008_create_market_states.sql") },
+    Migration { version: 9, name: "add_order_condition", sql: include_str!("../../migrations/postgres/0009_add_order_condition.sql") },
+    Migration { version: 10, name: "add_order_peg", sql: include_str!("../../migrations/postgres/0010_add_order_peg.sql") },
+    Migration { version: 11, name: "add_order_groups", sql: include_str!("../../migrations/postgres/0011_add_order_groups.sql") },
+];
+
+#[cfg(feature = "sqlite")]
+pub const SQLITE: &[Migration] = &[
+    Migration { version: 1, name: "create_orders", sql: include_str!("../../migrations/sqlite/0001_create_orders.sql") },
+    Migration { version: 2, name: "create_trades", sql: include_str!("../../migrations/sqlite/0002_create_trades.sql") },
+    Migration { version: 3, name: "create_accounts", sql: include_str!("../../migrations/sqlite/0003_create_accounts.sql") },
+    Migration { version: 4, name: "add_trade_fees", sql: include_str!("../../migrations/sqlite/0004_add_trade_fees.sql") },
+    Migration { version: 5, name: "add_trade_seq", sql: include_str!("../../migrations/sqlite/0005_add_trade_seq.sql") },
+    Migration { version: 6, name: "create_candles", sql: include_str!("../../migrations/sqlite/0006_create_candles.sql") },
+    Migration { version: 7, name: "add_orders_user_index", sql: include_str!("../../migrations/sqlite/0007_add_orders_user_index.sql") },
+    Migration { version: 8, name: "create_market_states", sql: include_str!("../../migrations/sqlite/0008_create_market_states.sql") },
+    Migration { version: 9, name: "add_order_condition", sql: include_str!("../../migrations/sqlite/0009_add_order_condition.sql") },
+    Migration { version: 10, name: "add_order_peg", sql: include_str!("../../migrations/sqlite/0010_add_order_peg.sql") },
+    Migration { version: 11, name: "add_order_groups", sql: include_str!("../../migrations/sqlite/0011_add_order_groups.sql") },
+];
+
+// 按版本号排序的、还没有执行过的迁移
+pub fn pending<'a>(migrations: &'a [Migration], applied: &[u32]) -> Vec<&'a Migration> {
+    let mut pending = migrations.iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .collect::<Vec<&Migration>>();
+    pending.sort_by_key(|migration| migration.version);
+    pending
+}
+
+// 把一个迁移文件拆成单条语句，去掉注释行
+// 预处理语句一次只能执行一条
+pub fn statements(sql: &str) -> Vec<String> {
+    let sql = sql.lines()
+        .filter(|line| !line.trim_start().starts_with("--"))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    sql.split(';')
+        .map(|statement| statement.trim().to_string())
+        .filter(|statement| !statement.is_empty())
+        .collect()
+}
+
+// MySQL的ALTER TABLE ... ADD不能加IF NOT EXISTS，中途失败后重新执行时要跳过已经加过的列和索引
+// 每条ALTER TABLE语句只加一个列或索引，返回(表名, 列名或索引名, 是否是索引)，其它语句返回None
+pub fn addition(statement: &str) -> Option<(String, String, bool)> {
+    let words = statement.split_whitespace().map(|word| word.to_uppercase()).collect::<Vec<String>>();
+    if words.len() < 6 || words[0] != "ALTER" || words[1] != "TABLE" || words[3] != "ADD" || words.iter().filter(|word| *word == "ADD").count() > 1 {
+        return None;
+    }
+    let original = statement.split_whitespace().collect::<Vec<&str>>();
+    let name = |i: usize| original[i].trim_matches('`').to_string();
+    match words[4].as_str() {
+        "COLUMN" => Some((name(2), name(5), false)),
+        "KEY" | "INDEX" => Some((name(2), name(5), true)),
+        "UNIQUE" if words.len() > 6 && (words[5] == "KEY" || words[5] == "INDEX") => Some((name(2), name(6), true)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{addition, pending, statements, Migration, MYSQL};
+
+    #[test]
+    fn versions_are_consecutive() {
+        let check = |migrations: &[Migration]| {
+            for (i, migration) in migrations.iter().enumerate() {
+                assert_eq!(i as u32 + 1, migration.version);
+                assert_eq!(MYSQL[i].name, migration.name);
+            }
+        };
+        check(MYSQL);
+        #[cfg(feature = "postgres")]
+        check(super::POSTGRES);
+        #[cfg(feature = "sqlite")]
+        check(super::SQLITE);
+    }
+
+    #[test]
+    fn can_find_pending_migrations() {
+        let migrations = [
+            Migration { version: 2, name: "b", sql: "" },
+            Migration { version: 1, name: "a", sql: "" },
+            Migration { version: 3, name: "c", sql: "" },
+        ];
+        let versions = |applied: &[u32]| pending(&migrations, applied).iter().map(|migration| migration.version).collect::<Vec<u32>>();
+        assert_eq!(vec![1, 2, 3], versions(&[]));
+        assert_eq!(vec![3], versions(&[1, 2]));
+        assert!(versions(&[1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn can_split_statements() {
+        let sql = "-- comment; not a statement\nCREATE TABLE a (id INT);\n\nCREATE INDEX i ON a (id);\n";
+        assert_eq!(vec!["CREATE TABLE a (id INT)", "CREATE INDEX i ON a (id)"], statements(sql));
+        assert_eq!(1, statements(MYSQL[0].sql).len());
+    }
+
+    #[test]
+    fn can_find_additions() {
+        let column = "ALTER TABLE trades ADD COLUMN ask_fee DECIMAL(32,16) NOT NULL DEFAULT 0";
+        assert_eq!(Some(("trades".to_string(), "ask_fee".to_string(), false)), addition(column));
+        let index = "ALTER TABLE trades ADD UNIQUE KEY index_trades_on_market_and_seq (market, seq)";
+        assert_eq!(Some(("trades".to_string(), "index_trades_on_market_and_seq".to_string(), true)), addition(index));
+        assert_eq!(None, addition("ALTER TABLE trades ADD COLUMN a INT, ADD COLUMN b INT"));
+        assert_eq!(None, addition("UPDATE trades SET seq = id"));
+
+        // MySQL的迁移里每条ALTER TABLE都要能跳过
+        for migration in MYSQL {
+            for statement in statements(migration.sql) {
+                assert!(!statement.to_uppercase().starts_with("ALTER") || addition(&statement).is_some(), "{}", statement);
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::error::Error;
+use mysql::IsolationLevel;
+use mysql::Pool;
+
+use crate::accounts;
+use crate::accounts::Change;
+use crate::candles::Candle;
+use crate::errors::InsufficientBalance;
+use crate::engine::Condition;
+use crate::engine::Peg;
+use crate::engine::MarketState;
+use crate::market::Market;
+use crate::models::Account;
+use crate::models::Order;
+use crate::models::Trade;
+use crate::models::save_candles;
+use crate::models::find_market_state;
+use crate::models::save_market_state;
+use crate::models::save_bracket_exits;
+use crate::models::find_bracket_exits;
+use crate::models::delete_bracket_exits;
+use crate::persister::Batch;
+use super::OrderQuery;
+use super::Storage;
+use super::migrations;
+
+// 原来的models::Order/models::Trade实现
+pub struct MysqlStorage {
+    pool: Pool,
+}
+
+impl MysqlStorage {
+    pub fn connect(url: &str) -> Result<MysqlStorage, Box<dyn Error>> {
+        Ok(MysqlStorage { pool: Pool::new(url)? })
+    }
+
+    // 当前库的表里是否已经有这个列或索引
+    fn schema_has<T>(conn: &mut T, table: &str, name: &str, index: bool) -> mysql::Result<bool>
+    where T: mysql::prelude::GenericConnection
+    {
+        let sql = if index {
+            "SELECT COUNT(*) FROM information_schema.statistics WHERE table_schema=DATABASE() AND table_name=? AND index_name=?"
+        } else {
+            "SELECT COUNT(*) FROM information_schema.columns WHERE table_schema=DATABASE() AND table_name=? AND column_name=?"
+        };
+        let mut result = conn.prep_exec(sql, (table, name))?;
+        match result.next() {
+            Some(row) => Ok(mysql::from_row::<u64>(row?) > 0),
+            None => Ok(false),
+        }
+    }
+
+    // 新建时冻结资金，由调用者提交事务
+    fn create_order_in<T>(conn: &mut T, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, condition: &Condition, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str, freeze: &Change) -> Result<(u64, bool), Box<dyn Error>>
+    where T: mysql::prelude::GenericConnection
+    {
+        let (id, created) = Order::create(conn, client_order_id, market, price, volume, side, super::fill_condition(condition), super::peg_columns(peg), created_by)?;
+        if created {
+            MysqlStorage::change_balance_in(conn, freeze)?;
+            if let Some(exits) = exits {
+                save_bracket_exits(conn, id, market, exits)?;
+            }
+        }
+        Ok((id, created))
+    }
+
+    fn change_balance_in<T>(conn: &mut T, change: &Change) -> Result<(), Box<dyn Error>>
+    where T: mysql::prelude::GenericConnection
+    {
+        if Account::change(conn, &change.user_id, &change.asset, &change.available.to_string(), &change.frozen.to_string(), change.overdraft)? {
+            Ok(())
+        } else {
+            Err(Box::new(InsufficientBalance {
+                user_id: change.user_id.clone(),
+                asset: change.asset.clone(),
+                amount: -change.available.clone(),
+            }))
+        }
+    }
+}
+
+impl Storage for MysqlStorage {
+    fn migrate(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        conn.query(r"CREATE TABLE IF NOT EXISTS schema_migrations (
+                        version INT UNSIGNED NOT NULL PRIMARY KEY,
+                        name VARCHAR(64) NOT NULL,
+                        applied_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+                      )")?;
+        let applied = conn.query("SELECT version FROM schema_migrations")?
+            .map(|row| row.map(mysql::from_row))
+            .collect::<mysql::Result<Vec<u32>>>()?;
+
+        let mut versions = Vec::new();
+        for migration in migrations::pending(migrations::MYSQL, &applied) {
+            // MySQL的DDL会隐式提交，不能放进事务；中途失败后重新migrate时整个迁移再执行一遍:
+            // CREATE TABLE用IF NOT EXISTS，UPDATE可以重复执行，已经加过的列和索引跳过
+            for statement in migrations::statements(migration.sql) {
+                if let Some((table, name, index)) = migrations::addition(&statement) {
+                    if MysqlStorage::schema_has(&mut conn, &table, &name, index)? {
+                        continue;
+                    }
+                }
+                conn.query(statement)?;
+            }
+            conn.prep_exec("INSERT INTO schema_migrations (version, name) VALUES (?, ?)", (migration.version, migration.name))?;
+            versions.push(migration.version);
+        }
+        Ok(versions)
+    }
+
+    fn create_order(&self, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, condition: &Condition, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str, freeze: &Change) -> Result<(u64, bool), Box<dyn Error>> {
+        let mut tx = self.pool.start_transaction(false, Some(IsolationLevel::RepeatableRead), Some(false))?;
+        // 余额不足时事务回滚，订单不会留下
+        let result = MysqlStorage::create_order_in(&mut tx, client_order_id, market, price, volume, side, condition, peg, exits, created_by, freeze)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    fn create_orders(&self, market: &str, created_by: &str, orders: &[(&str, f64, f64, u8, Change)]) -> Result<Vec<(u64, bool)>, Box<dyn Error>> {
+        let mut tx = self.pool.start_transaction(false, Some(IsolationLevel::RepeatableRead), Some(false))?;
+        // 余额不足或者只有一部分已经存在时事务回滚，一个订单都不会留下
+        let mut results = Vec::new();
+        for (client_order_id, price, volume, side, freeze) in orders {
+            results.push(MysqlStorage::create_order_in(&mut tx, client_order_id, market, *price, *volume, *side, &Condition::None, None, None, created_by, freeze)?);
+        }
+        super::check_created(created_by, orders, &results)?;
+        if let [(first_id, true), (second_id, true)] = results.as_slice() {
+            Order::link_oco(&mut tx, *first_id, *second_id)?;
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    fn write_batch(&self, market: &Market, batch: &Batch) -> Result<(), Box<dyn Error>> {
+        // TODO: 隔离级别需要调整
+        let mut tx = self.pool.start_transaction(false, Some(IsolationLevel::RepeatableRead), Some(false))?;
+        for (order_id, volume, trades_count, filled) in batch.order_fills() {
+            Order::sub_volumes(&mut tx, order_id, volume, trades_count, filled)?;
+        }
+        for (order_id, volume) in &batch.reductions {
+            Order::reduce_volume(&mut tx, *order_id, *volume)?;
+        }
+        for order_id in &batch.cancels {
+            Order::set_canceled(&mut tx, *order_id)?;
+            delete_bracket_exits(&mut tx, *order_id)?;
+        }
+
+        // 成交、手续费和余额结算和上面的写入在同一个事务里
+        let orders = Order::find_for_update(&mut tx, &batch.order_ids())?
+            .into_iter()
+            .map(|order| (order.id, order))
+            .collect::<HashMap<u64, Order>>();
+        let (changes, fees) = accounts::batch_changes(market, batch, &orders)?;
+        let trades = batch.trades.iter().zip(fees.iter())
+            .map(|(event, fees)| (event.id, event.price, event.volume, super::trend(event), event.ask_order_id, event.bid_order_id, fees.ask.to_string(), fees.bid.to_string()))
+            .collect::<Vec<(u64, f64, f64, u16, u64, u64, String, String)>>();
+        Trade::create_batch(&mut tx, &market.name, &trades)?;
+        for change in changes {
+            MysqlStorage::change_balance_in(&mut tx, &change)?;
+        }
+        save_candles(&mut tx, &batch.candles)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_candles(&self, candles: &[Candle]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(save_candles(&mut conn, candles)?)
+    }
+
+    fn find_trades(&self, market: &str, since: i64) -> Result<Vec<(f64, f64, i64)>, Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(Trade::find_since(&mut conn, market, since)?)
+    }
+
+    fn change_balance(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        let mut tx = self.pool.start_transaction(false, Some(IsolationLevel::RepeatableRead), Some(false))?;
+        MysqlStorage::change_balance_in(&mut tx, change)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn balance(&self, user_id: &str, asset: &str) -> Result<(f64, f64), Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(Account::find(&mut conn, user_id, asset)?.map_or((0.0, 0.0), |account| (account.available, account.frozen)))
+    }
+
+    fn find_open_orders(&self, market: &str) -> Result<Vec<Order>, Box<dyn Error>> {
+        Ok(Order::find_open(&self.pool, market)?)
+    }
+
+    fn open_totals(&self, market: &str) -> Result<Vec<(u8, u64, f64)>, Box<dyn Error>> {
+        Ok(Order::open_totals(&self.pool, market)?)
+    }
+
+    fn last_trade_id(&self, market: &str) -> Result<u64, Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(Trade::last_seq(&mut conn, market)?)
+    }
+
+    fn market_state(&self, market: &str) -> Result<Option<MarketState>, Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        match find_market_state(&mut conn, market)? {
+            Some(name) => Ok(Some(super::parse_market_state(&name)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_market_state(&self, market: &str, state: MarketState) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(save_market_state(&mut conn, market, state.name())?)
+    }
+
+    fn find_order(&self, id: u64) -> Result<Option<Order>, Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(Order::find_by_id(&mut conn, id)?)
+    }
+
+    fn find_orders(&self, query: &OrderQuery) -> Result<Vec<Order>, Box<dyn Error>> {
+        Ok(Order::find_by_query(&self.pool, query)?)
+    }
+
+    fn find_bracket_exits(&self, market: &str) -> Result<Vec<(u64, [(f64, f64, u8); 2])>, Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(find_bracket_exits(&mut conn, market)?)
+    }
+
+    fn delete_bracket_exits(&self, entry_id: u64) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.pool.get_conn()?;
+        Ok(delete_bracket_exits(&mut conn, entry_id)?)
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use postgres::Client;
+use postgres::NoTls;
+use postgres::Row;
+use postgres::Transaction;
+use postgres::types::ToSql;
+
+use crate::accounts;
+use crate::accounts::Change;
+use crate::candles::Candle;
+use crate::errors::InsufficientBalance;
+use crate::engine::Condition;
+use crate::engine::Peg;
+use crate::engine::MarketState;
+use crate::market::Market;
+use crate::models::Order;
+use crate::models::WAIT;
+use crate::models::DONE;
+use crate::models::CANCEL;
+use crate::persister::Batch;
+use super::OrderQuery;
+use super::QueryParam;
+use super::Storage;
+use super::migrations;
+
+const COLUMNS: &str = "id, client_order_id, market, price::FLOAT8, volume::FLOAT8, origin_volume::FLOAT8, state, side, trades_count, created_by, created_at, updated_at, fill_condition, peg_reference, peg_offset::FLOAT8, oco_id";
+
+// 数量和价格以文本传给数据库再转成NUMERIC
+pub struct PostgresStorage {
+    client: Mutex<Client>,
+}
+
+impl PostgresStorage {
+    pub fn connect(url: &str) -> Result<PostgresStorage, Box<dyn Error>> {
+        Ok(PostgresStorage { client: Mutex::new(Client::connect(url, NoTls)?) })
+    }
+
+    // 可用或冻结余额会变成负数时不修改，overdraft的可用余额除外
+    fn change_balance_in(tx: &mut Transaction, change: &Change) -> Result<(), Box<dyn Error>> {
+        tx.execute(
+            "INSERT INTO accounts (user_id, asset) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&change.user_id, &change.asset]
+        )?;
+        let updated = tx.execute(
+            r"UPDATE accounts
+              SET available=available+$3::TEXT::NUMERIC(32,16), frozen=frozen+$4::TEXT::NUMERIC(32,16), updated_at=now()
+              WHERE user_id=$1 AND asset=$2
+                AND ($5 OR available+$3::TEXT::NUMERIC(32,16)>=0) AND frozen+$4::TEXT::NUMERIC(32,16)>=0",
+            &[&change.user_id, &change.asset, &change.available.to_string(), &change.frozen.to_string(), &change.overdraft]
+        )?;
+        if updated == 1 {
+            Ok(())
+        } else {
+            Err(Box::new(InsufficientBalance {
+                user_id: change.user_id.clone(),
+                asset: change.asset.clone(),
+                amount: -change.available.clone(),
+            }))
+        }
+    }
+
+    // 唯一索引冲突时不插入也不报错，再查出已有的订单；新建时冻结资金，由调用者提交事务
+    fn create_order_in(tx: &mut Transaction, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, condition: &Condition, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str, freeze: &Change) -> Result<(u64, bool), Box<dyn Error>> {
+        let (peg_reference, peg_offset) = super::peg_columns(peg);
+        let inserted = tx.query_opt(
+            r"INSERT INTO orders
+                (client_order_id, market, price, volume, origin_volume, state, side, fill_condition, peg_reference, peg_offset, created_by)
+              VALUES
+                ($1, $2, $3::TEXT::NUMERIC(32,16), $4::TEXT::NUMERIC(32,16), $4::TEXT::NUMERIC(32,16), $5, $6, $7, $8, $9::TEXT::NUMERIC(32,16), $10)
+              ON CONFLICT (created_by, client_order_id) DO NOTHING
+              RETURNING id",
+            &[&client_order_id, &market, &price.to_string(), &volume.to_string(), &(WAIT as i16), &(side as i16), &super::fill_condition(condition),
+              &peg_reference, &peg_offset.map(|offset| offset.to_string()), &created_by]
+        )?;
+        let (id, created) = match inserted {
+            Some(row) => {
+                let id: i64 = row.get(0);
+                PostgresStorage::change_balance_in(tx, freeze)?;
+                if let Some(exits) = exits {
+                    tx.execute(
+                        r"INSERT INTO bracket_exits (entry_id, market, first_price, first_volume, first_side, second_price, second_volume, second_side)
+                          VALUES ($1, $2, $3::TEXT::NUMERIC(32,16), $4::TEXT::NUMERIC(32,16), $5, $6::TEXT::NUMERIC(32,16), $7::TEXT::NUMERIC(32,16), $8)",
+                        &[&id, &market, &exits[0].0.to_string(), &exits[0].1.to_string(), &(exits[0].2 as i16),
+                          &exits[1].0.to_string(), &exits[1].1.to_string(), &(exits[1].2 as i16)]
+                    )?;
+                }
+                (id as u64, true)
+            },
+            None => {
+                let row = tx.query_one(
+                    "SELECT id FROM orders WHERE created_by=$1 AND client_order_id=$2",
+                    &[&created_by, &client_order_id]
+                )?;
+                (row.get::<_, i64>(0) as u64, false)
+            }
+        };
+        Ok((id, created))
+    }
+
+    fn save_candles_in(tx: &mut Transaction, candles: &[Candle]) -> Result<(), Box<dyn Error>> {
+        for candle in candles {
+            tx.execute(
+                r"INSERT INTO candles (market, period, time, open, high, low, close, volume, funds, trades_count)
+                  VALUES ($1, $2, $3, $4::TEXT::NUMERIC(32,16), $5::TEXT::NUMERIC(32,16), $6::TEXT::NUMERIC(32,16), $7::TEXT::NUMERIC(32,16),
+                    $8::TEXT::NUMERIC(32,16), $9::TEXT::NUMERIC(32,16), $10)
+                  ON CONFLICT (market, period, time) DO UPDATE SET open=EXCLUDED.open, high=EXCLUDED.high, low=EXCLUDED.low,
+                    close=EXCLUDED.close, volume=EXCLUDED.volume, funds=EXCLUDED.funds, trades_count=EXCLUDED.trades_count, updated_at=now()",
+                &[&candle.market, &(candle.period as i32), &candle.time,
+                  &candle.open.to_string(), &candle.high.to_string(), &candle.low.to_string(), &candle.close.to_string(),
+                  &candle.volume.to_string(), &candle.funds.to_string(), &(candle.trades_count as i32)]
+            )?;
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &Row) -> Order {
+        Order {
+            id: row.get::<_, i64>(0) as u64,
+            client_order_id: row.get(1),
+            market: row.get(2),
+            price: row.get(3),
+            volume: row.get(4),
+            origin_volume: row.get(5),
+            state: row.get::<_, i16>(6) as u16,
+            side: row.get::<_, i16>(7) as u8,
+            trades_count: row.get::<_, i32>(8) as u16,
+            created_by: row.get(9),
+            created_at: row.get(10),
+            updated_at: row.get(11),
+            fill_condition: row.get(12),
+            peg_reference: row.get(13),
+            peg_offset: row.get(14),
+            oco_id: row.get::<_, Option<i64>>(15).map(|id| id as u64),
+        }
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn migrate(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        client.batch_execute(r"CREATE TABLE IF NOT EXISTS schema_migrations (
+                                 version INTEGER PRIMARY KEY,
+                                 name VARCHAR(64) NOT NULL,
+                                 applied_at TIMESTAMP NOT NULL DEFAULT now()
+                               )")?;
+        let applied = client.query("SELECT version FROM schema_migrations", &[])?
+            .iter()
+            .map(|row| row.get::<_, i32>(0) as u32)
+            .collect::<Vec<u32>>();
+
+        let mut versions = Vec::new();
+        // 每个迁移和它的版本记录在同一个事务里
+        for migration in migrations::pending(migrations::POSTGRES, &applied) {
+            let mut tx = client.transaction()?;
+            tx.batch_execute(migration.sql)?;
+            tx.execute("INSERT INTO schema_migrations (version, name) VALUES ($1, $2)", &[&(migration.version as i32), &migration.name])?;
+            tx.commit()?;
+            versions.push(migration.version);
+        }
+        Ok(versions)
+    }
+
+    fn create_order(&self, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, condition: &Condition, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str, freeze: &Change) -> Result<(u64, bool), Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        let mut tx = client.transaction()?;
+        // 余额不足时事务回滚，订单不会留下
+        let result = PostgresStorage::create_order_in(&mut tx, client_order_id, market, price, volume, side, condition, peg, exits, created_by, freeze)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    fn create_orders(&self, market: &str, created_by: &str, orders: &[(&str, f64, f64, u8, Change)]) -> Result<Vec<(u64, bool)>, Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        let mut tx = client.transaction()?;
+        // 余额不足或者只有一部分已经存在时事务回滚，一个订单都不会留下
+        let mut results = Vec::new();
+        for (client_order_id, price, volume, side, freeze) in orders {
+            results.push(PostgresStorage::create_order_in(&mut tx, client_order_id, market, *price, *volume, *side, &Condition::None, None, None, created_by, freeze)?);
+        }
+        super::check_created(created_by, orders, &results)?;
+        if let [(first_id, true), (second_id, true)] = results.as_slice() {
+            tx.execute("UPDATE orders SET oco_id=$1 WHERE id=$2", &[&(*second_id as i64), &(*first_id as i64)])?;
+            tx.execute("UPDATE orders SET oco_id=$1 WHERE id=$2", &[&(*first_id as i64), &(*second_id as i64)])?;
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    fn write_batch(&self, market: &Market, batch: &Batch) -> Result<(), Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        let mut tx = client.transaction()?;
+        for (order_id, volume, trades_count, filled) in batch.order_fills() {
+            let state = if filled { DONE } else { WAIT };
+            tx.execute(
+                r"UPDATE orders SET trades_count=trades_count+$1, volume=volume-$2::TEXT::NUMERIC(32,16), state=$3, updated_at=now() WHERE id=$4",
+                &[&(trades_count as i32), &volume.to_string(), &(state as i16), &(order_id as i64)]
+            )?;
+        }
+        for (order_id, volume) in &batch.reductions {
+            tx.execute(
+                "UPDATE orders SET volume=volume-$1::TEXT::NUMERIC(32,16), updated_at=now() WHERE id=$2",
+                &[&volume.to_string(), &(*order_id as i64)]
+            )?;
+        }
+        for order_id in &batch.cancels {
+            tx.execute(
+                "UPDATE orders SET state=$1, updated_at=now() WHERE id=$2",
+                &[&(CANCEL as i16), &(*order_id as i64)]
+            )?;
+            tx.execute("DELETE FROM bracket_exits WHERE entry_id=$1", &[&(*order_id as i64)])?;
+        }
+
+        // 成交、手续费和余额结算和上面的写入在同一个事务里
+        let ids = batch.order_ids().iter().map(|id| *id as i64).collect::<Vec<i64>>();
+        let orders = tx.query(format!("SELECT {} FROM orders WHERE id = ANY($1) FOR UPDATE", COLUMNS).as_str(), &[&ids])?
+            .iter()
+            .map(|row| {
+                let order = PostgresStorage::from_row(row);
+                (order.id, order)
+            })
+            .collect::<HashMap<u64, Order>>();
+        let (changes, fees) = accounts::batch_changes(market, batch, &orders)?;
+        for (trade, fees) in batch.trades.iter().zip(fees.iter()) {
+            tx.execute(
+                r"INSERT INTO trades (price, volume, ask_order_id, bid_order_id, ask_fee, bid_fee, market, seq, trend)
+                  VALUES ($1::TEXT::NUMERIC(32,16), $2::TEXT::NUMERIC(32,16), $3, $4, $5::TEXT::NUMERIC(32,16), $6::TEXT::NUMERIC(32,16), $7, $8, $9)",
+                &[&trade.price.to_string(), &trade.volume.to_string(), &(trade.ask_order_id as i64), &(trade.bid_order_id as i64), &fees.ask.to_string(), &fees.bid.to_string(),
+                  &market.name, &(trade.id as i64), &(super::trend(trade) as i16)]
+            )?;
+        }
+        for change in changes {
+            PostgresStorage::change_balance_in(&mut tx, &change)?;
+        }
+        PostgresStorage::save_candles_in(&mut tx, &batch.candles)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_candles(&self, candles: &[Candle]) -> Result<(), Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        let mut tx = client.transaction()?;
+        PostgresStorage::save_candles_in(&mut tx, candles)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // created_at是不带时区的本地时间，按会话的时区换算成unix时间
+    fn find_trades(&self, market: &str, since: i64) -> Result<Vec<(f64, f64, i64)>, Box<dyn Error>> {
+        let rows = self.client.lock().unwrap().query(
+            r"SELECT price::FLOAT8, volume::FLOAT8, EXTRACT(EPOCH FROM created_at::TIMESTAMPTZ)::BIGINT FROM trades
+              WHERE market=$1 AND created_at::TIMESTAMPTZ>=to_timestamp($2) ORDER BY seq",
+            &[&market, &(since as f64)]
+        )?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1), row.get(2))).collect())
+    }
+
+    fn change_balance(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        let mut tx = client.transaction()?;
+        PostgresStorage::change_balance_in(&mut tx, change)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn balance(&self, user_id: &str, asset: &str) -> Result<(f64, f64), Box<dyn Error>> {
+        let row = self.client.lock().unwrap().query_opt(
+            "SELECT available::FLOAT8, frozen::FLOAT8 FROM accounts WHERE user_id=$1 AND asset=$2",
+            &[&user_id, &asset]
+        )?;
+        Ok(row.map_or((0.0, 0.0), |row| (row.get(0), row.get(1))))
+    }
+
+    fn find_open_orders(&self, market: &str) -> Result<Vec<Order>, Box<dyn Error>> {
+        let rows = self.client.lock().unwrap().query(
+            format!("SELECT {} FROM orders WHERE market=$1 AND state=$2 ORDER BY created_at, id", COLUMNS).as_str(),
+            &[&market, &(WAIT as i16)]
+        )?;
+        Ok(rows.iter().map(PostgresStorage::from_row).collect())
+    }
+
+    fn open_totals(&self, market: &str) -> Result<Vec<(u8, u64, f64)>, Box<dyn Error>> {
+        let rows = self.client.lock().unwrap().query(
+            r"SELECT side, COUNT(*), SUM(volume)::FLOAT8 FROM orders WHERE market=$1 AND state=$2 GROUP BY side",
+            &[&market, &(WAIT as i16)]
+        )?;
+        Ok(rows.iter().map(|row| (row.get::<_, i16>(0) as u8, row.get::<_, i64>(1) as u64, row.get(2))).collect())
+    }
+
+    fn last_trade_id(&self, market: &str) -> Result<u64, Box<dyn Error>> {
+        let row = self.client.lock().unwrap().query_one("SELECT COALESCE(MAX(seq), 0) FROM trades WHERE market=$1", &[&market])?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    fn market_state(&self, market: &str) -> Result<Option<MarketState>, Box<dyn Error>> {
+        let row = self.client.lock().unwrap().query_opt("SELECT state FROM market_states WHERE market=$1", &[&market])?;
+        match row {
+            Some(row) => Ok(Some(super::parse_market_state(row.get(0))?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_market_state(&self, market: &str, state: MarketState) -> Result<(), Box<dyn Error>> {
+        self.client.lock().unwrap().execute(
+            r"INSERT INTO market_states (market, state) VALUES ($1, $2)
+              ON CONFLICT (market) DO UPDATE SET state=$2, updated_at=now()",
+            &[&market, &state.name()]
+        )?;
+        Ok(())
+    }
+
+    fn find_order(&self, id: u64) -> Result<Option<Order>, Box<dyn Error>> {
+        let row = self.client.lock().unwrap().query_opt(
+            format!("SELECT {} FROM orders WHERE id=$1", COLUMNS).as_str(),
+            &[&(id as i64)]
+        )?;
+        Ok(row.as_ref().map(PostgresStorage::from_row))
+    }
+
+    // created_at是不带时区的本地时间，参数按会话的时区换算，这样可以用上索引
+    fn find_orders(&self, query: &OrderQuery) -> Result<Vec<Order>, Box<dyn Error>> {
+        let (sql, params) = query.to_sql(COLUMNS, |i| format!("${}", i), |value| format!("to_timestamp({})::TIMESTAMP", value));
+        let params = params.into_iter().map(|param| -> Box<dyn ToSql + Sync> {
+            match param {
+                QueryParam::Text(text) => Box::new(text),
+                QueryParam::State(state) => Box::new(state as i16),
+                QueryParam::Id(id) => Box::new(id as i64),
+                QueryParam::Time(time) => Box::new(time as f64),
+            }
+        }).collect::<Vec<Box<dyn ToSql + Sync>>>();
+        let params = params.iter().map(|param| param.as_ref()).collect::<Vec<&(dyn ToSql + Sync)>>();
+        let rows = self.client.lock().unwrap().query(sql.as_str(), &params)?;
+        Ok(rows.iter().map(PostgresStorage::from_row).collect())
+    }
+
+    fn find_bracket_exits(&self, market: &str) -> Result<Vec<(u64, [(f64, f64, u8); 2])>, Box<dyn Error>> {
+        let rows = self.client.lock().unwrap().query(
+            r"SELECT entry_id, first_price::FLOAT8, first_volume::FLOAT8, first_side, second_price::FLOAT8, second_volume::FLOAT8, second_side
+              FROM bracket_exits WHERE market=$1 ORDER BY entry_id",
+            &[&market]
+        )?;
+        Ok(rows.iter().map(|row| (row.get::<_, i64>(0) as u64, [
+            (row.get(1), row.get(2), row.get::<_, i16>(3) as u8),
+            (row.get(4), row.get(5), row.get::<_, i16>(6) as u8),
+        ])).collect())
+    }
+
+    fn delete_bracket_exits(&self, entry_id: u64) -> Result<(), Box<dyn Error>> {
+        self.client.lock().unwrap().execute("DELETE FROM bracket_exits WHERE entry_id=$1", &[&(entry_id as i64)])?;
+        Ok(())
+    }
+}
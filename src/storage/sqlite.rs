@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use bigdecimal::BigDecimal;
+use bigdecimal::Zero;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::Row;
+use rusqlite::NO_PARAMS;
+use rusqlite::OptionalExtension;
+use rusqlite::types::Value;
+
+use crate::accounts;
+use crate::accounts::Change;
+use crate::candles::Candle;
+use crate::errors::InsufficientBalance;
+use crate::engine::Condition;
+use crate::engine::Peg;
+use crate::engine::MarketState;
+use crate::market::Market;
+use crate::models::Order;
+use crate::models::WAIT;
+use crate::models::DONE;
+use crate::models::CANCEL;
+use crate::persister::Batch;
+use super::OrderQuery;
+use super::QueryParam;
+use super::Storage;
+use super::migrations;
+
+const COLUMNS: &str = "id, client_order_id, market, price, volume, origin_volume, state, side, trades_count, created_by, created_at, updated_at, fill_condition, peg_reference, peg_offset, oco_id";
+
+// 嵌入式的存储，测试和本地开发不需要数据库服务
+// 订单和成交的数量和价格存成REAL，只适合开发环境；余额是十进制文本，可以精确核对
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    // sqlite://path/to/file.db 或 sqlite::memory:
+    pub fn connect(url: &str) -> Result<SqliteStorage, Box<dyn Error>> {
+        match url {
+            "sqlite::memory:" => SqliteStorage::open_in_memory(),
+            _ => Ok(SqliteStorage { conn: Mutex::new(Connection::open(url.trim_start_matches("sqlite://"))?) }),
+        }
+    }
+
+    // 内存数据库每次都是空的，打开时直接建表
+    pub fn open_in_memory() -> Result<SqliteStorage, Box<dyn Error>> {
+        let storage = SqliteStorage { conn: Mutex::new(Connection::open_in_memory()?) };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    fn find_balance(conn: &Connection, user_id: &str, asset: &str) -> Result<(BigDecimal, BigDecimal), Box<dyn Error>> {
+        let balance = conn.query_row(
+            "SELECT available, frozen FROM accounts WHERE user_id=?1 AND asset=?2",
+            params![user_id, asset],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        ).optional()?;
+        match balance {
+            Some((available, frozen)) => Ok((available.parse::<BigDecimal>()?, frozen.parse::<BigDecimal>()?)),
+            None => Ok((BigDecimal::zero(), BigDecimal::zero())),
+        }
+    }
+
+    // 在程序里计算新的余额，连接被独占，读和写之间不会有别的写入
+    fn change_balance_in(conn: &Connection, change: &Change) -> Result<(), Box<dyn Error>> {
+        let (available, frozen) = SqliteStorage::find_balance(conn, &change.user_id, &change.asset)?;
+        let available = available + change.available.clone();
+        let frozen = frozen + change.frozen.clone();
+        if (available < BigDecimal::zero() && !change.overdraft) || frozen < BigDecimal::zero() {
+            return Err(Box::new(InsufficientBalance {
+                user_id: change.user_id.clone(),
+                asset: change.asset.clone(),
+                amount: -change.available.clone(),
+            }));
+        }
+        conn.execute(
+            r"INSERT INTO accounts (user_id, asset, available, frozen) VALUES (?1, ?2, ?3, ?4)
+              ON CONFLICT (user_id, asset) DO UPDATE SET available=?3, frozen=?4, updated_at=CURRENT_TIMESTAMP",
+            params![change.user_id, change.asset, available.to_string(), frozen.to_string()]
+        )?;
+        Ok(())
+    }
+
+    // 唯一索引冲突时忽略，再查出已有的订单；新建时冻结资金，由调用者提交事务
+    fn create_order_in(tx: &Connection, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, condition: &Condition, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str, freeze: &Change) -> Result<(u64, bool), Box<dyn Error>> {
+        let (peg_reference, peg_offset) = super::peg_columns(peg);
+        let inserted = tx.execute(
+            r"INSERT OR IGNORE INTO orders
+                (client_order_id, market, price, volume, origin_volume, state, side, fill_condition, peg_reference, peg_offset, created_by)
+              VALUES
+                (?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![client_order_id, market, price, volume, WAIT as i64, side as i64, super::fill_condition(condition), peg_reference, peg_offset, created_by]
+        )?;
+        if inserted == 1 {
+            let id = tx.last_insert_rowid() as u64;
+            SqliteStorage::change_balance_in(tx, freeze)?;
+            if let Some(exits) = exits {
+                tx.execute(
+                    r"INSERT INTO bracket_exits (entry_id, market, first_price, first_volume, first_side, second_price, second_volume, second_side)
+                      VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![id as i64, market, exits[0].0, exits[0].1, exits[0].2 as i64, exits[1].0, exits[1].1, exits[1].2 as i64]
+                )?;
+            }
+            return Ok((id, true));
+        }
+
+        let id: i64 = tx.query_row(
+            "SELECT id FROM orders WHERE created_by=?1 AND client_order_id=?2",
+            params![created_by, client_order_id],
+            |row| row.get(0)
+        )?;
+        Ok((id as u64, false))
+    }
+
+    fn save_candles_in(conn: &Connection, candles: &[Candle]) -> Result<(), Box<dyn Error>> {
+        for candle in candles {
+            conn.execute(
+                r"INSERT INTO candles (market, period, time, open, high, low, close, volume, funds, trades_count)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                  ON CONFLICT (market, period, time) DO UPDATE SET open=?4, high=?5, low=?6, close=?7, volume=?8, funds=?9,
+                    trades_count=?10, updated_at=CURRENT_TIMESTAMP",
+                params![candle.market, candle.period as i64, candle.time, candle.open, candle.high, candle.low, candle.close,
+                        candle.volume, candle.funds, candle.trades_count as i64]
+            )?;
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Order> {
+        Ok(Order {
+            id: row.get::<_, i64>(0)? as u64,
+            client_order_id: row.get(1)?,
+            market: row.get(2)?,
+            price: row.get(3)?,
+            volume: row.get(4)?,
+            origin_volume: row.get(5)?,
+            state: row.get::<_, i64>(6)? as u16,
+            side: row.get::<_, i64>(7)? as u8,
+            trades_count: row.get::<_, i64>(8)? as u16,
+            created_by: row.get(9)?,
+            created_at: row.get(10)?,
+            updated_at: row.get(11)?,
+            fill_condition: row.get(12)?,
+            peg_reference: row.get(13)?,
+            peg_offset: row.get(14)?,
+            oco_id: row.get::<_, Option<i64>>(15)?.map(|id| id as u64),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn migrate(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.execute_batch(r"CREATE TABLE IF NOT EXISTS schema_migrations (
+                               version INTEGER PRIMARY KEY,
+                               name TEXT NOT NULL,
+                               applied_at TEXT DEFAULT CURRENT_TIMESTAMP
+                             )")?;
+        let applied = {
+            let mut stmt = conn.prepare("SELECT version FROM schema_migrations")?;
+            let versions = stmt.query_map(NO_PARAMS, |row| row.get::<_, i64>(0))?
+                .collect::<rusqlite::Result<Vec<i64>>>()?;
+            versions.into_iter().map(|version| version as u32).collect::<Vec<u32>>()
+        };
+
+        let mut versions = Vec::new();
+        // 每个迁移和它的版本记录在同一个事务里
+        for migration in migrations::pending(migrations::SQLITE, &applied) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration.sql)?;
+            tx.execute("INSERT INTO schema_migrations (version, name) VALUES (?1, ?2)", params![migration.version as i64, migration.name])?;
+            tx.commit()?;
+            versions.push(migration.version);
+        }
+        Ok(versions)
+    }
+
+    fn create_order(&self, client_order_id: &str, market: &str, price: f64, volume: f64, side: u8, condition: &Condition, peg: Option<&Peg>, exits: Option<&[(f64, f64, u8); 2]>, created_by: &str, freeze: &Change) -> Result<(u64, bool), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        // 余额不足时事务回滚，订单不会留下
+        let result = SqliteStorage::create_order_in(&tx, client_order_id, market, price, volume, side, condition, peg, exits, created_by, freeze)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    fn create_orders(&self, market: &str, created_by: &str, orders: &[(&str, f64, f64, u8, Change)]) -> Result<Vec<(u64, bool)>, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        // 余额不足或者只有一部分已经存在时事务回滚，一个订单都不会留下
+        let mut results = Vec::new();
+        for (client_order_id, price, volume, side, freeze) in orders {
+            results.push(SqliteStorage::create_order_in(&tx, client_order_id, market, *price, *volume, *side, &Condition::None, None, None, created_by, freeze)?);
+        }
+        super::check_created(created_by, orders, &results)?;
+        if let [(first_id, true), (second_id, true)] = results.as_slice() {
+            tx.execute("UPDATE orders SET oco_id=?1 WHERE id=?2", params![*second_id as i64, *first_id as i64])?;
+            tx.execute("UPDATE orders SET oco_id=?1 WHERE id=?2", params![*first_id as i64, *second_id as i64])?;
+        }
+        tx.commit()?;
+        Ok(results)
+    }
+
+    fn write_batch(&self, market: &Market, batch: &Batch) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        for (order_id, volume, trades_count, filled) in batch.order_fills() {
+            let state = if filled { DONE } else { WAIT };
+            tx.execute(
+                "UPDATE orders SET trades_count=trades_count+?1, volume=volume-?2, state=?3, updated_at=CURRENT_TIMESTAMP WHERE id=?4",
+                params![trades_count as i64, volume, state as i64, order_id as i64]
+            )?;
+        }
+        for (order_id, volume) in &batch.reductions {
+            tx.execute(
+                "UPDATE orders SET volume=volume-?1, updated_at=CURRENT_TIMESTAMP WHERE id=?2",
+                params![volume, *order_id as i64]
+            )?;
+        }
+        for order_id in &batch.cancels {
+            tx.execute(
+                "UPDATE orders SET state=?1, updated_at=CURRENT_TIMESTAMP WHERE id=?2",
+                params![CANCEL as i64, *order_id as i64]
+            )?;
+            tx.execute("DELETE FROM bracket_exits WHERE entry_id=?1", params![*order_id as i64])?;
+        }
+
+        // 成交、手续费和余额结算和上面的写入在同一个事务里
+        let mut orders = HashMap::new();
+        for id in batch.order_ids() {
+            let order = tx.query_row(
+                &format!("SELECT {} FROM orders WHERE id=?1", COLUMNS),
+                params![id as i64],
+                SqliteStorage::from_row
+            )?;
+            orders.insert(id, order);
+        }
+        let (changes, fees) = accounts::batch_changes(market, batch, &orders)?;
+        for (trade, fees) in batch.trades.iter().zip(fees.iter()) {
+            tx.execute(
+                "INSERT INTO trades (price, volume, ask_order_id, bid_order_id, ask_fee, bid_fee, market, seq, trend) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![trade.price, trade.volume, trade.ask_order_id as i64, trade.bid_order_id as i64, fees.ask.to_string(), fees.bid.to_string(),
+                        market.name, trade.id as i64, super::trend(trade) as i64]
+            )?;
+        }
+        for change in changes {
+            SqliteStorage::change_balance_in(&tx, &change)?;
+        }
+        SqliteStorage::save_candles_in(&tx, &batch.candles)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_candles(&self, candles: &[Candle]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        SqliteStorage::save_candles_in(&tx, candles)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // created_at是UTC的文本
+    fn find_trades(&self, market: &str, since: i64) -> Result<Vec<(f64, f64, i64)>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r"SELECT price, volume, CAST(strftime('%s', created_at) AS INTEGER) FROM trades
+              WHERE market=?1 AND CAST(strftime('%s', created_at) AS INTEGER)>=?2 ORDER BY seq"
+        )?;
+        let trades = stmt.query_map(params![market, since], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(f64, f64, i64)>>>()?;
+        Ok(trades)
+    }
+
+    fn change_balance(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        SqliteStorage::change_balance_in(&tx, change)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn balance(&self, user_id: &str, asset: &str) -> Result<(f64, f64), Box<dyn Error>> {
+        let (available, frozen) = SqliteStorage::find_balance(&self.conn.lock().unwrap(), user_id, asset)?;
+        Ok((available.to_string().parse::<f64>()?, frozen.to_string().parse::<f64>()?))
+    }
+
+    fn find_open_orders(&self, market: &str) -> Result<Vec<Order>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM orders WHERE market=?1 AND state=?2 ORDER BY created_at, id", COLUMNS))?;
+        let orders = stmt.query_map(params![market, WAIT as i64], SqliteStorage::from_row)?
+            .collect::<rusqlite::Result<Vec<Order>>>()?;
+        Ok(orders)
+    }
+
+    fn open_totals(&self, market: &str) -> Result<Vec<(u8, u64, f64)>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT side, COUNT(*), SUM(volume) FROM orders WHERE market=?1 AND state=?2 GROUP BY side")?;
+        let totals = stmt.query_map(params![market, WAIT as i64], |row| {
+            Ok((row.get::<_, i64>(0)? as u8, row.get::<_, i64>(1)? as u64, row.get(2)?))
+        })?.collect::<rusqlite::Result<Vec<(u8, u64, f64)>>>()?;
+        Ok(totals)
+    }
+
+    fn last_trade_id(&self, market: &str) -> Result<u64, Box<dyn Error>> {
+        let seq: i64 = self.conn.lock().unwrap().query_row("SELECT COALESCE(MAX(seq), 0) FROM trades WHERE market=?1", params![market], |row| row.get(0))?;
+        Ok(seq as u64)
+    }
+
+    fn market_state(&self, market: &str) -> Result<Option<MarketState>, Box<dyn Error>> {
+        let name: Option<String> = self.conn.lock().unwrap()
+            .query_row("SELECT state FROM market_states WHERE market=?1", params![market], |row| row.get(0))
+            .optional()?;
+        match name {
+            Some(name) => Ok(Some(super::parse_market_state(&name)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_market_state(&self, market: &str, state: MarketState) -> Result<(), Box<dyn Error>> {
+        self.conn.lock().unwrap().execute(
+            r"INSERT INTO market_states (market, state) VALUES (?1, ?2)
+              ON CONFLICT (market) DO UPDATE SET state=?2, updated_at=CURRENT_TIMESTAMP",
+            params![market, state.name()]
+        )?;
+        Ok(())
+    }
+
+    fn find_order(&self, id: u64) -> Result<Option<Order>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let order = conn.query_row(&format!("SELECT {} FROM orders WHERE id=?1", COLUMNS), params![id as i64], SqliteStorage::from_row).optional()?;
+        Ok(order)
+    }
+
+    // created_at是UTC的文本，参数转换成同样格式的文本再比较
+    fn find_orders(&self, query: &OrderQuery) -> Result<Vec<Order>, Box<dyn Error>> {
+        let (sql, params) = query.to_sql(COLUMNS, |i| format!("?{}", i), |value| format!("datetime({}, 'unixepoch')", value));
+        let params = params.into_iter().map(|param| match param {
+            QueryParam::Text(text) => Value::Text(text),
+            QueryParam::State(state) => Value::Integer(state as i64),
+            QueryParam::Id(id) => Value::Integer(id as i64),
+            QueryParam::Time(time) => Value::Integer(time),
+        }).collect::<Vec<Value>>();
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        let orders = stmt.query_map(&params, SqliteStorage::from_row)?
+            .collect::<rusqlite::Result<Vec<Order>>>()?;
+        Ok(orders)
+    }
+
+    fn find_bracket_exits(&self, market: &str) -> Result<Vec<(u64, [(f64, f64, u8); 2])>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            r"SELECT entry_id, first_price, first_volume, first_side, second_price, second_volume, second_side
+              FROM bracket_exits WHERE market=?1 ORDER BY entry_id"
+        )?;
+        let exits = stmt.query_map(params![market], |row| {
+            Ok((row.get::<_, i64>(0)? as u64, [
+                (row.get(1)?, row.get(2)?, row.get::<_, i64>(3)? as u8),
+                (row.get(4)?, row.get(5)?, row.get::<_, i64>(6)? as u8),
+            ]))
+        })?.collect::<rusqlite::Result<Vec<(u64, [(f64, f64, u8); 2])>>>()?;
+        Ok(exits)
+    }
+
+    fn delete_bracket_exits(&self, entry_id: u64) -> Result<(), Box<dyn Error>> {
+        self.conn.lock().unwrap().execute("DELETE FROM bracket_exits WHERE entry_id=?1", params![entry_id as i64])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rusqlite::params;
+    use super::SqliteStorage;
+    use crate::accounts;
+    use crate::accounts::Change;
+    use crate::candles::Candle;
+    use crate::candles::CandleAggregator;
+    use crate::storage::Storage;
+    use crate::fees::FeeRate;
+    use crate::fees::FeeSchedule;
+    use crate::market::Market;
+    use crate::models::WAIT;
+    use crate::models::DONE;
+    use crate::models::CANCEL;
+    use crate::storage::OrderQuery;
+    use crate::engine::Condition;
+    use crate::engine::MarketState;
+    use crate::engine::Side;
+    use crate::engine::TradeEvent;
+    use crate::persister::Batch;
+
+    fn create_order(storage: &SqliteStorage, client_order_id: &str, price: f64, volume: f64, side: u8, user_id: &str) -> (u64, bool) {
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let freeze = accounts::freeze(&market, user_id, side, price, volume);
+        storage.create_order(client_order_id, "ethbtc", price, volume, side, &Condition::None, None, None, user_id, &freeze).unwrap()
+    }
+
+    fn create_storage() -> SqliteStorage {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        for user_id in &["u1", "u2"] {
+            storage.change_balance(&Change::deposit(user_id, "btc", 10.0)).unwrap();
+            storage.change_balance(&Change::deposit(user_id, "eth", 10.0)).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn can_create_orders_once_per_client_order_id() {
+        let storage = create_storage();
+        assert_eq!((1, true), create_order(&storage, "c1", 1.5, 2.0, 1, "u1"));
+        assert_eq!((2, true), create_order(&storage, "c1", 1.5, 2.0, 1, "u2"));
+        assert_eq!((1, false), create_order(&storage, "c1", 1.6, 3.0, 0, "u1"));
+
+        let orders = storage.find_open_orders("ethbtc").unwrap();
+        assert_eq!(vec![1, 2], orders.iter().map(|order| order.id).collect::<Vec<u64>>());
+        assert_eq!(WAIT as u16, orders[0].state);
+        assert_eq!(2.0, orders[0].origin_volume);
+        // 重复的订单不会再冻结
+        assert_eq!((7.0, 3.0), storage.balance("u1", "btc").unwrap());
+        assert_eq!((10.0, 0.0), storage.balance("u1", "eth").unwrap());
+    }
+
+    #[test]
+    fn rejects_orders_without_enough_balance() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let freeze = accounts::freeze(&market, "u1", 1, 2.0, 5.5);
+        let err = storage.create_order("c1", "ethbtc", 2.0, 5.5, 1, &Condition::None, None, None, "u1", &freeze).unwrap_err();
+        assert_eq!("u1 has not enough btc to freeze 11.0", err.to_string());
+
+        // 订单和冻结一起回滚
+        assert!(storage.find_open_orders("ethbtc").unwrap().is_empty());
+        assert_eq!((10.0, 0.0), storage.balance("u1", "btc").unwrap());
+        assert!(storage.change_balance(&Change::deposit("u3", "btc", -0.1)).is_err());
+    }
+
+    #[test]
+    fn creates_oco_legs_in_one_transaction() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let leg = |client_order_id, price, volume, side| (client_order_id, price, volume, side, accounts::freeze(&market, "u1", side, price, volume));
+        let legs = [leg("c1", 1.5, 2.0, 1), leg("c2", 1.7, 2.0, 0)];
+        assert_eq!(vec![(1, true), (2, true)], storage.create_orders("ethbtc", "u1", &legs).unwrap());
+        assert_eq!(vec![(1, false), (2, false)], storage.create_orders("ethbtc", "u1", &legs).unwrap());
+
+        // 只有一条腿已经存在时整组回滚
+        let err = storage.create_orders("ethbtc", "u1", &[leg("c3", 1.5, 1.0, 1), leg("c2", 1.7, 2.0, 0)]).unwrap_err();
+        assert_eq!("only some of the orders c3,c2 of u1 already exist", err.to_string());
+        // 第二条腿余额不足时第一条腿也回滚
+        assert!(storage.create_orders("ethbtc", "u1", &[leg("c4", 1.5, 1.0, 1), leg("c5", 1.7, 9.0, 0)]).is_err());
+
+        let orders = storage.find_open_orders("ethbtc").unwrap();
+        assert_eq!(vec![Some(2), Some(1)], orders.iter().map(|order| order.oco_id).collect::<Vec<Option<u64>>>());
+        assert_eq!((7.0, 3.0), storage.balance("u1", "btc").unwrap());
+        assert_eq!((8.0, 2.0), storage.balance("u1", "eth").unwrap());
+    }
+
+    #[test]
+    fn keeps_bracket_exits_until_placed_or_canceled() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let exits = [(1.8, 2.0, 0), (1.4, 2.0, 0)];
+        for client_order_id in &["c1", "c2"] {
+            let freeze = accounts::freeze(&market, "u1", 1, 1.5, 2.0);
+            storage.create_order(client_order_id, "ethbtc", 1.5, 2.0, 1, &Condition::None, None, Some(&exits), "u1", &freeze).unwrap();
+        }
+        assert_eq!(vec![(1, exits), (2, exits)], storage.find_bracket_exits("ethbtc").unwrap());
+        assert!(storage.find_bracket_exits("btcusdt").unwrap().is_empty());
+
+        // 入场单撤销时一起删除
+        storage.write_batch(&market, &Batch { cancels: vec![1], ..Batch::default() }).unwrap();
+        assert_eq!(vec![(2, exits)], storage.find_bracket_exits("ethbtc").unwrap());
+        storage.delete_bracket_exits(2).unwrap();
+        assert!(storage.find_bracket_exits("ethbtc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrates_only_once() {
+        let storage = SqliteStorage::connect("sqlite::memory:").unwrap();
+        assert!(storage.migrate().unwrap().is_empty());
+
+        let storage = SqliteStorage::connect(&format!("sqlite://{}", ":memory:")).unwrap();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11], storage.migrate().unwrap());
+        assert!(storage.migrate().unwrap().is_empty());
+        assert!(storage.find_open_orders("ethbtc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn can_write_fills_and_cancels() {
+        let storage = create_storage();
+        let mut market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        market.fees = FeeSchedule::new("fees", FeeRate::new(0.001, 0.002));
+        let (bid, _) = create_order(&storage, "c1", 1.5, 2.0, 1, "u1");
+        let (ask, _) = create_order(&storage, "c1", 1.5, 0.5, 0, "u2");
+        let (other, _) = create_order(&storage, "c2", 1.7, 1.0, 0, "u2");
+
+        storage.write_batch(&market, &Batch {
+            trades: vec![TradeEvent {
+                id: 7,
+                price: 1.5,
+                volume: 0.5,
+                funds: 0.75,
+                ask_order_id: ask,
+                ask_order_filled: true,
+                bid_order_id: bid,
+                bid_order_filled: false,
+                taker_side: Side::Sell,
+                maker_order_id: bid,
+                taker_order_id: ask,
+                ask_owner: "u2".to_string(),
+                bid_owner: "u1".to_string(),
+            }],
+            cancels: vec![other],
+            reductions: vec![],
+            candles: vec![],
+        }).unwrap();
+
+        let orders = storage.find_open_orders("ethbtc").unwrap();
+        assert_eq!(1, orders.len());
+        assert_eq!((bid, 1.5, 1), (orders[0].id, orders[0].volume, orders[0].trades_count));
+        assert_eq!(vec![(1, 1, 1.5)], storage.open_totals("ethbtc").unwrap());
+        assert!(storage.find_open_orders("btcusdt").unwrap().is_empty());
+
+        // 卖方是taker，从0.75btc里扣0.2%；买方是maker，从0.5eth里扣0.1%
+        assert_eq!((7.0, 2.25), storage.balance("u1", "btc").unwrap());
+        assert_eq!((10.4995, 0.0), storage.balance("u1", "eth").unwrap());
+        assert_eq!((10.7485, 0.0), storage.balance("u2", "btc").unwrap());
+        assert_eq!((9.5, 0.0), storage.balance("u2", "eth").unwrap());
+        assert_eq!((0.0015, 0.0), storage.balance("fees", "btc").unwrap());
+        assert_eq!((0.0005, 0.0), storage.balance("fees", "eth").unwrap());
+        assert_eq!(7, storage.last_trade_id("ethbtc").unwrap());
+        assert_eq!(0, storage.last_trade_id("btcusdt").unwrap());
+
+        let conn = storage.conn.lock().unwrap();
+        let state: i64 = conn.query_row("SELECT state FROM orders WHERE id=?1", params![ask as i64], |row| row.get(0)).unwrap();
+        assert_eq!(DONE as i64, state);
+        let (ask_fee, bid_fee): (String, String) = conn.query_row("SELECT ask_fee, bid_fee FROM trades", params![], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((0.0015, 0.0005), (ask_fee.parse::<f64>().unwrap(), bid_fee.parse::<f64>().unwrap()));
+        let (market, seq, trend): (String, i64, i64) = conn.query_row("SELECT market, seq, trend FROM trades", params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap();
+        assert_eq!(("ethbtc".to_string(), 7, 0), (market, seq, trend));
+    }
+
+    #[test]
+    fn can_backfill_candles_from_trades() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let (bid, _) = create_order(&storage, "c1", 1.5, 2.0, 1, "u1");
+        let (ask, _) = create_order(&storage, "c1", 1.4, 2.0, 0, "u2");
+        let trades = (1..=2).map(|id| TradeEvent {
+            id: id,
+            price: 1.5,
+            volume: 0.5,
+            funds: 0.75,
+            ask_order_id: ask,
+            ask_order_filled: false,
+            bid_order_id: bid,
+            bid_order_filled: false,
+            taker_side: Side::Sell,
+            maker_order_id: bid,
+            taker_order_id: ask,
+            ask_owner: "u2".to_string(),
+            bid_owner: "u1".to_string(),
+        }).collect::<Vec<TradeEvent>>();
+        storage.write_batch(&market, &Batch { trades: trades, ..Batch::default() }).unwrap();
+
+        let now = Utc::now().timestamp();
+        let trades = storage.find_trades("ethbtc", now - 60).unwrap();
+        assert_eq!(2, trades.len());
+        assert!(trades.iter().all(|(price, volume, timestamp)| (*price, *volume) == (1.5, 0.5) && (now - timestamp).abs() < 60));
+        assert!(storage.find_trades("ethbtc", now + 60).unwrap().is_empty());
+
+        // 回补可以重复执行，同一根K线覆盖
+        let (aggregator, _closed) = CandleAggregator::backfill("ethbtc", &trades);
+        let candles = aggregator.current().into_iter().cloned().collect::<Vec<Candle>>();
+        storage.save_candles(&candles).unwrap();
+        storage.save_candles(&candles).unwrap();
+        let conn = storage.conn.lock().unwrap();
+        let (count, volume): (i64, f64) = conn.query_row("SELECT COUNT(*), SUM(volume) FROM candles WHERE period=60", params![], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+        assert_eq!((1, 1.0), (count, volume));
+    }
+
+    #[test]
+    fn can_reduce_orders() {
+        let storage = create_storage();
+        let market = Market::new("ethbtc", "eth", "btc", 8, 8);
+        let (bid, _) = create_order(&storage, "c1", 1.5, 2.0, 1, "u1");
+        let (ask, _) = create_order(&storage, "c1", 1.5, 1.0, 0, "u2");
+
+        // 减量之后再撤单，撤单只退回剩余的部分
+        storage.write_batch(&market, &Batch { reductions: vec![(bid, 1.5), (ask, 0.25)], cancels: vec![ask], ..Batch::default() }).unwrap();
+        let order = storage.find_order(bid).unwrap().unwrap();
+        assert_eq!((0.5, 2.0, WAIT as u16), (order.volume, order.origin_volume, order.state));
+        assert_eq!((9.25, 0.75), storage.balance("u1", "btc").unwrap());
+        assert_eq!((10.0, 0.0), storage.balance("u2", "eth").unwrap());
+    }
+
+    #[test]
+    fn can_query_orders() {
+        let storage = create_storage();
+        for (client_order_id, user_id) in &[("c1", "u1"), ("c2", "u2"), ("c3", "u1"), ("c4", "u1")] {
+            create_order(&storage, client_order_id, 1.5, 1.0, 1, user_id);
+        }
+        storage.write_batch(&Market::new("ethbtc", "eth", "btc", 8, 8), &Batch { cancels: vec![3], ..Batch::default() }).unwrap();
+
+        assert_eq!(Some("c2".to_string()), storage.find_order(2).unwrap().map(|order| order.client_order_id));
+        assert!(storage.find_order(5).unwrap().is_none());
+
+        let ids = |query: OrderQuery| storage.find_orders(&query).unwrap().iter().map(|order| order.id).collect::<Vec<u64>>();
+        assert_eq!(vec![4, 3, 1], ids(OrderQuery::new().with_user("u1")));
+        assert_eq!(vec![4, 1], ids(OrderQuery::new().with_user("u1").with_market("ethbtc").with_state(WAIT)));
+        assert_eq!(vec![3], ids(OrderQuery::new().with_state(CANCEL)));
+        assert!(ids(OrderQuery::new().with_market("btcusdt")).is_empty());
+
+        // 翻页
+        assert_eq!(vec![4, 3], ids(OrderQuery::new().with_page(None, 2)));
+        assert_eq!(vec![2, 1], ids(OrderQuery::new().with_page(Some(3), 2)));
+
+        let now = Utc::now().timestamp();
+        assert_eq!(4, ids(OrderQuery::new().with_time_range(Some(now - 60), Some(now + 60))).len());
+        assert!(ids(OrderQuery::new().with_time_range(Some(now + 60), None)).is_empty());
+        assert!(ids(OrderQuery::new().with_time_range(None, Some(now - 60))).is_empty());
+    }
+
+    #[test]
+    fn can_save_market_state() {
+        let storage = create_storage();
+        assert_eq!(None, storage.market_state("ethbtc").unwrap());
+        storage.set_market_state("ethbtc", MarketState::Halted).unwrap();
+        storage.set_market_state("ethbtc", MarketState::CancelOnly).unwrap();
+        assert_eq!(Some(MarketState::CancelOnly), storage.market_state("ethbtc").unwrap());
+        assert_eq!(None, storage.market_state("btcusdt").unwrap());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::fmt;
+use bigdecimal::BigDecimal;
+use bigdecimal::Zero;
+
+use crate::accounts::decimal;
+use crate::engine::OrderBookPair;
+use crate::engine::TradeEvent;
+
+// 滚动窗口的长度和桶的粒度，单位秒
+const WINDOW: i64 = 86400;
+const BUCKET: i64 = 60;
+
+// 一个市场最近24小时的行情
+// change_percent是相对open的涨跌百分比，保留4位小数；窗口里没有成交时open/high/low都等于last
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ticker {
+    pub market: String,
+    pub last: f64,
+    pub open: f64,
+    pub change: f64,
+    pub change_percent: f64,
+    pub high: f64,
+    pub low: f64,
+    // base数量和quote金额
+    pub volume: f64,
+    pub funds: f64,
+    pub best_bid: Option<f64>,
+    pub best_ask: Option<f64>,
+}
+
+impl Ticker {
+    pub fn routing_key(&self) -> String {
+        format!("ticker.{}", self.market)
+    }
+}
+
+fn price_or_dash(price: Option<f64>) -> String {
+    price.map_or("-".to_string(), |price| price.to_string())
+}
+
+impl fmt::Display for Ticker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ticker market={} last={} open={} change={} change_percent={} high={} low={} volume={} funds={} bid={} ask={}",
+            self.market, self.last, self.open, self.change, self.change_percent,
+            self.high, self.low, self.volume, self.funds,
+            price_or_dash(self.best_bid), price_or_dash(self.best_ask))
+    }
+}
+
+// 一分钟内的成交汇总
+struct Bucket {
+    time: i64,
+    open: f64,
+    high: f64,
+    low: f64,
+    volume: BigDecimal,
+    funds: BigDecimal,
+}
+
+// 按分钟分桶的24小时滚动窗口，成交量和成交额是增量维护的十进制和，
+// 最高最低价只在过期的桶恰好持有极值时才重新扫描（最多1440个桶）
+pub struct TickerWindow {
+    market: String,
+    buckets: VecDeque<Bucket>,
+    last: Option<f64>,
+    high: Option<f64>,
+    low: Option<f64>,
+    volume: BigDecimal,
+    funds: BigDecimal,
+    best_bid: Option<f64>,
+    best_ask: Option<f64>,
+    changed: bool,
+}
+
+impl TickerWindow {
+    pub fn new(market: &str) -> TickerWindow {
+        TickerWindow {
+            market: market.to_string(),
+            buckets: VecDeque::new(),
+            last: None,
+            high: None,
+            low: None,
+            volume: BigDecimal::zero(),
+            funds: BigDecimal::zero(),
+            best_bid: None,
+            best_ask: None,
+            changed: false,
+        }
+    }
+
+    // 用最近24小时的历史成交(price, volume, 时间)初始化
+    pub fn backfill(market: &str, trades: &[(f64, f64, i64)]) -> TickerWindow {
+        let mut window = TickerWindow::new(market);
+        for (price, volume, timestamp) in trades {
+            let funds = (decimal(*price) * decimal(*volume)).to_string().parse::<f64>().unwrap();
+            window.add(*price, *volume, funds, *timestamp);
+        }
+        window
+    }
+
+    pub fn add_trade(&mut self, trade: &TradeEvent, timestamp: i64) {
+        self.add(trade.price, trade.volume, trade.funds, timestamp);
+    }
+
+    // 成交的时间要求基本递增，比最后一个桶还早的成交算进最后一个桶
+    pub fn add(&mut self, price: f64, volume: f64, funds: f64, timestamp: i64) {
+        self.expire(timestamp);
+        let time = timestamp - timestamp.rem_euclid(BUCKET);
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.time >= time => {
+                bucket.high = bucket.high.max(price);
+                bucket.low = bucket.low.min(price);
+                bucket.volume += decimal(volume);
+                bucket.funds += decimal(funds);
+            },
+            _ => self.buckets.push_back(Bucket {
+                time: time,
+                open: price,
+                high: price,
+                low: price,
+                volume: decimal(volume),
+                funds: decimal(funds),
+            })
+        }
+        self.last = Some(price);
+        self.high = Some(self.high.map_or(price, |high| high.max(price)));
+        self.low = Some(self.low.map_or(price, |low| low.min(price)));
+        self.volume += decimal(volume);
+        self.funds += decimal(funds);
+        self.changed = true;
+    }
+
+    // 用两边订单簿的最优价格更新买一卖一
+    pub fn set_book(&mut self, order_book_pair: &OrderBookPair) {
+        let best_bid = order_book_pair.buy_order_book.top().map(|order| order.price);
+        let best_ask = order_book_pair.sell_order_book.top().map(|order| order.price);
+        if (best_bid, best_ask) != (self.best_bid, self.best_ask) {
+            self.best_bid = best_bid;
+            self.best_ask = best_ask;
+            self.changed = true;
+        }
+    }
+
+    // 上次调用之后行情是否有变化，用来决定是否需要发布
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::replace(&mut self.changed, false)
+    }
+
+    // 移出now之前24小时以外的桶
+    pub fn expire(&mut self, now: i64) {
+        let mut rescan = false;
+        while let Some(bucket) = self.buckets.front() {
+            if bucket.time + BUCKET > now - WINDOW {
+                break;
+            }
+            let bucket = self.buckets.pop_front().unwrap();
+            self.volume -= bucket.volume;
+            self.funds -= bucket.funds;
+            rescan = rescan || Some(bucket.high) == self.high || Some(bucket.low) == self.low;
+            self.changed = true;
+        }
+        if rescan {
+            self.high = self.buckets.iter().map(|bucket| bucket.high).fold(None, |high: Option<f64>, price| Some(high.map_or(price, |high| high.max(price))));
+            self.low = self.buckets.iter().map(|bucket| bucket.low).fold(None, |low: Option<f64>, price| Some(low.map_or(price, |low| low.min(price))));
+        }
+    }
+
+    pub fn ticker(&mut self, now: i64) -> Ticker {
+        self.expire(now);
+        let last = self.last.unwrap_or(0.0);
+        let open = self.buckets.front().map_or(last, |bucket| bucket.open);
+        let change = decimal(last) - decimal(open);
+        let change_percent = if open == 0.0 {
+            BigDecimal::zero()
+        } else {
+            (change.clone() * BigDecimal::from(100) / decimal(open)).with_scale(4)
+        };
+        Ticker {
+            market: self.market.clone(),
+            last: last,
+            open: open,
+            change: change.to_string().parse::<f64>().unwrap(),
+            change_percent: change_percent.to_string().parse::<f64>().unwrap(),
+            high: self.high.unwrap_or(last),
+            low: self.low.unwrap_or(last),
+            volume: self.volume.to_string().parse::<f64>().unwrap(),
+            funds: self.funds.to_string().parse::<f64>().unwrap(),
+            best_bid: self.best_bid,
+            best_ask: self.best_ask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TickerWindow;
+    use crate::engine::LimitOrder;
+    use crate::engine::OrderBookPair;
+    use crate::engine::Side;
+
+    #[test]
+    fn keeps_a_rolling_window() {
+        let mut window = TickerWindow::new("ethbtc");
+        let start = 1_600_000_000;
+        window.add(0.05, 1.0, 0.05, start);
+        window.add(0.03, 0.5, 0.015, start + 30);
+        window.add(0.04, 0.1, 0.004, start + 3600);
+        assert!(window.take_changed());
+        assert!(!window.take_changed());
+
+        let ticker = window.ticker(start + 3600);
+        assert_eq!((0.04, 0.05, -0.01, -20.0), (ticker.last, ticker.open, ticker.change, ticker.change_percent));
+        assert_eq!((0.05, 0.03, 1.6, 0.069), (ticker.high, ticker.low, ticker.volume, ticker.funds));
+
+        // 前两个桶过期之后最高最低价重新计算
+        let ticker = window.ticker(start + 86400 + 120);
+        assert!(window.take_changed());
+        assert_eq!((0.04, 0.04, 0.0, 0.0), (ticker.last, ticker.open, ticker.change, ticker.change_percent));
+        assert_eq!((0.04, 0.04, 0.1, 0.004), (ticker.high, ticker.low, ticker.volume, ticker.funds));
+
+        // 窗口里没有成交时保留最新价
+        let ticker = window.ticker(start + 2 * 86400);
+        assert_eq!((0.04, 0.04, 0.04, 0.0), (ticker.last, ticker.open, ticker.high, ticker.volume));
+    }
+
+    #[test]
+    fn can_backfill() {
+        let mut window = TickerWindow::backfill("ethbtc", &[(0.031, 0.3, 100), (0.029, 0.7, 250)]);
+        let ticker = window.ticker(300);
+        assert_eq!((0.029, 0.031, 1.0, 0.0296), (ticker.last, ticker.open, ticker.volume, ticker.funds));
+    }
+
+    #[test]
+    fn tracks_top_of_book() {
+        let mut window = TickerWindow::new("ethbtc");
+        let mut order_book_pair = OrderBookPair::new();
+        window.set_book(&order_book_pair);
+        assert!(!window.take_changed());
+
+        order_book_pair.buy_order_book.add(LimitOrder::new(1, Side::Buy, 1.0, 0.029));
+        order_book_pair.buy_order_book.add(LimitOrder::new(2, Side::Buy, 1.0, 0.03));
+        order_book_pair.sell_order_book.add(LimitOrder::new(3, Side::Sell, 1.0, 0.031));
+        window.set_book(&order_book_pair);
+        assert!(window.take_changed());
+
+        let ticker = window.ticker(0);
+        assert_eq!((Some(0.03), Some(0.031)), (ticker.best_bid, ticker.best_ask));
+        assert_eq!("ticker market=ethbtc last=0 open=0 change=0 change_percent=0 high=0 low=0 volume=0 funds=0 bid=0.03 ask=0.031", ticker.to_string());
+    }
+}